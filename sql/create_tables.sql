@@ -1,67 +1,115 @@
-
----
-
-### 2. MariaDB/MySQL Table Creation Script (`sql/create_tables.sql`)
-
-Place this file in a directory named `sql` at the root of your repository. Using a dedicated `sql` folder for database scripts is a common and acceptable practice.
-
-```sql
--- create_tables.sql
--- This script creates the necessary tables for DMParser if they do not already exist.
--- Run this script on your MariaDB/MySQL database.
-
--- Table: campaigns
-CREATE TABLE IF NOT EXISTS campaigns (
-    id INT AUTO_INCREMENT PRIMARY KEY,
-    campaignName VARCHAR(255) NOT NULL,
-    vertical INT NOT NULL DEFAULT 1,
-    textingActive TINYINT NOT NULL DEFAULT 0,
-    flag INT NOT NULL,
-    emoji VARCHAR(50),
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
-
--- Table: emoji
-CREATE TABLE IF NOT EXISTS emoji (
-    id INT AUTO_INCREMENT PRIMARY KEY,
-    e VARCHAR(50) NOT NULL,
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
-
--- Table: address
-CREATE TABLE IF NOT EXISTS address (
-    id INT AUTO_INCREMENT PRIMARY KEY,
-    street VARCHAR(255),
-    unit_type VARCHAR(50),
-    unit_num VARCHAR(50),
-    mail_city VARCHAR(100),
-    state VARCHAR(50),
-    zip VARCHAR(20),
-    latitude VARCHAR(50),
-    longitude VARCHAR(50),
-    fullname VARCHAR(255),
-    fname VARCHAR(100),
-    lname VARCHAR(100),
-    mailingAddress VARCHAR(255),
-    mailingCity VARCHAR(100),
-    mailingState VARCHAR(50),
-    mailingZip VARCHAR(20),
-    flag INT,
-    DMID VARCHAR(100),
-    via INT,
-    map_image_url VARCHAR(255),
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-    UNIQUE KEY uniq_dmid (DMID)
-) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
-
--- Table: phonequeue
-CREATE TABLE IF NOT EXISTS phonequeue (
-    id INT AUTO_INCREMENT PRIMARY KEY,
-    aid INT,
-    phone1 VARCHAR(50),
-    phone2 VARCHAR(50),
-    phone3 VARCHAR(50),
-    step INT,
-    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-    FOREIGN KEY (aid) REFERENCES address(id) ON DELETE CASCADE
-) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+-- create_tables.sql
+-- This script creates the necessary tables for DMParser if they do not already exist.
+-- Run this script on your MariaDB/MySQL database.
+
+-- Table: campaigns
+CREATE TABLE IF NOT EXISTS campaigns (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    campaignName VARCHAR(255) NOT NULL,
+    vertical INT NOT NULL DEFAULT 1,
+    textingActive TINYINT NOT NULL DEFAULT 0,
+    flag INT NOT NULL,
+    emoji VARCHAR(50),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: flag_sequence
+-- Single-row counter for allocating new campaign flags. Locked with
+-- SELECT ... FOR UPDATE so concurrent runs never hand out the same flag.
+CREATE TABLE IF NOT EXISTS flag_sequence (
+    id TINYINT PRIMARY KEY,
+    next_flag BIGINT NOT NULL
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: emoji
+CREATE TABLE IF NOT EXISTS emoji (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    e VARCHAR(50) NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: address
+CREATE TABLE IF NOT EXISTS address (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    street VARCHAR(255),
+    unit_type VARCHAR(50),
+    unit_num VARCHAR(50),
+    mail_city VARCHAR(100),
+    state VARCHAR(50),
+    zip VARCHAR(20),
+    latitude VARCHAR(50),
+    longitude VARCHAR(50),
+    fullname VARCHAR(255),
+    fname VARCHAR(100),
+    lname VARCHAR(100),
+    mailingAddress VARCHAR(255),
+    mailingCity VARCHAR(100),
+    mailingState VARCHAR(50),
+    mailingZip VARCHAR(20),
+    flag INT,
+    DMID VARCHAR(100),
+    via INT,
+    map_image_url VARCHAR(255),
+    email VARCHAR(255),
+    owner_occupied TINYINT(1),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE KEY uniq_dmid (DMID)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: phonequeue
+CREATE TABLE IF NOT EXISTS phonequeue (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    aid INT,
+    phone1 VARCHAR(50),
+    phone2 VARCHAR(50),
+    phone3 VARCHAR(50),
+    step INT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (aid) REFERENCES address(id) ON DELETE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: processed_files
+-- Used when PROCESSED_TRACKING=database, and for duplicate-file detection when
+-- DUPLICATES_DIR is set.
+CREATE TABLE IF NOT EXISTS processed_files (
+    id INT AUTO_INCREMENT PRIMARY KEY,
+    filename VARCHAR(255) NOT NULL,
+    content_hash CHAR(64) NOT NULL,
+    rows_inserted INT NOT NULL DEFAULT 0,
+    processed_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE KEY uniq_file_hash (filename, content_hash)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: optout
+-- Phone numbers that must never be queued. Only read when OPTOUT_TABLE is set;
+-- an existing opt-out table works too if it has a `phone` column.
+CREATE TABLE IF NOT EXISTS optout (
+    phone VARCHAR(50) NOT NULL PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: runs
+-- One row per run when RUN_LOG_ENABLED=true: inserted as `running` at start and
+-- updated with the run's totals and exit status when it ends.
+CREATE TABLE IF NOT EXISTS runs (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    host VARCHAR(255) NOT NULL,
+    started_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMP NULL,
+    status VARCHAR(20) NOT NULL DEFAULT 'running',
+    exit_code INT,
+    files_attempted INT,
+    files_succeeded INT,
+    rows_inserted INT
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+-- Table: phone_registry
+-- One row per phone number in phonequeue. Only used when PHONE_DEDUP_MODE=db,
+-- where the primary key enforces phone uniqueness across concurrent writers.
+-- A unique index on phonequeue can't do this, since the same number may be
+-- stored in any of phone1, phone2 or phone3.
+CREATE TABLE IF NOT EXISTS phone_registry (
+    phone VARCHAR(50) NOT NULL PRIMARY KEY,
+    claimed_by VARCHAR(64),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
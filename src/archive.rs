@@ -0,0 +1,212 @@
+//! Reads `.zip` uploads. The `.csv` files in an archive, at any depth, are
+//! extracted to a scratch directory and handed to `process_file` one by one
+//! like local uploads. The archive itself is marked done once none of its
+//! files is left to process; otherwise it stays in place for the next run.
+
+use crate::{
+    deadline_passed, file_sha256, log_timestamp, mark_file_done, process_file, quarantine_file, Config, FileClaim,
+    PhoneSet, ProcessedTracking, RunSummary,
+};
+use anyhow::{Context, Result};
+use sqlx::{MySql, Pool};
+use std::{
+    collections::HashSet,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicUsize,
+    time::Instant,
+};
+use zip::ZipArchive;
+
+/// Processes the CSV files in one archive. Returns whether the archive was
+/// finished (marked done or quarantined), as `FileSummary::completed` does for
+/// a single file.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_archive(
+    pool: Option<&Pool<MySql>>,
+    archive_path: &Path,
+    config: &Config,
+    limit: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    campaigns_created: &AtomicUsize,
+    global_phone_set: &mut PhoneSet,
+    optout: &HashSet<String>,
+    summary: &mut RunSummary,
+) -> Result<bool> {
+    let file_name = archive_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let claim = match config.processed_tracking {
+        ProcessedTracking::Move => match FileClaim::acquire(archive_path)? {
+            Some(claim) => Some(claim),
+            None => {
+                eprintln!("File {} was claimed by another process; skipping.", file_name);
+                return Ok(true);
+            }
+        },
+        ProcessedTracking::Database => None,
+    };
+    let archive_path = claim.as_ref().map_or(archive_path, |claim| claim.path());
+    let content_hash = if config.records_processed_files() || config.verify_moves {
+        Some(file_sha256(archive_path)?)
+    } else {
+        None
+    };
+
+    // Extracted files are processed with the regular move tracking in a
+    // scratch upload directory; one that is still there afterwards wasn't
+    // finished.
+    let scratch = env::temp_dir().join(format!("dmparser-zip-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&scratch);
+    let local_config = Config {
+        upload_dir: scratch.join("uploads").to_string_lossy().to_string(),
+        processed_dir: scratch.join("processed").to_string_lossy().to_string(),
+        processed_tracking: ProcessedTracking::Move,
+        duplicates_dir: config
+            .duplicates_dir
+            .as_ref()
+            .map(|_| scratch.join("duplicates").to_string_lossy().to_string()),
+        ..config.clone()
+    };
+    fs::create_dir_all(&local_config.processed_dir)
+        .with_context(|| format!("Failed to create scratch directory: {}", local_config.processed_dir))?;
+
+    let result = match extract_csv_files(archive_path, Path::new(&local_config.upload_dir), config) {
+        Ok(files) => {
+            process_members(
+                pool,
+                &files,
+                &local_config,
+                limit,
+                campaign_flag,
+                deadline,
+                campaigns_created,
+                global_phone_set,
+                optout,
+                summary,
+            )
+            .await
+        }
+        // Like a malformed CSV file, an archive that can't be read or holds
+        // misnamed files won't do better on a retry.
+        Err(e) => {
+            eprintln!(
+                "WARNING: {} can't be imported: {:#}. Moving the file to {}.",
+                file_name, e, config.quarantine_dir
+            );
+            quarantine_file(config, archive_path, &file_name)
+                .with_context(|| format!("Failed to quarantine {}", file_name))?;
+            Ok(None)
+        }
+    };
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        eprintln!("Failed to remove scratch directory {:?}: {:?}", scratch, e);
+    }
+    match result? {
+        Some(rows_inserted) => {
+            mark_file_done(pool, config, archive_path, &file_name, content_hash.as_deref(), rows_inserted).await?;
+            Ok(true)
+        }
+        None => Ok(!archive_path.exists()),
+    }
+}
+
+/// Processes the extracted files in order. Returns the rows inserted if every
+/// file was finished, or `None` if the run stopped before the end.
+#[allow(clippy::too_many_arguments)]
+async fn process_members(
+    pool: Option<&Pool<MySql>>,
+    files: &[PathBuf],
+    local_config: &Config,
+    limit: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    campaigns_created: &AtomicUsize,
+    global_phone_set: &mut PhoneSet,
+    optout: &HashSet<String>,
+    summary: &mut RunSummary,
+) -> Result<Option<usize>> {
+    let mut rows_inserted = 0;
+    for file_path in files {
+        let insert_budget = limit.map(|limit| limit.saturating_sub(summary.rows_inserted));
+        if insert_budget == Some(0) || deadline_passed(deadline) {
+            eprintln!(
+                "[{}] Stopping inside an archive; it stays in the upload directory and is read again on the next run.",
+                log_timestamp()
+            );
+            return Ok(None);
+        }
+        let file_summary = process_file(
+            pool,
+            file_path,
+            local_config,
+            global_phone_set,
+            optout,
+            insert_budget,
+            campaign_flag,
+            deadline,
+            campaigns_created,
+        )
+        .await?;
+        summary.record_file(&file_summary);
+        rows_inserted += file_summary.rows_inserted;
+        if file_path.exists() {
+            return Ok(None);
+        }
+    }
+    Ok(Some(rows_inserted))
+}
+
+/// Extracts every `.csv` file in the archive into `dest`, flattening any
+/// directories, and returns their paths sorted by name. Fails if a file's name
+/// doesn't match `FILENAME_REGEX`, if two files share a name, or if there is
+/// no CSV file at all. macOS resource forks (`__MACOSX/`, `._*`) are ignored.
+fn extract_csv_files(archive_path: &Path, dest: &Path, config: &Config) -> Result<Vec<PathBuf>> {
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("Failed to open {}", archive_path.display()))?;
+    let mut archive = ZipArchive::new(file).context("not a readable zip archive")?;
+    fs::create_dir_all(dest)
+        .with_context(|| format!("Failed to create scratch directory: {}", dest.display()))?;
+
+    let mut files = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).context("unreadable archive entry")?;
+        if entry.is_dir() {
+            continue;
+        }
+        let Some(entry_path) = entry.enclosed_name() else {
+            return Err(anyhow::anyhow!("unsafe path {} in archive", entry.name()));
+        };
+        if entry_path.components().any(|part| part.as_os_str() == "__MACOSX") {
+            continue;
+        }
+        let name = entry_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let is_csv = Path::new(&name)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        if !is_csv || name.starts_with("._") {
+            continue;
+        }
+        if !config.filename_pattern.matches(&name) {
+            return Err(anyhow::anyhow!("{} doesn't match FILENAME_REGEX", entry_path.display()));
+        }
+        let path = dest.join(&name);
+        if path.exists() {
+            return Err(anyhow::anyhow!("more than one file is named {}", name));
+        }
+        let mut out = fs::File::create(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        std::io::copy(&mut entry, &mut out)
+            .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+        files.push(path);
+    }
+    if files.is_empty() {
+        return Err(anyhow::anyhow!("no CSV files in archive"));
+    }
+    files.sort();
+    Ok(files)
+}
@@ -0,0 +1,6787 @@
+//! Core of DMParser: imports Deal Machine CSV exports into the `address` and
+//! `phonequeue` tables. [`Importer`] is the entry point for embedding; the
+//! `DMParser` binary is a thin command-line wrapper around it.
+
+use anyhow::{Context, Result};
+use chrono::{Local, Utc};
+use chrono_tz::Tz;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use encoding_rs::Encoding;
+pub use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    mysql::{MySqlConnection, MySqlPoolOptions, MySqlRow},
+    Connection, MySql, Pool, Row, Transaction,
+};
+use std::{
+    collections::BTreeMap,
+    collections::BTreeSet,
+    collections::HashSet,
+    collections::HashMap,
+    env,
+    fs,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "zip")]
+mod archive;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "xlsx")]
+mod xlsx;
+
+/// Columns written to the `address` table, in bind order.
+const ADDRESS_COLUMNS: [&str; 19] = [
+    "street", "unit_type", "unit_num", "mail_city", "state", "zip", "latitude", "longitude",
+    "fullname", "fname", "lname", "mailingAddress", "mailingCity", "mailingState", "mailingZip",
+    "flag", "DMID", "via", "map_image_url",
+];
+
+/// Loose shape check for owner emails: something@domain.tld, without spaces.
+static EMAIL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[^@\s]+@[^@\s]+\.[^@\s.]+$").expect("valid email regex"));
+
+/// Names allowed for `ADDRESS_TABLE` and `PHONEQUEUE_TABLE`.
+static TABLE_NAME_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z_][A-Za-z0-9_]{0,63}$").expect("valid table name regex"));
+
+/// Address columns that `ADDRESS_OMIT_COLUMNS` may leave out for schema variants.
+const OPTIONAL_ADDRESS_COLUMNS: [&str; 3] = ["state", "via", "map_image_url"];
+
+/// CSV columns a file must have, unless `COLUMN_DEFAULTS` supplies a value.
+const REQUIRED_COLUMNS: [&str; 24] = [
+    "property_address_line_1",
+    "property_address_line_2",
+    "property_address_city",
+    "property_address_state",
+    "property_address_zipcode",
+    "property_lat",
+    "property_lng",
+    "owner_1_firstname",
+    "owner_1_lastname",
+    "owner_1_name",
+    "owner_address_line_1",
+    "owner_address_city",
+    "owner_address_state",
+    "owner_address_zip",
+    "lead_id",
+    "owner_2_firstname",
+    "owner_2_lastname",
+    "owner_2_name",
+    "contact_1_phone1",
+    "contact_1_phone2",
+    "contact_1_phone3",
+    "contact_2_phone1",
+    "contact_2_phone2",
+    "contact_2_phone3",
+];
+
+/// Built-in synonyms for common vendor header variants, as `(alias, column)`.
+/// Headers are compared by `header_key`, so spelling variants such as
+/// `Property Address Line1`, `owner_1_first_name` or `contact_1_phone_1` need
+/// no entry here.
+const HEADER_ALIASES: [(&str, &str); 27] = [
+    ("property_address", "property_address_line_1"),
+    ("property_street", "property_address_line_1"),
+    ("prop_address", "property_address_line_1"),
+    ("property_unit", "property_address_line_2"),
+    ("property_city", "property_address_city"),
+    ("prop_city", "property_address_city"),
+    ("property_state", "property_address_state"),
+    ("prop_state", "property_address_state"),
+    ("property_zip", "property_address_zipcode"),
+    ("property_address_zip", "property_address_zipcode"),
+    ("prop_zip", "property_address_zipcode"),
+    ("lat", "property_lat"),
+    ("latitude", "property_lat"),
+    ("property_latitude", "property_lat"),
+    ("lng", "property_lng"),
+    ("lon", "property_lng"),
+    ("long", "property_lng"),
+    ("longitude", "property_lng"),
+    ("property_longitude", "property_lng"),
+    ("owner_1_full_name", "owner_1_name"),
+    ("owner_2_full_name", "owner_2_name"),
+    ("mailing_address", "owner_address_line_1"),
+    ("owner_address", "owner_address_line_1"),
+    ("mailing_city", "owner_address_city"),
+    ("mailing_state", "owner_address_state"),
+    ("mailing_zip", "owner_address_zip"),
+    ("owner_address_zipcode", "owner_address_zip"),
+];
+
+/// Required columns that can't be given a default: a file without them has
+/// nothing worth importing. The dedup key columns are never defaultable either.
+const ESSENTIAL_COLUMNS: [&str; 2] = ["property_address_line_1", "lead_id"];
+
+/// Joins the values of `DEDUP_KEY_COLUMNS` into a row's DMID.
+const DEDUP_KEY_SEPARATOR: &str = "|";
+
+/// Maximum number of lead IDs per `IN (...)` lookup when prefetching DMIDs in scoped mode.
+const DMID_LOOKUP_CHUNK_SIZE: usize = 1000;
+
+/// Upper bound on the delay between database connection attempts.
+const DB_CONNECT_MAX_BACKOFF_SECONDS: u64 = 60;
+
+/// Timeout for the end-of-run webhook request.
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+
+/// Timeout for pushing metrics to the Prometheus pushgateway.
+const PUSHGATEWAY_TIMEOUT_SECONDS: u64 = 10;
+
+/// Prometheus metrics for the run, registered on first use and pushed to
+/// `PUSHGATEWAY_URL` before exit.
+static METRICS: LazyLock<ImportMetrics> = LazyLock::new(ImportMetrics::new);
+
+struct ImportMetrics {
+    registry: Registry,
+    rows_inserted: IntCounter,
+    rows_rejected: IntCounterVec,
+    file_duration: Histogram,
+    files_processed: IntCounter,
+    move_checksum_mismatches: IntCounter,
+    /// Also what `MAX_NEW_CAMPAIGNS_PER_RUN` is checked against.
+    campaigns_created: IntCounter,
+}
+
+impl ImportMetrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let rows_inserted = IntCounter::new("dmparser_rows_inserted_total", "Address rows inserted")
+            .expect("valid metric");
+        let rows_rejected = IntCounterVec::new(
+            Opts::new("dmparser_rows_rejected_total", "Rows rejected, by reason"),
+            &["reason"],
+        )
+        .expect("valid metric");
+        let file_duration = Histogram::with_opts(
+            HistogramOpts::new("dmparser_file_duration_seconds", "Time spent processing one file")
+                .buckets(prometheus::exponential_buckets(1.0, 2.0, 14).expect("valid buckets")),
+        )
+        .expect("valid metric");
+        let files_processed = IntCounter::new("dmparser_files_processed_total", "Files read to the end or to a stop")
+            .expect("valid metric");
+
+        registry.register(Box::new(rows_inserted.clone())).expect("unique metric");
+        registry.register(Box::new(rows_rejected.clone())).expect("unique metric");
+        registry.register(Box::new(file_duration.clone())).expect("unique metric");
+        let move_checksum_mismatches = IntCounter::new(
+            "dmparser_move_checksum_mismatches_total",
+            "Processed files whose hash changed when moved (VERIFY_MOVES)",
+        )
+        .expect("valid metric");
+
+        let campaigns_created = IntCounter::new("dmparser_campaigns_created_total", "Campaigns created by the run")
+            .expect("valid metric");
+
+        registry.register(Box::new(files_processed.clone())).expect("unique metric");
+        registry.register(Box::new(move_checksum_mismatches.clone())).expect("unique metric");
+        registry.register(Box::new(campaigns_created.clone())).expect("unique metric");
+
+        ImportMetrics {
+            registry,
+            rows_inserted,
+            rows_rejected,
+            file_duration,
+            files_processed,
+            move_checksum_mismatches,
+            campaigns_created,
+        }
+    }
+
+    fn reject(&self, reason: &str, count: u64) {
+        self.rows_rejected.with_label_values(&[reason]).inc_by(count);
+    }
+}
+
+/// Timezone used by [`log_timestamp`], set once from `LOG_TIMEZONE`.
+static LOG_TIMEZONE: OnceLock<LogTimezone> = OnceLock::new();
+
+/// Sets the timezone for log and lock-file timestamps. Only the first call has
+/// an effect; until then the host's local time is used.
+pub fn set_log_timezone(timezone: LogTimezone) {
+    let _ = LOG_TIMEZONE.set(timezone);
+}
+
+/// Current time formatted for log lines, in the configured `LOG_TIMEZONE`.
+pub fn log_timestamp() -> String {
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    match LOG_TIMEZONE.get().copied().unwrap_or(LogTimezone::Local) {
+        LogTimezone::Local => Local::now().format(FORMAT).to_string(),
+        LogTimezone::Named(tz) => Utc::now().with_timezone(&tz).format(FORMAT).to_string(),
+    }
+}
+
+/// Vertical assigned to new campaigns whose filename doesn't encode one.
+const DEFAULT_VERTICAL: i64 = 1;
+
+/// Default filename pattern: `<timestamp>_skipAI_<flag>_[V<vertical>_]<original name>.csv`.
+const DEFAULT_FILENAME_REGEX: &str =
+    r"^(?P<timestamp>\d+)_skipAI_(?P<skip_ai>\d+)_(?:V(?P<vertical>\d+)_)?(?P<name>.+\.csv)$";
+
+/// Compiled filename pattern. The pattern must provide the timestamp, skip-AI
+/// flag and original filename either as the named groups `timestamp`, `skip_ai`
+/// and `name`, or as the first three positional groups in that order. An optional
+/// named `vertical` group sets the vertical of new campaigns.
+#[derive(Clone)]
+pub struct FilenamePattern {
+    regex: Regex,
+    named: bool,
+}
+
+/// The pieces of an upload's filename extracted by `FilenamePattern`.
+struct FilenameParts<'a> {
+    _timestamp: &'a str,
+    skip_ai_flag: i64,
+    vertical: i64,
+    original_filename: &'a str,
+}
+
+impl FilenamePattern {
+    const REQUIRED_GROUPS: [&'static str; 3] = ["timestamp", "skip_ai", "name"];
+
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .with_context(|| format!("Invalid FILENAME_REGEX: {}", pattern))?;
+        let names: HashSet<&str> = regex.capture_names().flatten().collect();
+        let missing: Vec<&str> = Self::REQUIRED_GROUPS
+            .iter()
+            .filter(|group| !names.contains(*group))
+            .copied()
+            .collect();
+        let named = missing.is_empty();
+        // Naming only some of the groups is almost certainly a typo.
+        if !named && missing.len() != Self::REQUIRED_GROUPS.len() {
+            return Err(anyhow::anyhow!(
+                "FILENAME_REGEX is missing named groups {:?}: {}",
+                missing,
+                pattern
+            ));
+        }
+        // `captures_len` includes the implicit whole-match group.
+        if !named && regex.captures_len() < Self::REQUIRED_GROUPS.len() + 1 {
+            return Err(anyhow::anyhow!(
+                "FILENAME_REGEX needs the named groups {:?} or at least three capture groups: {}",
+                Self::REQUIRED_GROUPS,
+                pattern
+            ));
+        }
+        Ok(Self { regex, named })
+    }
+
+    /// Whether a filename fits the pattern.
+    pub fn matches(&self, file_name: &str) -> bool {
+        self.parse(file_name).is_some()
+    }
+
+    /// Matches a filename, returning `None` if it doesn't fit the pattern.
+    fn parse<'a>(&self, file_name: &'a str) -> Option<FilenameParts<'a>> {
+        let captures = self.regex.captures(file_name)?;
+        let group = |name: &str, index: usize| {
+            if self.named {
+                captures.name(name)
+            } else {
+                captures.get(index)
+            }
+            .map(|m| m.as_str())
+        };
+        Some(FilenameParts {
+            _timestamp: group("timestamp", 1)?,
+            skip_ai_flag: group("skip_ai", 2)?.parse().unwrap_or(0),
+            vertical: captures
+                .name("vertical")
+                .and_then(|m| m.as_str().parse().ok())
+                .unwrap_or(DEFAULT_VERTICAL),
+            original_filename: group("name", 3)?,
+        })
+    }
+}
+
+/// Cleans up campaign names derived from upload filenames. Listed suffixes are
+/// stripped from the end of the stem (repeatedly, ignoring case), then the
+/// optional regex replacement is applied. The default is the identity.
+#[derive(Clone, Default)]
+pub struct CampaignNameTransform {
+    strip_suffixes: Vec<String>,
+    replace: Option<(Regex, String)>,
+}
+
+impl CampaignNameTransform {
+    /// `template` uses the `regex` crate's replacement syntax (`$1`, `${name}`).
+    pub fn new(strip_suffixes: Vec<String>, replace: Option<(&str, &str)>) -> Result<Self> {
+        let replace = match replace {
+            Some((pattern, template)) => Some((
+                Regex::new(pattern)
+                    .with_context(|| format!("Invalid CAMPAIGN_NAME_REGEX: {}", pattern))?,
+                template.to_string(),
+            )),
+            None => None,
+        };
+        Ok(Self {
+            strip_suffixes,
+            replace,
+        })
+    }
+
+    /// Returns the cleaned name, or the stem unchanged if cleaning would leave nothing.
+    fn apply(&self, stem: &str) -> String {
+        let mut name = stem;
+        while let Some(suffix) = self.strip_suffixes.iter().find(|suffix| {
+            name.len() > suffix.len()
+                && name.is_char_boundary(name.len() - suffix.len())
+                && name[name.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        }) {
+            name = &name[..name.len() - suffix.len()];
+        }
+
+        let name = match &self.replace {
+            Some((regex, template)) => regex.replace(name, template.as_str()).trim().to_string(),
+            None => name.to_string(),
+        };
+        if name.is_empty() {
+            stem.to_string()
+        } else {
+            name
+        }
+    }
+}
+
+/// Struct representing a record to be inserted into the `address` table.
+#[derive(Debug, Default, Serialize)]
+pub struct AddressRecord {
+    pub street: String,
+    pub unit_type: String,
+    pub unit_num: String,
+    pub mail_city: String,
+    pub state: String, // New field for property_address_state
+    pub zip: String,
+    /// `None` is stored as NULL; see `MISSING_COORDINATES`.
+    pub latitude: Option<String>,
+    pub longitude: Option<String>,
+    pub fullname: String,
+    pub fname: String,
+    pub lname: String,
+    pub mailing_address: String,
+    pub mailing_city: String,
+    pub mailing_state: String,
+    pub mailing_zip: String,
+    pub flag: i64,
+    pub dmid: String,
+    pub via: i64,
+    pub map_image_url: String,
+    /// Lower-cased owner email; only inserted with `STORE_EMAIL`.
+    pub email: String,
+    /// Whether the mailing address is the property itself, `None` when either
+    /// is too incomplete to tell; only inserted with `STORE_OWNER_OCCUPIED`.
+    pub owner_occupied: Option<bool>,
+}
+
+/// Struct representing a record to be inserted into the `phonequeue` table.
+#[derive(Debug, Serialize)]
+pub struct PhoneQueueRecord {
+    /// Numbers for `phone1`, `phone2`, ... in order; at most `PHONE_COLUMNS`
+    /// of them. Columns beyond the last number are inserted as NULL.
+    pub phones: Vec<String>,
+}
+
+/// The default `phonequeue` phone column names for `PHONE_COLUMNS`: `phone1` to `phone<N>`.
+fn phone_column_names(count: usize) -> Vec<String> {
+    (1..=count).map(|slot| format!("phone{}", slot)).collect()
+}
+
+/// Combined record that holds both the address data and its optional phone data.
+/// This ensures the ordering is maintained so that each phone record is matched with
+/// the correct address row.
+#[derive(Debug)]
+struct CombinedRecord {
+    address: AddressRecord,
+    phone: Option<PhoneQueueRecord>,
+}
+
+/// A campaign that processing the upload directory would create.
+#[derive(Debug)]
+pub struct NewCampaign {
+    pub name: String,
+    pub files: Vec<PathBuf>,
+}
+
+/// Imports CSV files into the database. Holds the connection pool and the set of
+/// phone numbers already in `phonequeue`, which is loaded once when the importer
+/// is created; create a new importer to pick up numbers written by other processes.
+/// With `OUTPUT_MODE=jsonl` there is no pool, and records are written to files.
+pub struct Importer {
+    config: Config,
+    pool: Option<Pool<MySql>>,
+    phone_set: tokio::sync::Mutex<PhoneSet>,
+    /// Opted-out phones from `OPTOUT_TABLE`, never assigned to a row.
+    optout: HashSet<String>,
+    limit: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    files: Option<Vec<PathBuf>>,
+    /// Campaigns created by the current run, for `MAX_NEW_CAMPAIGNS_PER_RUN`.
+    campaigns_created: AtomicUsize,
+}
+
+impl Importer {
+    /// Connects to the database, verifies the address columns and starts
+    /// loading the phone set (in the background with `CONCURRENT_PREFETCH`).
+    /// With `OUTPUT_MODE=jsonl` nothing connects, and the phone set starts out
+    /// with the numbers in `PHONE_SEED_FILE`, if any.
+    pub async fn new(config: Config) -> Result<Self> {
+        LazyLock::force(&METRICS);
+        set_log_timezone(config.log_timezone);
+
+        if config.output_mode == OutputMode::Jsonl {
+            let phones = match &config.phone_seed_file {
+                Some(path) => load_phone_seed(path)?,
+                None => HashSet::new(),
+            };
+            return Ok(Self {
+                config,
+                pool: None,
+                phone_set: tokio::sync::Mutex::new(PhoneSet::Ready(phones)),
+                optout: HashSet::new(),
+                limit: None,
+                campaign_flag: None,
+                deadline: None,
+                files: None,
+                campaigns_created: AtomicUsize::new(0),
+            });
+        }
+
+        // Establish a connection pool to the MySQL database.
+        let pool = connect(&config).await?;
+
+        verify_address_columns(&pool, &config.address_table, &config.address_columns).await?;
+        verify_phone_columns(&pool, &config.phonequeue_table, &config.phone_columns).await?;
+
+        // Prefetch all phone numbers from the database. In concurrent mode the
+        // prefetch runs in the background while files are scanned and the first
+        // file's header, campaign and DMIDs are set up. Either way it overlaps
+        // with loading the opt-out list.
+        let optout = async {
+            match &config.optout_table {
+                Some(table) => prefetch_optout(&pool, table).await,
+                None => Ok(HashSet::new()),
+            }
+        };
+        let (phone_set, optout) = if config.concurrent_prefetch {
+            let phone_set = PhoneSet::spawn(&pool, config.phonequeue_table.clone(), config.phone_columns.clone());
+            (phone_set, optout.await?)
+        } else {
+            let (phones, optout) = tokio::join!(
+                prefetch_all_phone_numbers(&pool, &config.phonequeue_table, &config.phone_columns),
+                optout
+            );
+            (PhoneSet::Ready(phones.context("Failed to prefetch phone numbers")?), optout?)
+        };
+
+        Ok(Self {
+            config,
+            pool: Some(pool),
+            phone_set: tokio::sync::Mutex::new(phone_set),
+            optout,
+            limit: None,
+            campaign_flag: None,
+            deadline: None,
+            files: None,
+            campaigns_created: AtomicUsize::new(0),
+        })
+    }
+
+    /// Stops once this many rows have been inserted, leaving the rest for a later run.
+    pub fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Loads every file into the existing campaign with this flag instead of the
+    /// campaign named after the file. The campaign must already exist.
+    pub fn with_campaign_flag(mut self, flag: Option<i64>) -> Self {
+        self.campaign_flag = flag;
+        self
+    }
+
+    /// Stops reading once this instant passes, even in the middle of a file,
+    /// and doesn't start another file; see `MAX_TOTAL_SECONDS`. The deadline is
+    /// computed by the caller so that setup time counts against it too.
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Processes exactly these files, in this order, instead of the files found
+    /// in `UPLOAD_DIR`. They may live in any directory.
+    pub fn with_files(mut self, files: Option<Vec<PathBuf>>) -> Self {
+        self.files = files;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Lists the campaigns a run would create, with the files that would create
+    /// them, without changing anything. Empty with `--flag`, without a database
+    /// or for an S3 upload directory.
+    pub async fn new_campaigns(&self) -> Result<Vec<NewCampaign>> {
+        let config = &self.config;
+        let s3_source = self.files.is_none() && config.upload_dir.starts_with("s3://");
+        let pool = match &self.pool {
+            Some(pool) if self.campaign_flag.is_none() && !s3_source => pool,
+            _ => return Ok(Vec::new()),
+        };
+        let files = match &self.files {
+            Some(files) => files.clone(),
+            None if !Path::new(&config.upload_dir).is_dir() => return Ok(Vec::new()),
+            None => get_csv_files(&config.upload_dir, &config.upload_glob, &config.output_dirs())
+                .context("Failed to retrieve CSV files")?,
+        };
+        let mut by_name: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for path in files {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if let Some(parts) = config.filename_pattern.parse(&file_name) {
+                let (_, campaign_name) = campaign_name_for(config, parts.original_filename);
+                by_name.entry(campaign_name).or_default().push(path);
+            }
+        }
+        let mut new_campaigns = Vec::new();
+        for (name, files) in by_name {
+            if find_campaign(pool, &name).await?.is_none() {
+                new_campaigns.push(NewCampaign { name, files });
+            }
+        }
+        Ok(new_campaigns)
+    }
+
+    /// Processes a single CSV file, marking it done according to `PROCESSED_TRACKING`
+    /// once it completes. Unlike `process_directory`, no process lock is taken.
+    pub async fn process_file(&self, path: &Path) -> Result<FileSummary> {
+        let mut phone_set = self.phone_set.lock().await;
+        process_file(
+            self.pool.as_ref(),
+            path,
+            &self.config,
+            &mut phone_set,
+            &self.optout,
+            self.limit,
+            self.campaign_flag,
+            self.deadline,
+            &self.campaigns_created,
+        )
+        .await
+    }
+
+    /// Processes every CSV file in the upload directory under the process lock.
+    pub async fn process_directory(&self) -> Result<RunSummary> {
+        let start = Instant::now();
+        let mut summary = RunSummary::default();
+        self.run(&mut summary).await?;
+        summary.elapsed_seconds = start.elapsed().as_secs_f64();
+        Ok(summary)
+    }
+
+    /// Like `process_directory`, but accumulates into `summary`, so the stats of
+    /// files handled before an error are still available to the caller.
+    pub async fn run(&self, summary: &mut RunSummary) -> Result<()> {
+        let config = &self.config;
+        let pool = self.pool.as_ref();
+        self.campaigns_created.store(0, Ordering::SeqCst);
+        let s3_source = self.files.is_none() && config.upload_dir.starts_with("s3://");
+        if s3_source && !cfg!(feature = "s3") {
+            return Err(anyhow::anyhow!(
+                "UPLOAD_DIR {} is an S3 URL, but DMParser was built without the `s3` feature",
+                config.upload_dir
+            ));
+        }
+
+        if !s3_source {
+            // Ensure upload and processed directories exist.
+            if self.files.is_none() {
+                fs::create_dir_all(&config.upload_dir)
+                    .with_context(|| format!("Failed to create upload directory: {}", config.upload_dir))?;
+                warn_if_nested_dirs(&config.upload_dir, &config.output_dirs());
+            }
+            if config.processed_tracking == ProcessedTracking::Move {
+                fs::create_dir_all(&config.processed_dir)
+                    .with_context(|| format!("Failed to create processed directory: {}", config.processed_dir))?;
+            }
+        }
+
+        // Check the target campaign up front rather than failing every file.
+        if let (Some(flag), Some(pool)) = (self.campaign_flag, pool) {
+            campaign_for_flag(pool, flag).await?;
+        }
+
+        // Acquire a lock to prevent concurrent executions.
+        let _lock_guard = ProcessLock::acquire(config)
+            .with_context(|| "Failed to acquire process lock")?;
+
+        let mut global_phone_set = self.phone_set.lock().await;
+
+        #[cfg(feature = "s3")]
+        if s3_source {
+            return s3::process_bucket(
+                pool,
+                config,
+                self.limit,
+                self.campaign_flag,
+                self.deadline,
+                &self.campaigns_created,
+                &mut global_phone_set,
+                &self.optout,
+                summary,
+            )
+            .await;
+        }
+
+        let mut attempts = FileAttempts::load(&config.attempts_file)?;
+
+        // Return files left claimed by a crashed or killed run to the queue.
+        reclaim_stale_claims(&config.upload_dir, &config.upload_glob, Duration::from_secs(config.stale_claim_seconds));
+
+        // Retrieve list of CSV files to process, unless the caller chose them.
+        let mut files = match &self.files {
+            Some(files) => files.clone(),
+            None => get_csv_files(&config.upload_dir, &config.upload_glob, &config.output_dirs())
+                .context("Failed to retrieve CSV files")?,
+        };
+
+        // With database tracking, files stay in the upload directory once done. Skip
+        // any whose name and content were already recorded; a file replaced with new
+        // content under the same name is processed again.
+        if let (ProcessedTracking::Database, Some(pool)) = (config.processed_tracking, pool) {
+            let processed = fetch_processed_files(pool).await?;
+            let mut pending = Vec::with_capacity(files.len());
+            for path in files {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                match processed.get(&file_name) {
+                    Some(hashes) if hashes.contains(&file_sha256(&path)?) => {}
+                    _ => pending.push(path),
+                }
+            }
+            files = pending;
+        }
+
+        if files.is_empty() {
+            global_phone_set.abort();
+            eprintln!(
+                "[{}] No files to process.",
+                log_timestamp()
+            );
+            return Ok(()); // Nothing to do
+        }
+
+        // Process each CSV file individually.
+        for file_path in files {
+            // With --limit, stop once the run has inserted enough rows and leave the
+            // remaining files untouched.
+            let insert_budget = self.limit.map(|limit| limit.saturating_sub(summary.rows_inserted));
+            if insert_budget == Some(0) {
+                eprintln!(
+                    "Row limit of {} reached; leaving remaining files in {}.",
+                    self.limit.unwrap_or_default(),
+                    config.upload_dir
+                );
+                break;
+            }
+            if deadline_passed(self.deadline) {
+                eprintln!(
+                    "[{}] MAX_TOTAL_SECONDS reached; leaving remaining files in {}.",
+                    log_timestamp(),
+                    config.upload_dir
+                );
+                break;
+            }
+
+            let file_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let result = if is_zip_archive(&file_path) {
+                self.process_archive(&file_path, &mut global_phone_set, summary).await
+            } else {
+                process_file(
+                    pool,
+                    &file_path,
+                    config,
+                    &mut global_phone_set,
+                    &self.optout,
+                    insert_budget,
+                    self.campaign_flag,
+                    self.deadline,
+                    &self.campaigns_created,
+                )
+                .await
+                .map(|file_summary| {
+                    summary.record_file(&file_summary);
+                    file_summary.completed
+                })
+            };
+            match result {
+                Ok(completed) => {
+                    if completed {
+                        attempts.reset(&file_name)?;
+                    }
+                }
+                Err(e) => {
+                    // A failed background prefetch is fatal for the whole run, exactly
+                    // as it is when the prefetch runs up front.
+                    if let PhoneSet::Failed = *global_phone_set {
+                        return Err(e);
+                    }
+                    // Errors here are typically transient (e.g. a database outage), so the
+                    // file stays in the upload directory to be retried on the next run.
+                    // Malformed files are moved aside by `process_file` itself.
+                    summary.record_error(&file_path, &e);
+                    let failures = attempts.record_failure(&file_name)?;
+                    if failures < config.max_file_attempts {
+                        eprintln!(
+                            "Error processing file {:?} (attempt {} of {}): {:?}. The file was left in place for retry.",
+                            file_path, failures, config.max_file_attempts, e
+                        );
+                        continue;
+                    }
+
+                    // A file that keeps failing is moved out of the way so it can't block
+                    // every future run; it is kept for investigation, never discarded.
+                    eprintln!(
+                        "ERROR: File {:?} failed {} times and is being quarantined in {}. Last error: {:?}",
+                        file_path, failures, config.quarantine_dir, e
+                    );
+                    match quarantine_file(config, &file_path, &file_name) {
+                        Ok(()) => {
+                            attempts.reset(&file_name)?;
+                            summary.files_quarantined += 1;
+                        }
+                        Err(move_err) => eprintln!(
+                            "ERROR: Failed to quarantine {:?}: {:?}",
+                            file_path, move_err
+                        ),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Processes the CSV files inside a `.zip` upload, recording each in
+    /// `summary`. Returns whether the archive was finished.
+    #[cfg_attr(not(feature = "zip"), allow(unused_variables))]
+    async fn process_archive(
+        &self,
+        path: &Path,
+        global_phone_set: &mut PhoneSet,
+        summary: &mut RunSummary,
+    ) -> Result<bool> {
+        #[cfg(feature = "zip")]
+        return archive::process_archive(
+            self.pool.as_ref(),
+            path,
+            &self.config,
+            self.limit,
+            self.campaign_flag,
+            self.deadline,
+            &self.campaigns_created,
+            global_phone_set,
+            &self.optout,
+            summary,
+        )
+        .await;
+        #[cfg(not(feature = "zip"))]
+        Err(anyhow::anyhow!(
+            "{} is a zip archive, but DMParser was built without the `zip` feature",
+            path.display()
+        ))
+    }
+}
+
+/// Whether an upload is a `.zip` archive of CSV files.
+fn is_zip_archive(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Whether the run-wide `MAX_TOTAL_SECONDS` deadline, if any, has passed.
+pub(crate) fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Establishes a connection pool to the MySQL database.
+///
+/// A database that isn't reachable yet (for example right after a reboot) is
+/// retried up to `DB_CONNECT_ATTEMPTS` times with exponential backoff starting at
+/// `DB_CONNECT_BACKOFF_SECONDS`. Errors reported by the server itself, such as
+/// bad credentials, fail immediately.
+///
+/// `INIT_SQL` runs on every new pool connection. It also runs on the probe
+/// connection, so a statement the server rejects fails here rather than on the
+/// first query.
+pub async fn connect(config: &Config) -> Result<Pool<MySql>> {
+    let initial = Duration::from_secs(config.db_connect_backoff_seconds);
+    let mut backoff = backoff::ExponentialBackoff {
+        current_interval: initial,
+        initial_interval: initial,
+        randomization_factor: 0.25,
+        max_interval: Duration::from_secs(DB_CONNECT_MAX_BACKOFF_SECONDS),
+        multiplier: 2.0,
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+    // Probe with a single connection: the pool itself keeps retrying a refused
+    // connection until its acquire timeout, which would make every attempt slow.
+    let mut attempt = 1;
+    loop {
+        let probe = match MySqlConnection::connect(&config.database_url).await {
+            Ok(mut conn) => {
+                let result = run_init_sql(&mut conn, &config.init_sql).await;
+                let _ = conn.close().await;
+                result
+            }
+            Err(e) => Err(e),
+        };
+        let error = match probe {
+            Ok(()) => {
+                let init_sql = config.init_sql.clone();
+                return MySqlPoolOptions::new()
+                    .max_connections(5)
+                    .after_connect(move |conn, _meta| {
+                        let init_sql = init_sql.clone();
+                        Box::pin(async move { run_init_sql(conn, &init_sql).await })
+                    })
+                    .connect(&config.database_url)
+                    .await
+                    .context(DatabaseUnavailable);
+            }
+            Err(e) => e,
+        };
+        let retryable = !matches!(error, sqlx::Error::Database(_) | sqlx::Error::Configuration(_));
+        let delay = backoff::backoff::Backoff::next_backoff(&mut backoff);
+        match delay {
+            Some(delay) if retryable && attempt < config.db_connect_attempts => {
+                eprintln!(
+                    "[{}] Database not available (attempt {} of {}): {}. Retrying in {:.1}s.",
+                    log_timestamp(),
+                    attempt,
+                    config.db_connect_attempts,
+                    error,
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return Err(error).context(DatabaseUnavailable),
+        }
+    }
+}
+
+/// Runs the `INIT_SQL` statements on a new connection.
+async fn run_init_sql(conn: &mut MySqlConnection, statements: &[String]) -> sqlx::Result<()> {
+    for statement in statements {
+        sqlx::Executor::execute(&mut *conn, statement.as_str()).await?;
+    }
+    Ok(())
+}
+
+/// Connects once, without retries, and verifies the address and phone columns,
+/// for a preflight check of the configuration.
+pub async fn check_database(config: &Config) -> Result<()> {
+    let pool = connect(&Config {
+        db_connect_attempts: 1,
+        ..config.clone()
+    })
+    .await?;
+    let result = match verify_address_columns(&pool, &config.address_table, &config.address_columns).await {
+        Ok(()) => verify_phone_columns(&pool, &config.phonequeue_table, &config.phone_columns).await,
+        Err(e) => Err(e),
+    };
+    pool.close().await;
+    result
+}
+
+/// Confirms that every configured address column exists in the live address
+/// table, so a schema mismatch fails the run up front instead of every batch.
+async fn verify_address_columns(pool: &Pool<MySql>, table: &str, columns: &[&str]) -> Result<()> {
+    let missing = missing_columns(pool, table, columns.iter().copied()).await?;
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The {} table is missing columns {:?}. Add them, or list the optional ones in ADDRESS_OMIT_COLUMNS.",
+            table,
+            missing
+        ));
+    }
+    Ok(())
+}
+
+/// Confirms that the phonequeue table has the configured phone columns.
+async fn verify_phone_columns(pool: &Pool<MySql>, table: &str, phone_columns: &[String]) -> Result<()> {
+    let missing = missing_columns(pool, table, phone_columns.iter().map(String::as_str)).await?;
+    if !missing.is_empty() {
+        return Err(anyhow::anyhow!(
+            "The {} table is missing columns {:?}. Add them, or adjust PHONE_COLUMNS or PHONE_COLUMN_NAMES.",
+            table,
+            missing
+        ));
+    }
+    Ok(())
+}
+
+/// The given columns that don't exist in `table` of the current database.
+async fn missing_columns<'a>(
+    pool: &Pool<MySql>,
+    table: &str,
+    columns: impl Iterator<Item = &'a str>,
+) -> Result<Vec<&'a str>> {
+    let existing: HashSet<String> = sqlx::query_scalar(
+        "SELECT COLUMN_NAME FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = ?",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await
+    .with_context(|| format!("Failed to read {} table columns", table))?
+    .into_iter()
+    .collect();
+    Ok(columns
+        .filter(|column| !existing.iter().any(|c| c.eq_ignore_ascii_case(column)))
+        .collect())
+}
+
+/// Canonical schema, embedded from `sql/create_tables.sql` so that the script,
+/// `--migrate` and any column checks share a single definition.
+const SCHEMA_SQL: &str = include_str!("../sql/create_tables.sql");
+
+/// One `CREATE TABLE` statement from the canonical schema.
+struct TableSchema {
+    name: String,
+    columns: Vec<String>,
+    create_sql: String,
+}
+
+/// Splits the canonical schema into its tables and their column names.
+fn schema_tables() -> Vec<TableSchema> {
+    const CONSTRAINT_KEYWORDS: [&str; 6] = ["PRIMARY", "UNIQUE", "KEY", "INDEX", "FOREIGN", "CONSTRAINT"];
+
+    SCHEMA_SQL
+        .split(';')
+        .map(|statement| {
+            statement
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("--"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .filter_map(|statement| {
+            let statement = statement.trim();
+            let rest = statement.strip_prefix("CREATE TABLE IF NOT EXISTS ")?;
+            let name = rest.split_whitespace().next()?.to_string();
+            let body = &statement[statement.find('(')? + 1..statement.rfind(')')?];
+            let columns = body
+                .lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .filter(|word| !CONSTRAINT_KEYWORDS.contains(word))
+                .map(str::to_string)
+                .collect();
+            Some(TableSchema {
+                name,
+                columns,
+                create_sql: statement.to_string(),
+            })
+        })
+        .collect()
+}
+
+impl TableSchema {
+    /// The table with `ADDRESS_TABLE`, `PHONEQUEUE_TABLE` and
+    /// `PHONE_COLUMN_NAMES` applied.
+    fn configured(&self, config: &Config) -> TableSchema {
+        let name = match self.name.as_str() {
+            "address" => config.address_table.clone(),
+            "phonequeue" => config.phonequeue_table.clone(),
+            name => name.to_string(),
+        };
+        let mut columns = self.columns.clone();
+        let mut create_sql = self
+            .create_sql
+            .replacen(
+                &format!("CREATE TABLE IF NOT EXISTS {} ", self.name),
+                &format!("CREATE TABLE IF NOT EXISTS {} ", name),
+                1,
+            )
+            .replace("REFERENCES address(", &format!("REFERENCES {}(", config.address_table));
+        if self.name == "phonequeue" {
+            columns.retain(|column| !["phone1", "phone2", "phone3"].contains(&column.as_str()));
+            columns.extend(config.phone_columns.iter().cloned());
+            let phone_lines = config
+                .phone_columns
+                .iter()
+                .map(|column| format!("    {} VARCHAR(50),", column))
+                .collect::<Vec<_>>()
+                .join("\n");
+            create_sql = create_sql.replacen(
+                "    phone1 VARCHAR(50),\n    phone2 VARCHAR(50),\n    phone3 VARCHAR(50),",
+                &phone_lines,
+                1,
+            );
+        }
+        TableSchema {
+            name,
+            columns,
+            create_sql,
+        }
+    }
+}
+
+/// Creates any missing tables from the canonical schema, under the configured
+/// table and phone column names. Safe to run repeatedly.
+pub async fn migrate(pool: &Pool<MySql>, config: &Config) -> Result<()> {
+    for table in schema_tables() {
+        let table = table.configured(config);
+        sqlx::query(&table.create_sql)
+            .execute(pool)
+            .await
+            .with_context(|| format!("Failed to create table {}", table.name))?;
+        eprintln!(
+            "Ensured table {} ({} columns).",
+            table.name,
+            table.columns.len()
+        );
+    }
+
+    // Register phones that were inserted before the registry existed (or by
+    // writers that bypass it). Already-registered phones are left alone.
+    let selects = config
+        .phone_columns
+        .iter()
+        .map(|column| {
+            format!(
+                "SELECT TRIM({column}) FROM {table} WHERE {column} IS NOT NULL AND TRIM({column}) <> ''",
+                column = column,
+                table = config.phonequeue_table
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+    let backfilled = sqlx::query(&format!("INSERT IGNORE INTO phone_registry (phone) {}", selects))
+        .execute(pool)
+        .await
+        .context("Failed to backfill phone_registry")?
+        .rows_affected();
+    eprintln!("Backfilled {} phones into phone_registry.", backfilled);
+    Ok(())
+}
+
+/// Per-file statistics returned by `process_file`.
+#[derive(Debug, Default)]
+pub struct FileSummary {
+    pub rows_read: usize,
+    pub rows_inserted: usize,
+    pub rows_rejected: usize,
+    /// Rows with a blank latitude or longitude that the geocoder didn't fill,
+    /// whether kept or rejected.
+    pub rows_missing_coordinates: usize,
+    /// Rows whose coordinates came from `Config::geocoder`.
+    pub rows_geocoded: usize,
+    /// Rows mailed to the property address for lack of a mailing address.
+    pub rows_mailing_fallback: usize,
+    /// Non-empty candidate phones of the rows that got as far as phone
+    /// selection; the sum of the four fields below.
+    pub phones_seen: usize,
+    /// Candidate phones dropped because they were already taken, either in the
+    /// database or earlier in the run.
+    pub phones_existing: usize,
+    /// Candidate phones dropped because the same row already listed them.
+    pub phones_repeated: usize,
+    /// Phones assigned to this file's rows.
+    pub phones_new: usize,
+    /// Candidate phones dropped because they are in `OPTOUT_TABLE`.
+    pub phones_suppressed: usize,
+    /// False when processing stopped early (timeout or row limit) and the file
+    /// was left in the upload directory.
+    pub completed: bool,
+    /// True when the file had no data rows.
+    pub empty: bool,
+    /// True when the file's content matched an already processed file.
+    pub duplicate: bool,
+    /// Rows rejected by each validation rule, keyed by the rule's reason.
+    pub rule_rejections: BTreeMap<String, usize>,
+    /// Flag of the campaign the rows went to, whether found, created or given
+    /// with `--flag`. `None` when the file was set aside before a campaign was
+    /// chosen, or without a database and `--flag`.
+    pub campaign_flag: Option<i64>,
+}
+
+impl FileSummary {
+    /// Summary for a file that was handled without reading any rows
+    /// (claimed elsewhere, or rejected before processing).
+    fn skipped() -> Self {
+        Self {
+            completed: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// Aggregate statistics for a whole run, reported in the final log line and webhook.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub files_processed: usize,
+    pub files_incomplete: usize,
+    pub files_quarantined: usize,
+    pub files_empty: usize,
+    pub files_duplicate: usize,
+    pub rows_read: usize,
+    pub rows_inserted: usize,
+    pub rows_rejected: usize,
+    pub rows_missing_coordinates: usize,
+    pub rows_geocoded: usize,
+    pub rows_mailing_fallback: usize,
+    pub phones_seen: usize,
+    pub phones_existing: usize,
+    pub phones_repeated: usize,
+    pub phones_new: usize,
+    pub phones_suppressed: usize,
+    pub rule_rejections: BTreeMap<String, usize>,
+    /// Flags of the campaigns that files were loaded into.
+    pub campaign_flags: BTreeSet<i64>,
+    pub file_errors: Vec<FileError>,
+    pub elapsed_seconds: f64,
+}
+
+/// A file that failed to process, with the error that caused it.
+#[derive(Debug, Serialize)]
+pub struct FileError {
+    pub file: String,
+    pub error: String,
+}
+
+impl RunSummary {
+    fn record_file(&mut self, file: &FileSummary) {
+        self.files_processed += 1;
+        if !file.completed {
+            self.files_incomplete += 1;
+        }
+        if file.empty {
+            self.files_empty += 1;
+        }
+        if file.duplicate {
+            self.files_duplicate += 1;
+        }
+        self.rows_read += file.rows_read;
+        self.rows_inserted += file.rows_inserted;
+        self.rows_rejected += file.rows_rejected;
+        self.rows_missing_coordinates += file.rows_missing_coordinates;
+        self.rows_geocoded += file.rows_geocoded;
+        self.rows_mailing_fallback += file.rows_mailing_fallback;
+        self.phones_seen += file.phones_seen;
+        self.phones_existing += file.phones_existing;
+        self.phones_repeated += file.phones_repeated;
+        self.phones_new += file.phones_new;
+        self.phones_suppressed += file.phones_suppressed;
+        self.campaign_flags.extend(file.campaign_flag);
+        for (reason, count) in &file.rule_rejections {
+            *self.rule_rejections.entry(reason.clone()).or_default() += count;
+        }
+    }
+
+    fn record_error(&mut self, file_path: &Path, error: &anyhow::Error) {
+        self.file_errors.push(FileError {
+            file: file_path.display().to_string(),
+            error: format!("{:#}", error),
+        });
+    }
+}
+
+/// Records the start of a run in the `runs` table and returns its ID, for
+/// `update_run_end`.
+pub async fn insert_run_start(pool: &Pool<MySql>) -> Result<u64> {
+    let result = sqlx::query("INSERT INTO runs (host, status) VALUES (?, 'running')")
+        .bind(host_name())
+        .execute(pool)
+        .await
+        .context("Failed to record run start")?;
+    Ok(result.last_insert_id())
+}
+
+/// Completes a run's row in the `runs` table with its totals and exit status.
+/// Files that failed count as attempted but not succeeded, as do files left
+/// partially processed.
+pub async fn update_run_end(pool: &Pool<MySql>, run_id: u64, summary: &RunSummary, status: ExitStatus) -> Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE runs
+        SET finished_at = CURRENT_TIMESTAMP, status = ?, exit_code = ?,
+            files_attempted = ?, files_succeeded = ?, rows_inserted = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(status.name())
+    .bind(status.code())
+    .bind((summary.files_processed + summary.file_errors.len()) as u64)
+    .bind((summary.files_processed - summary.files_incomplete) as u64)
+    .bind(summary.rows_inserted as u64)
+    .bind(run_id)
+    .execute(pool)
+    .await
+    .context("Failed to record run end")?;
+    Ok(())
+}
+
+/// This machine's host name, for the `runs` table.
+fn host_name() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Replaces this job's metrics on the pushgateway with the run's values.
+pub async fn push_metrics(url: &str) -> Result<()> {
+    let mut body = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut body)
+        .context("Failed to encode metrics")?;
+
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(PUSHGATEWAY_TIMEOUT_SECONDS))
+        .build()?
+        .put(format!("{}/metrics/job/dmparser", url.trim_end_matches('/')))
+        .header(reqwest::header::CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(body)
+        .send()
+        .await
+        .context("Pushgateway request failed")?
+        .error_for_status()
+        .context("Pushgateway returned an error status")?;
+    Ok(())
+}
+
+/// POSTs the run summary to the configured webhook. The payload carries a
+/// human-readable `text` line (so it renders directly in Slack) alongside the
+/// structured stats.
+pub async fn send_webhook(url: &str, summary: &RunSummary, run_error: Option<&str>) -> Result<()> {
+    let status = if run_error.is_some() { "error" } else { "success" };
+    let mut text = format!(
+        "DMParser run {}: {} files processed, {} failed, {} rows inserted, {} rows rejected in {:.1}s",
+        status,
+        summary.files_processed,
+        summary.file_errors.len(),
+        summary.rows_inserted,
+        summary.rows_rejected,
+        summary.elapsed_seconds
+    );
+    if let Some(e) = run_error {
+        text.push_str(&format!("\nError: {}", e));
+    }
+
+    let payload = serde_json::json!({
+        "text": text,
+        "status": status,
+        "error": run_error,
+        "summary": summary,
+    });
+
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+        .build()?
+        .post(url)
+        .json(&payload)
+        .send()
+        .await
+        .context("Webhook request failed")?
+        .error_for_status()
+        .context("Webhook returned an error status")?;
+    Ok(())
+}
+
+/// Loads configuration from environment variables.
+#[derive(Clone)]
+pub struct Config {
+    /// Empty with `OUTPUT_MODE=jsonl` when `DATABASE_URL` is unset.
+    pub database_url: String,
+    pub output_mode: OutputMode,
+    /// `ADDRESS_TABLE`; a plain identifier, as it is interpolated into SQL.
+    pub address_table: String,
+    /// `PHONEQUEUE_TABLE`; a plain identifier, as it is interpolated into SQL.
+    pub phonequeue_table: String,
+    /// Table of opted-out phones, in its `phone` column; unset to disable.
+    pub optout_table: Option<String>,
+    pub jsonl_dir: String,
+    /// File of phone numbers, one per line, that seeds the phone set in jsonl mode.
+    pub phone_seed_file: Option<String>,
+    pub upload_dir: String,
+    pub upload_glob: String,
+    pub output_layout: OutputLayout,
+    pub validation_rules: Vec<ValidationRule>,
+    pub processed_dir: String,
+    pub lock_file_path: String,
+    pub lock_mode: LockMode,
+    pub batch_size: usize,
+    pub insert_chunk_size: usize,
+    pub max_execution_seconds: u64,
+    /// Extra seconds a file past `max_execution_seconds` may take when it is
+    /// estimated to finish within them; `0` stops it at the limit.
+    pub soft_timeout_grace_seconds: u64,
+    /// Limit for the whole run, across files; `None` when unset or `0`.
+    pub max_total_seconds: Option<u64>,
+    pub concurrent_prefetch: bool,
+    pub dmid_prefetch: DmidPrefetchStrategy,
+    pub webhook_url: Option<String>,
+    /// Record each run in the `runs` table.
+    pub run_log_enabled: bool,
+    /// Mask record values (emails, phones, names) before they are logged.
+    pub redact_pii: bool,
+    pub pushgateway_url: Option<String>,
+    pub stale_claim_seconds: u64,
+    pub name_case: NameCase,
+    pub normalize_names: bool,
+    pub filename_pattern: FilenamePattern,
+    pub campaign_name_transform: CampaignNameTransform,
+    pub csv_dialect: CsvDialect,
+    pub duplicate_header_policy: DuplicateHeaderPolicy,
+    /// Fewest header fields a file may have before the precheck rejects it.
+    pub min_header_columns: usize,
+    pub duplicate_row_selection: DuplicateRowSelection,
+    pub address_dedup: AddressDedup,
+    pub missing_coordinates: MissingCoordinates,
+    /// Tried before `missing_coordinates` applies. Not read from the
+    /// environment; embedding code replaces the no-op default.
+    pub geocoder: Arc<dyn Geocoder>,
+    pub state_filter: StateFilter,
+    /// Statements run on every new database connection.
+    pub init_sql: Vec<String>,
+    pub processed_tracking: ProcessedTracking,
+    pub phone_dedup_mode: PhoneDedupMode,
+    pub phone_conflict_policy: PhoneConflictPolicy,
+    pub quarantine_dir: String,
+    pub duplicates_dir: Option<String>,
+    /// Re-hash files after moving them to `processed_dir` and compare.
+    pub verify_moves: bool,
+    pub rejects_dir: Option<String>,
+    pub max_file_attempts: u32,
+    pub db_connect_attempts: u32,
+    pub db_connect_backoff_seconds: u64,
+    pub attempts_file: String,
+    pub resume_file: Option<String>,
+    pub owner_mode: OwnerMode,
+    /// `via` and placeholder map image for each skip-AI flag value.
+    pub skip_ai_tiers: SkipAiTiers,
+    pub dedup_key_columns: Vec<String>,
+    /// Values for required columns that a file may lack, by column name.
+    pub column_defaults: BTreeMap<String, String>,
+    pub address_columns: Vec<&'static str>,
+    pub store_email: bool,
+    pub email_column: String,
+    pub require_phone: bool,
+    /// Reject rows whose property street, city or zip is blank.
+    pub require_property_address: bool,
+    /// Store blank address text fields as NULL instead of `''`.
+    pub empty_as_null: bool,
+    /// Decode HTML entities in name and address fields.
+    pub decode_html_entities: bool,
+    /// Literal `(from, to)` replacements applied to name and address fields,
+    /// in order, after entity decoding.
+    pub name_replacements: Vec<(String, String)>,
+    /// Copy the property address into blank mailing address fields.
+    pub mailing_fallback_to_property: bool,
+    /// Decimal places coordinates are rounded to; `None` stores them as read.
+    pub coord_decimals: Option<usize>,
+    /// `phonequeue` phone column names in slot order; `phone1` to `phone<N>`
+    /// unless `PHONE_COLUMN_NAMES` is set.
+    pub phone_columns: Vec<String>,
+    pub quarantine_empty_files: bool,
+    pub collapse_whitespace: bool,
+    pub commit_strategy: CommitStrategy,
+    /// With `PerFile`, commit the file's transaction early once this many rows
+    /// are uncommitted.
+    pub max_pending_rows: Option<usize>,
+    pub staging_mode: bool,
+    pub max_inserts_per_second: Option<u64>,
+    pub normalize_address: bool,
+    pub parse_unit_type: bool,
+    pub unit_type_column: Option<String>,
+    pub log_batch_timings: bool,
+    pub slow_batch_ms: Option<u64>,
+    /// Send each chunk's address and phone INSERTs as one multi-statement query.
+    pub fast_batch: bool,
+    pub log_timezone: LogTimezone,
+    pub campaign_isolation_level: Option<IsolationLevel>,
+    /// When false, a file whose campaign doesn't exist yet fails instead of
+    /// creating it.
+    pub allow_new_campaigns: bool,
+    /// Match variant header names to the expected columns (`HEADER_ALIASES`).
+    pub header_aliases: bool,
+    /// Most campaigns a run may create; files needing more are quarantined.
+    pub max_new_campaigns_per_run: Option<usize>,
+    pub progress_interval_seconds: u64,
+    pub progress_every_batches: usize,
+}
+
+/// Transaction isolation level for campaign creation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::ReadUncommitted => "READ UNCOMMITTED",
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+impl FromStr for IsolationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace([' ', '-'], "_").as_str() {
+            "read_uncommitted" => Ok(Self::ReadUncommitted),
+            "read_committed" => Ok(Self::ReadCommitted),
+            "repeatable_read" => Ok(Self::RepeatableRead),
+            "serializable" => Ok(Self::Serializable),
+            other => Err(format!(
+                "expected `read_uncommitted`, `read_committed`, `repeatable_read` or `serializable`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+/// Where phone uniqueness is enforced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhoneDedupMode {
+    /// Only the in-memory set prefetched at startup.
+    Memory,
+    /// The in-memory set as a fast path, with `phone_registry` as the final arbiter.
+    Db,
+}
+
+impl FromStr for PhoneDedupMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "db" => Ok(Self::Db),
+            other => Err(format!("expected `memory` or `db`, got `{}`", other)),
+        }
+    }
+}
+
+/// What happens to a record whose phones were all registered by another writer
+/// (database phone dedup only).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhoneConflictPolicy {
+    /// Keep the address without a phonequeue row.
+    KeepAddress,
+    /// Drop the record, as the in-memory check would have.
+    DropRecord,
+}
+
+impl FromStr for PhoneConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "keep_address" => Ok(Self::KeepAddress),
+            "drop_record" => Ok(Self::DropRecord),
+            other => Err(format!("expected `keep_address` or `drop_record`, got `{}`", other)),
+        }
+    }
+}
+
+/// When the rows of a file are committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitStrategy {
+    /// Every batch is its own transaction.
+    PerBatch,
+    /// One transaction per file, each batch in a savepoint, committed once the
+    /// file stops cleanly or `MAX_PENDING_ROWS` rows are uncommitted.
+    PerFile,
+}
+
+impl FromStr for CommitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "per_batch" => Ok(Self::PerBatch),
+            "per_file" => Ok(Self::PerFile),
+            other => Err(format!("expected `per_batch` or `per_file`, got `{}`", other)),
+        }
+    }
+}
+
+/// How a file is marked as done once processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessedTracking {
+    /// Move the file into the processed directory.
+    Move,
+    /// Leave the file in place and record its name and content hash in `processed_files`.
+    Database,
+}
+
+impl FromStr for ProcessedTracking {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "move" => Ok(Self::Move),
+            "database" => Ok(Self::Database),
+            other => Err(format!("expected `move` or `database`, got `{}`", other)),
+        }
+    }
+}
+
+/// A row-level check from `VALIDATION_RULES_FILE`. A row whose `column` matches
+/// the rule is rejected, with `reason` recorded in the run summary.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationRule {
+    pub column: String,
+    #[serde(flatten)]
+    pub check: RuleCheck,
+    pub reason: Option<String>,
+    #[serde(skip)]
+    regex: Option<Regex>,
+}
+
+/// How a `ValidationRule` tests a field (trimmed). Text comparisons ignore ASCII case.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RuleCheck {
+    Equals { value: String },
+    In { values: Vec<String> },
+    Matches { pattern: String },
+    /// Inclusive bounds; fields that aren't numbers never match.
+    InRange { min: f64, max: f64 },
+}
+
+impl ValidationRule {
+    /// Reads a JSON array of rules and compiles their patterns.
+    fn load(path: &str) -> Result<Vec<Self>> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read validation rules file {}", path))?;
+        let mut rules: Vec<Self> = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse validation rules file {}", path))?;
+        for rule in &mut rules {
+            if let RuleCheck::Matches { pattern } = &rule.check {
+                rule.regex = Some(
+                    Regex::new(pattern)
+                        .with_context(|| format!("Invalid pattern in validation rule for {}: {}", rule.column, pattern))?,
+                );
+            }
+        }
+        Ok(rules)
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match &self.check {
+            RuleCheck::Equals { value: expected } => value.eq_ignore_ascii_case(expected),
+            RuleCheck::In { values } => values.iter().any(|expected| value.eq_ignore_ascii_case(expected)),
+            RuleCheck::Matches { .. } => self.regex.as_ref().is_some_and(|regex| regex.is_match(value)),
+            RuleCheck::InRange { min, max } => value
+                .parse::<f64>()
+                .is_ok_and(|number| (*min..=*max).contains(&number)),
+        }
+    }
+
+    /// The configured reason, or a description of the rule.
+    fn reason(&self) -> String {
+        if let Some(reason) = &self.reason {
+            return reason.clone();
+        }
+        match &self.check {
+            RuleCheck::Equals { value } => format!("{} equals {:?}", self.column, value),
+            RuleCheck::In { values } => format!("{} in {:?}", self.column, values),
+            RuleCheck::Matches { pattern } => format!("{} matches {:?}", self.column, pattern),
+            RuleCheck::InRange { min, max } => format!("{} in range {}..={}", self.column, min, max),
+        }
+    }
+}
+
+/// Where files from upload subdirectories go when moved to an output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputLayout {
+    /// Directly into the output directory, under the file name only.
+    Flatten,
+    /// Under the same relative subdirectory they had in the upload directory.
+    Preserve,
+}
+
+impl FromStr for OutputLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "flatten" => Ok(Self::Flatten),
+            "preserve" => Ok(Self::Preserve),
+            other => Err(format!("expected `flatten` or `preserve`, got `{}`", other)),
+        }
+    }
+}
+
+/// Which column is used when a file has several columns with the same header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateHeaderPolicy {
+    First,
+    Last,
+    /// Quarantine the file without importing it.
+    Fail,
+}
+
+impl FromStr for DuplicateHeaderPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            "fail" => Ok(Self::Fail),
+            other => Err(format!("expected `first`, `last` or `fail`, got `{}`", other)),
+        }
+    }
+}
+
+/// The `via` and `map_image_url` written for rows with a given skip-AI flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipAiTier {
+    pub via: i64,
+    pub map_image_url: String,
+}
+
+/// Maps skip-AI flag values to tiers. Flags without an entry of their own use
+/// the fallback tier, which by default skips AI entirely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipAiTiers {
+    tiers: BTreeMap<i64, SkipAiTier>,
+    fallback: SkipAiTier,
+}
+
+impl SkipAiTiers {
+    pub fn get(&self, flag: i64) -> &SkipAiTier {
+        self.tiers.get(&flag).unwrap_or(&self.fallback)
+    }
+}
+
+impl Default for SkipAiTiers {
+    fn default() -> Self {
+        SkipAiTiers {
+            tiers: BTreeMap::from([(
+                0,
+                SkipAiTier {
+                    via: 0,
+                    map_image_url: "0".to_string(),
+                },
+            )]),
+            fallback: SkipAiTier {
+                via: 100,
+                map_image_url: "google/img/missing.webp".to_string(),
+            },
+        }
+    }
+}
+
+/// Parses comma-separated `<flag>=<via>:<map_image_url>` entries, with `*` as
+/// the flag of the fallback tier. Entries override the defaults.
+impl FromStr for SkipAiTiers {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut tiers = SkipAiTiers::default();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (flag, tier) = entry
+                .split_once('=')
+                .and_then(|(flag, tier)| Some((flag.trim(), tier.split_once(':')?)))
+                .ok_or_else(|| format!("expected `<flag>=<via>:<map_image_url>`, got `{}`", entry))?;
+            let tier = SkipAiTier {
+                via: tier.0.trim().parse().map_err(|_| format!("invalid via `{}` in `{}`", tier.0, entry))?,
+                map_image_url: tier.1.trim().to_string(),
+            };
+            if flag == "*" {
+                tiers.fallback = tier;
+            } else {
+                let flag = flag.parse().map_err(|_| format!("invalid flag `{}` in `{}`", flag, entry))?;
+                tiers.tiers.insert(flag, tier);
+            }
+        }
+        Ok(tiers)
+    }
+}
+
+/// Which row is imported when a file has several rows with the same dedup key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateRowSelection {
+    First,
+    /// The row with the most phone numbers, then the most non-empty fields;
+    /// the earliest of equally complete rows wins.
+    MostComplete,
+}
+
+impl FromStr for DuplicateRowSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "first" => Ok(Self::First),
+            "most_complete" => Ok(Self::MostComplete),
+            other => Err(format!("expected `first` or `most_complete`, got `{}`", other)),
+        }
+    }
+}
+
+/// In-file dedup of rows that describe the same property under different lead IDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressDedup {
+    Off,
+    /// Street, unit and zip as read.
+    Exact,
+    /// USPS-normalized street, unit number without its designator, and 5-digit zip.
+    Normalized,
+}
+
+impl FromStr for AddressDedup {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "exact" => Ok(Self::Exact),
+            "normalized" => Ok(Self::Normalized),
+            other => Err(format!("expected `off`, `exact` or `normalized`, got `{}`", other)),
+        }
+    }
+}
+
+impl AddressDedup {
+    /// The key identifying a property for in-file dedup, or `None` when dedup is
+    /// off or the row has no street. `unit_line` is the unparsed secondary line.
+    fn key(self, street: &str, unit_line: &str, zip: &str) -> Option<String> {
+        if street.trim().is_empty() {
+            return None;
+        }
+        match self {
+            AddressDedup::Off => None,
+            AddressDedup::Exact => Some(format!("{}|{}|{}", street.trim(), unit_line.trim(), zip.trim())),
+            AddressDedup::Normalized => {
+                let (_, unit_num) = split_unit_designator(unit_line);
+                let unit_num: String = unit_num
+                    .chars()
+                    .filter(|c| !c.is_whitespace())
+                    .collect::<String>()
+                    .to_uppercase();
+                let zip: String = zip.trim().chars().take(5).collect();
+                Some(format!("{}|{}|{}", normalize_address(street), unit_num, zip))
+            }
+        }
+    }
+}
+
+/// Looks up coordinates for rows that arrive without them. Set `Config::geocoder`
+/// to an implementation backed by a real provider before creating the
+/// `Importer`:
+///
+/// ```ignore
+/// use dmparser::{AddressRecord, BoxFuture, Config, Geocoder};
+///
+/// struct MyGeocoder { client: reqwest::Client }
+///
+/// impl Geocoder for MyGeocoder {
+///     fn geocode<'a>(&'a self, address: &'a AddressRecord) -> BoxFuture<'a, Result<Option<(f64, f64)>>> {
+///         Box::pin(async move {
+///             // Query the provider with address.street, address.mail_city,
+///             // address.state and address.zip.
+///             Ok(None)
+///         })
+///     }
+/// }
+///
+/// let mut config = Config::from_env()?;
+/// config.geocoder = Arc::new(MyGeocoder { client: reqwest::Client::new() });
+/// ```
+///
+/// Only the property fields of `address` (street, unit, city, state and zip)
+/// are filled in. `Ok(None)` means no match, and the row is handled by
+/// `MISSING_COORDINATES`, as is a row whose lookup fails, after a warning.
+/// Rows rejected for other reasons are never looked up. Calls are made one row
+/// at a time, so cache or rate-limit inside the implementation as the provider
+/// requires.
+pub trait Geocoder: Send + Sync {
+    fn geocode<'a>(&'a self, address: &'a AddressRecord) -> BoxFuture<'a, Result<Option<(f64, f64)>>>;
+}
+
+/// The default `Geocoder`, which never finds coordinates.
+pub struct NoopGeocoder;
+
+impl Geocoder for NoopGeocoder {
+    fn geocode<'a>(&'a self, _address: &'a AddressRecord) -> BoxFuture<'a, Result<Option<(f64, f64)>>> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+/// What happens to rows with a blank `property_lat` or `property_lng`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingCoordinates {
+    /// Store the coordinates as read, i.e. as empty strings.
+    Empty,
+    /// Store both coordinates as NULL.
+    Null,
+    /// Reject the row as `missing_coordinates`.
+    Skip,
+}
+
+impl FromStr for MissingCoordinates {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "empty" => Ok(Self::Empty),
+            "null" => Ok(Self::Null),
+            "skip" => Ok(Self::Skip),
+            other => Err(format!("expected `empty`, `null` or `skip`, got `{}`", other)),
+        }
+    }
+}
+
+/// Where processed records go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    Mysql,
+    /// One JSON object per record in `<JSONL_DIR>/<file stem>.jsonl`; the
+    /// database is never used.
+    Jsonl,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mysql" => Ok(Self::Mysql),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(format!("expected `mysql` or `jsonl`, got `{}`", other)),
+        }
+    }
+}
+
+/// Quoting rules used to read CSV files, so vendor-specific dialects parse correctly.
+#[derive(Debug, Clone)]
+pub struct CsvDialect {
+    pub quote: u8,
+    pub escape: Option<u8>,
+    pub double_quote: bool,
+    /// Lines starting with this byte are skipped entirely.
+    pub comment: Option<u8>,
+    /// Accept rows with fewer or more fields than the header. Missing trailing
+    /// fields read as empty.
+    pub flexible: bool,
+    /// Character encoding of CSV files. Anything but UTF-8 is decoded to
+    /// UTF-8 before parsing.
+    pub encoding: &'static Encoding,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            quote: b'"',
+            escape: None,
+            double_quote: true,
+            comment: None,
+            flexible: false,
+            encoding: encoding_rs::UTF_8,
+        }
+    }
+}
+
+impl CsvDialect {
+    fn reader_builder(&self) -> ReaderBuilder {
+        let mut builder = ReaderBuilder::new();
+        builder
+            .has_headers(true)
+            .quote(self.quote)
+            .escape(self.escape)
+            .double_quote(self.double_quote)
+            .comment(self.comment)
+            .flexible(self.flexible);
+        builder
+    }
+}
+
+/// Timezone that log timestamps are rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimezone {
+    /// The host's local timezone.
+    Local,
+    /// An IANA timezone such as `America/New_York`.
+    Named(Tz),
+}
+
+impl FromStr for LogTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(Self::Local);
+        }
+        s.parse::<Tz>().map(Self::Named).map_err(|_| {
+            format!("expected `local` or an IANA timezone name like `America/New_York`, got `{}`", s)
+        })
+    }
+}
+
+/// Which owners of a CSV row become address records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OwnerMode {
+    /// Owner 1 only, with contact 1's phones.
+    Primary,
+    /// One record preferring owner 1 and falling back to owner 2 per field.
+    SecondaryFallback,
+    /// A separate record for a distinct owner 2, under `<lead_id>-2`.
+    Both,
+}
+
+impl FromStr for OwnerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "primary" => Ok(Self::Primary),
+            "secondary_fallback" => Ok(Self::SecondaryFallback),
+            "both" => Ok(Self::Both),
+            other => Err(format!("expected `primary`, `secondary_fallback` or `both`, got `{}`", other)),
+        }
+    }
+}
+
+/// Casing applied to owner names by `clean_name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameCase {
+    Upper,
+    Lower,
+    Title,
+}
+
+impl FromStr for NameCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "upper" => Ok(Self::Upper),
+            "lower" => Ok(Self::Lower),
+            "title" => Ok(Self::Title),
+            other => Err(format!("expected `upper`, `lower` or `title`, got `{}`", other)),
+        }
+    }
+}
+
+/// How existing DMIDs are loaded for duplicate detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmidPrefetchStrategy {
+    /// Load every DMID already stored under the campaign's flag.
+    Full,
+    /// Read the file's lead_ids first and only look those up.
+    Scoped,
+}
+
+impl FromStr for DmidPrefetchStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "scoped" => Ok(Self::Scoped),
+            other => Err(format!("expected `full` or `scoped`, got `{}`", other)),
+        }
+    }
+}
+
+impl Config {
+    /// Destination for moving an upload into `dir` according to `OUTPUT_LAYOUT`,
+    /// creating any missing directories.
+    fn output_path(&self, dir: &str, file_path: &Path, file_name: &str) -> std::io::Result<PathBuf> {
+        let mut destination = PathBuf::from(dir);
+        if self.output_layout == OutputLayout::Preserve {
+            if let Some(relative) = file_path
+                .parent()
+                .and_then(|parent| parent.strip_prefix(&self.upload_dir).ok())
+            {
+                destination.push(relative);
+            }
+        }
+        fs::create_dir_all(&destination)?;
+        destination.push(file_name);
+        Ok(destination)
+    }
+
+    /// Whether finished files are recorded in `processed_files`: for database
+    /// tracking, and for duplicate detection.
+    fn records_processed_files(&self) -> bool {
+        self.duplicates_dir.is_some() || self.processed_tracking == ProcessedTracking::Database
+    }
+
+    /// A name or address field with `DECODE_HTML_ENTITIES` and
+    /// `NAME_REPLACEMENTS` applied.
+    fn clean_text(&self, value: &str) -> String {
+        let mut value = if self.decode_html_entities {
+            decode_html_entities(value)
+        } else {
+            value.to_string()
+        };
+        for (from, to) in &self.name_replacements {
+            value = value.replace(from.as_str(), to);
+        }
+        value
+    }
+
+    /// A value read from a record, as it may appear in the logs: masked with
+    /// `REDACT_PII`, otherwise unchanged.
+    fn loggable(&self, value: &str) -> String {
+        if self.redact_pii {
+            redact_pii(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Directories files are moved into after processing. These are never scanned for input.
+    fn output_dirs(&self) -> Vec<&str> {
+        let mut dirs = match self.processed_tracking {
+            ProcessedTracking::Move => vec![self.processed_dir.as_str(), self.quarantine_dir.as_str()],
+            ProcessedTracking::Database => vec![self.quarantine_dir.as_str()],
+        };
+        dirs.extend(self.duplicates_dir.as_deref());
+        dirs.extend(self.rejects_dir.as_deref());
+        if self.output_mode == OutputMode::Jsonl {
+            dirs.push(self.jsonl_dir.as_str());
+        }
+        dirs
+    }
+
+    pub fn from_env() -> Result<Self> {
+        Self::load(true)
+    }
+
+    /// Like `from_env`, but `DATABASE_URL` may be unset, for modes such as
+    /// `--validate-only` that never connect.
+    pub fn from_env_offline() -> Result<Self> {
+        Self::load(false)
+    }
+
+    fn load(database_required: bool) -> Result<Self> {
+        fn parse_env_var<T: std::str::FromStr>(
+            key: &str,
+            default: Option<T>,
+        ) -> Result<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match env::var(key) {
+                Ok(val) => val.parse::<T>().map_err(|e| {
+                    anyhow::anyhow!("Invalid value for {}: {}", key, e)
+                }),
+                Err(_) => match default {
+                    Some(d) => Ok(d),
+                    None => Err(anyhow::anyhow!("Environment variable {} is required", key)),
+                },
+            }
+        }
+
+        // For settings where 0 is never meaningful: a zero BATCH_SIZE inserts
+        // one row per batch, and a zero time limit or attempt count fails every
+        // file at once. It's almost certainly a typo, so it's rejected.
+        fn parse_nonzero_env_var<T: std::str::FromStr + Default + PartialEq>(key: &str, default: T) -> Result<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            let value = parse_env_var(key, Some(default))?;
+            if value == T::default() {
+                return Err(anyhow::anyhow!("Invalid value for {}: must be greater than 0", key));
+            }
+            Ok(value)
+        }
+
+        // Table names can't be bound as parameters and are interpolated into
+        // SQL, so only plain identifiers are allowed.
+        fn table_name(key: &str, default: &str) -> Result<String> {
+            let name = env::var(key).map(|name| name.trim().to_string()).unwrap_or_default();
+            if name.is_empty() {
+                return Ok(default.to_string());
+            }
+            if !TABLE_NAME_REGEX.is_match(&name) {
+                return Err(anyhow::anyhow!(
+                    "Invalid value for {}: {:?} is not a plain table name (letters, digits and _, at most 64)",
+                    key,
+                    name
+                ));
+            }
+            Ok(name)
+        }
+
+        fn parse_env_byte(key: &str) -> Result<Option<u8>> {
+            match env::var(key) {
+                Ok(val) => match val.as_bytes() {
+                    [byte] if byte.is_ascii() => Ok(Some(*byte)),
+                    _ => Err(anyhow::anyhow!(
+                        "Invalid value for {}: expected a single ASCII character, got {:?}",
+                        key,
+                        val
+                    )),
+                },
+                Err(_) => Ok(None),
+            }
+        }
+
+        fn parse_env_list(key: &str) -> Vec<String> {
+            env::var(key)
+                .unwrap_or_default()
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        }
+
+        let omit_columns = parse_env_list("ADDRESS_OMIT_COLUMNS");
+        if let Some(column) = omit_columns
+            .iter()
+            .find(|c| !OPTIONAL_ADDRESS_COLUMNS.contains(&c.as_str()))
+        {
+            return Err(anyhow::anyhow!(
+                "Invalid value for ADDRESS_OMIT_COLUMNS: {} cannot be omitted (optional columns: {:?})",
+                column,
+                OPTIONAL_ADDRESS_COLUMNS
+            ));
+        }
+        let mut address_columns: Vec<&'static str> = ADDRESS_COLUMNS
+            .into_iter()
+            .filter(|column| !omit_columns.iter().any(|c| c == column))
+            .collect();
+        let dedup_key_columns = match parse_env_list("DEDUP_KEY_COLUMNS") {
+            columns if columns.is_empty() => vec!["lead_id".to_string()],
+            columns => columns,
+        };
+        let mut column_defaults = BTreeMap::new();
+        for entry in parse_env_list("COLUMN_DEFAULTS") {
+            let (column, value) = entry.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!("Invalid value for COLUMN_DEFAULTS: expected `column=value`, got `{}`", entry)
+            })?;
+            let column = column.trim();
+            if !REQUIRED_COLUMNS.contains(&column) {
+                return Err(anyhow::anyhow!(
+                    "Invalid value for COLUMN_DEFAULTS: {} is not a required column",
+                    column
+                ));
+            }
+            if ESSENTIAL_COLUMNS.contains(&column) || dedup_key_columns.iter().any(|c| c == column) {
+                return Err(anyhow::anyhow!(
+                    "Invalid value for COLUMN_DEFAULTS: {} is essential and can't have a default",
+                    column
+                ));
+            }
+            column_defaults.insert(column.to_string(), value.trim().to_string());
+        }
+        let mut name_replacements = Vec::new();
+        for entry in parse_env_list("NAME_REPLACEMENTS") {
+            match entry.split_once("=>") {
+                Some((from, to)) if !from.trim().is_empty() => {
+                    name_replacements.push((from.trim().to_string(), to.trim().to_string()))
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "Invalid value for NAME_REPLACEMENTS: expected `from=>to`, got `{}`",
+                        entry
+                    ))
+                }
+            }
+        }
+        let phone_column_count: Option<usize> = match env::var("PHONE_COLUMNS") {
+            Ok(_) => Some(parse_env_var("PHONE_COLUMNS", None)?),
+            Err(_) => None,
+        };
+        // Beyond 15 decimals an f64 only adds noise.
+        let coord_decimals: Option<usize> = match env::var("COORD_DECIMALS") {
+            Ok(_) => match parse_env_var("COORD_DECIMALS", None)? {
+                decimals if decimals > 15 => {
+                    return Err(anyhow::anyhow!("Invalid value for COORD_DECIMALS: at most 15, got {}", decimals))
+                }
+                decimals => Some(decimals),
+            },
+            Err(_) => None,
+        };
+        let phone_columns = match parse_env_list("PHONE_COLUMN_NAMES") {
+            names if names.is_empty() => phone_column_names(phone_column_count.unwrap_or(3)),
+            names => {
+                // The names are interpolated into SQL, so only plain identifiers are allowed.
+                if let Some(name) = names
+                    .iter()
+                    .find(|name| !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                {
+                    return Err(anyhow::anyhow!(
+                        "Invalid value for PHONE_COLUMN_NAMES: {:?} is not a plain column name",
+                        name
+                    ));
+                }
+                if phone_column_count.is_some_and(|count| count != names.len()) {
+                    return Err(anyhow::anyhow!(
+                        "PHONE_COLUMNS is {} but PHONE_COLUMN_NAMES lists {} columns",
+                        phone_column_count.unwrap_or_default(),
+                        names.len()
+                    ));
+                }
+                names
+            }
+        };
+        if phone_columns.is_empty() {
+            return Err(anyhow::anyhow!("Invalid value for PHONE_COLUMNS: must be at least 1"));
+        }
+        // The email column is opt-in, so installs without it keep working.
+        let store_email = parse_env_var("STORE_EMAIL", Some(false))?;
+        if store_email {
+            address_columns.push("email");
+        }
+        // Likewise opt-in.
+        if parse_env_var("STORE_OWNER_OCCUPIED", Some(false))? {
+            address_columns.push("owner_occupied");
+        }
+
+        let batch_size = parse_nonzero_env_var("BATCH_SIZE", 1000)?;
+        let default_dialect = CsvDialect::default();
+        // Without a replacement, matches of the regex are removed.
+        let campaign_name_regex = env::var("CAMPAIGN_NAME_REGEX").ok();
+        let campaign_name_replacement = env::var("CAMPAIGN_NAME_REPLACEMENT").unwrap_or_default();
+        // EXPAND_OWNERS=true predates OWNER_MODE and still selects `both`.
+        let default_owner_mode = if parse_env_var("EXPAND_OWNERS", Some(false))? {
+            OwnerMode::Both
+        } else {
+            OwnerMode::SecondaryFallback
+        };
+        // Likewise FILE_TRANSACTION=true predates COMMIT_STRATEGY.
+        let default_commit_strategy = if parse_env_var("FILE_TRANSACTION", Some(false))? {
+            CommitStrategy::PerFile
+        } else {
+            CommitStrategy::PerBatch
+        };
+
+        let output_mode = parse_env_var("OUTPUT_MODE", Some(OutputMode::Mysql))?;
+        let config = Self {
+            database_url: match (env::var("DATABASE_URL"), output_mode) {
+                (Ok(url), _) => url,
+                (Err(_), OutputMode::Jsonl) => String::new(),
+                (Err(_), OutputMode::Mysql) if !database_required => String::new(),
+                (Err(e), OutputMode::Mysql) => {
+                    return Err(e).context("DATABASE_URL must be set in .env file");
+                }
+            },
+            output_mode,
+            address_table: table_name("ADDRESS_TABLE", "address")?,
+            phonequeue_table: table_name("PHONEQUEUE_TABLE", "phonequeue")?,
+            optout_table: Some(table_name("OPTOUT_TABLE", "")?).filter(|table| !table.is_empty()),
+            jsonl_dir: env::var("JSONL_DIR").unwrap_or_else(|_| "./jsonl".to_string()),
+            phone_seed_file: env::var("PHONE_SEED_FILE").ok().filter(|path| !path.trim().is_empty()),
+            upload_dir: env::var("UPLOAD_DIR").unwrap_or_else(|_| "./uploads".to_string()),
+            upload_glob: env::var("UPLOAD_GLOB").unwrap_or_else(|_| "*.csv".to_string()),
+            output_layout: parse_env_var("OUTPUT_LAYOUT", Some(OutputLayout::Flatten))?,
+            validation_rules: match env::var("VALIDATION_RULES_FILE") {
+                Ok(path) if !path.trim().is_empty() => ValidationRule::load(&path)?,
+                _ => Vec::new(),
+            },
+            processed_dir: env::var("PROCESSED_DIR").unwrap_or_else(|_| "./processed".to_string()),
+            lock_file_path: env::var("LOCK_FILE").unwrap_or_else(|_| "./process.lock".to_string()),
+            lock_mode: parse_env_var("LOCK_MODE", Some(LockMode::File))?,
+            quarantine_dir: env::var("QUARANTINE_DIR").unwrap_or_else(|_| "./quarantine".to_string()),
+            duplicates_dir: env::var("DUPLICATES_DIR").ok().filter(|dir| !dir.trim().is_empty()),
+            verify_moves: parse_env_var("VERIFY_MOVES", Some(false))?,
+            rejects_dir: env::var("REJECTS_DIR").ok().filter(|dir| !dir.trim().is_empty()),
+            max_file_attempts: parse_nonzero_env_var("MAX_FILE_ATTEMPTS", 3)?,
+            db_connect_attempts: parse_nonzero_env_var("DB_CONNECT_ATTEMPTS", 5)?,
+            db_connect_backoff_seconds: parse_env_var("DB_CONNECT_BACKOFF_SECONDS", Some(2))?,
+            attempts_file: env::var("ATTEMPTS_FILE").unwrap_or_else(|_| "./file_attempts.json".to_string()),
+            resume_file: if parse_env_var("RESUME_INTERRUPTED_FILES", Some(true))? {
+                Some(env::var("RESUME_FILE").unwrap_or_else(|_| "./file_resume.json".to_string()))
+            } else {
+                None
+            },
+            batch_size,
+            insert_chunk_size: parse_nonzero_env_var("INSERT_CHUNK_SIZE", batch_size)?,
+            max_execution_seconds: parse_nonzero_env_var("MAX_EXECUTION_SECONDS", 3600)?,
+            soft_timeout_grace_seconds: parse_env_var("SOFT_TIMEOUT_GRACE_SECONDS", Some(0))?,
+            max_total_seconds: Some(parse_env_var("MAX_TOTAL_SECONDS", Some(0))?).filter(|&seconds| seconds > 0),
+            concurrent_prefetch: parse_env_var("CONCURRENT_PREFETCH", Some(true))?,
+            dmid_prefetch: parse_env_var("DMID_PREFETCH", Some(DmidPrefetchStrategy::Full))?,
+            stale_claim_seconds: parse_env_var("STALE_CLAIM_SECONDS", Some(7200))?,
+            name_case: parse_env_var("NAME_CASE", Some(NameCase::Title))?,
+            normalize_names: parse_env_var("NORMALIZE_NAMES", Some(false))?,
+            owner_mode: parse_env_var("OWNER_MODE", Some(default_owner_mode))?,
+            skip_ai_tiers: parse_env_var("SKIP_AI_TIERS", Some(SkipAiTiers::default()))?,
+            column_defaults,
+            dedup_key_columns,
+            address_columns,
+            store_email,
+            email_column: env::var("EMAIL_COLUMN").unwrap_or_else(|_| "owner_email".to_string()),
+            require_phone: parse_env_var("REQUIRE_PHONE", Some(true))?,
+            require_property_address: parse_env_var("REQUIRE_PROPERTY_ADDRESS", Some(true))?,
+            coord_decimals,
+            mailing_fallback_to_property: parse_env_var("MAILING_FALLBACK_TO_PROPERTY", Some(false))?,
+            decode_html_entities: parse_env_var("DECODE_HTML_ENTITIES", Some(false))?,
+            empty_as_null: parse_env_var("EMPTY_AS_NULL", Some(false))?,
+            name_replacements,
+            phone_columns,
+            quarantine_empty_files: parse_env_var("QUARANTINE_EMPTY_FILES", Some(false))?,
+            collapse_whitespace: parse_env_var("COLLAPSE_WHITESPACE", Some(false))?,
+            commit_strategy: parse_env_var("COMMIT_STRATEGY", Some(default_commit_strategy))?,
+            max_pending_rows: Some(parse_env_var("MAX_PENDING_ROWS", Some(0))?).filter(|&rows| rows > 0),
+            staging_mode: parse_env_var("STAGING_MODE", Some(false))?,
+            max_inserts_per_second: Some(parse_env_var("MAX_INSERTS_PER_SECOND", Some(0))?)
+                .filter(|&rate| rate > 0),
+            normalize_address: parse_env_var("NORMALIZE_ADDRESS", Some(false))?,
+            parse_unit_type: parse_env_var("PARSE_UNIT_TYPE", Some(false))?,
+            unit_type_column: env::var("UNIT_TYPE_COLUMN").ok().filter(|column| !column.trim().is_empty()),
+            log_batch_timings: parse_env_var("LOG_BATCH_TIMINGS", Some(false))?,
+            fast_batch: parse_env_var("FAST_BATCH", Some(false))?,
+            slow_batch_ms: Some(parse_env_var("SLOW_BATCH_MS", Some(0))?).filter(|&ms| ms > 0),
+            log_timezone: parse_env_var("LOG_TIMEZONE", Some(LogTimezone::Local))?,
+            progress_interval_seconds: parse_env_var("PROGRESS_INTERVAL_SECONDS", Some(60))?,
+            progress_every_batches: parse_env_var("PROGRESS_EVERY_BATCHES", Some(10))?,
+            campaign_isolation_level: match env::var("CAMPAIGN_ISOLATION_LEVEL") {
+                Ok(_) => Some(parse_env_var("CAMPAIGN_ISOLATION_LEVEL", None)?),
+                Err(_) => None,
+            },
+            allow_new_campaigns: parse_env_var("ALLOW_NEW_CAMPAIGNS", Some(true))?,
+            header_aliases: parse_env_var("HEADER_ALIASES", Some(true))?,
+            max_new_campaigns_per_run: match env::var("MAX_NEW_CAMPAIGNS_PER_RUN") {
+                Ok(_) => Some(parse_env_var("MAX_NEW_CAMPAIGNS_PER_RUN", None)?),
+                Err(_) => None,
+            },
+            processed_tracking: parse_env_var("PROCESSED_TRACKING", Some(ProcessedTracking::Move))?,
+            phone_dedup_mode: parse_env_var("PHONE_DEDUP_MODE", Some(PhoneDedupMode::Memory))?,
+            phone_conflict_policy: parse_env_var(
+                "PHONE_CONFLICT_POLICY",
+                Some(PhoneConflictPolicy::KeepAddress),
+            )?,
+            duplicate_header_policy: parse_env_var("DUPLICATE_HEADER_POLICY", Some(DuplicateHeaderPolicy::Last))?,
+            min_header_columns: parse_env_var("MIN_HEADER_COLUMNS", Some(2))?,
+            duplicate_row_selection: parse_env_var("DUPLICATE_ROW_SELECTION", Some(DuplicateRowSelection::First))?,
+            address_dedup: parse_env_var("ADDRESS_DEDUP", Some(AddressDedup::Off))?,
+            missing_coordinates: parse_env_var("MISSING_COORDINATES", Some(MissingCoordinates::Empty))?,
+            geocoder: Arc::new(NoopGeocoder),
+            state_filter: StateFilter::new(parse_env_list("STATE_ALLOWLIST"), parse_env_list("STATE_BLOCKLIST"))?,
+            init_sql: env::var("INIT_SQL")
+                .unwrap_or_default()
+                .split(';')
+                .map(|statement| statement.trim().to_string())
+                .filter(|statement| !statement.is_empty())
+                .collect(),
+            csv_dialect: CsvDialect {
+                quote: parse_env_byte("CSV_QUOTE")?.unwrap_or(default_dialect.quote),
+                escape: parse_env_byte("CSV_ESCAPE")?.or(default_dialect.escape),
+                double_quote: parse_env_var("CSV_DOUBLE_QUOTE", Some(default_dialect.double_quote))?,
+                comment: parse_env_byte("CSV_COMMENT_CHAR")?.or(default_dialect.comment),
+                flexible: parse_env_var("CSV_FLEXIBLE", Some(default_dialect.flexible))?,
+                encoding: match env::var("INPUT_ENCODING") {
+                    Ok(label) => Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| {
+                        anyhow::anyhow!("Invalid value for INPUT_ENCODING: unknown encoding {:?}", label)
+                    })?,
+                    Err(_) => default_dialect.encoding,
+                },
+            },
+            filename_pattern: FilenamePattern::new(
+                &env::var("FILENAME_REGEX").unwrap_or_else(|_| DEFAULT_FILENAME_REGEX.to_string()),
+            )?,
+            campaign_name_transform: CampaignNameTransform::new(
+                parse_env_list("CAMPAIGN_NAME_STRIP_SUFFIXES"),
+                campaign_name_regex
+                    .as_deref()
+                    .map(|pattern| (pattern, campaign_name_replacement.as_str())),
+            )?,
+            webhook_url: env::var("WEBHOOK_URL").ok().filter(|url| !url.trim().is_empty()),
+            pushgateway_url: env::var("PUSHGATEWAY_URL").ok().filter(|url| !url.trim().is_empty()),
+            run_log_enabled: parse_env_var("RUN_LOG_ENABLED", Some(false))?,
+            redact_pii: parse_env_var("REDACT_PII", Some(false))?,
+        };
+
+        // These all rely on database tables.
+        if config.output_mode == OutputMode::Jsonl {
+            let conflicts = [
+                ("PROCESSED_TRACKING=database", config.processed_tracking == ProcessedTracking::Database),
+                ("DUPLICATES_DIR", config.duplicates_dir.is_some()),
+                ("STAGING_MODE", config.staging_mode),
+                ("COMMIT_STRATEGY=per_file", config.commit_strategy == CommitStrategy::PerFile),
+                ("PHONE_DEDUP_MODE=db", config.phone_dedup_mode == PhoneDedupMode::Db),
+                ("RUN_LOG_ENABLED", config.run_log_enabled),
+                ("OPTOUT_TABLE", config.optout_table.is_some()),
+            ];
+            if let Some((setting, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(anyhow::anyhow!("{} can't be used with OUTPUT_MODE=jsonl", setting));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Retrieves the files matching `upload_glob` (e.g. `*.csv`, or `**/*.csv` to
+/// include subdirectories) in the upload directory, skipping
+/// anything that lives under one of the `excluded_dirs` (e.g. the processed
+/// directory) so files that were just moved are never picked up again. Files
+/// claimed by a run in progress (`*.processing`) are never listed.
+fn get_csv_files(upload_dir: &str, upload_glob: &str, excluded_dirs: &[&str]) -> Result<Vec<PathBuf>> {
+    let excluded: Vec<PathBuf> = excluded_dirs
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .collect();
+    let mut files = Vec::new();
+    for glob_pattern in upload_globs(upload_glob) {
+        let pattern = format!("{}/{}", upload_dir, glob_pattern);
+        files.extend(
+            glob::glob(&pattern)
+                .with_context(|| format!("Invalid UPLOAD_GLOB: {}", upload_glob))?
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .filter(|path| !path.to_string_lossy().ends_with(CLAIM_SUFFIX))
+                .filter(|path| {
+                    let parent = path.parent().and_then(|p| fs::canonicalize(p).ok());
+                    !parent.is_some_and(|parent| excluded.iter().any(|dir| parent.starts_with(dir)))
+                }),
+        );
+    }
+    // A file matched by more than one pattern is only processed once.
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// The individual patterns of a comma-separated `UPLOAD_GLOB`.
+fn upload_globs(upload_glob: &str) -> impl Iterator<Item = &str> {
+    upload_glob.split(',').map(str::trim).filter(|pattern| !pattern.is_empty())
+}
+
+/// Warns when an output directory is the same as, or nested inside, the upload
+/// directory. Such files are excluded from scanning, but the layout is almost
+/// always a misconfiguration.
+fn warn_if_nested_dirs(upload_dir: &str, output_dirs: &[&str]) {
+    let Ok(upload) = fs::canonicalize(upload_dir) else {
+        return;
+    };
+    for dir in output_dirs {
+        if let Ok(output) = fs::canonicalize(dir) {
+            if output.starts_with(&upload) {
+                eprintln!(
+                    "Warning: {} is inside the upload directory {}; files there will be ignored when scanning.",
+                    output.display(),
+                    upload.display()
+                );
+            }
+        }
+    }
+}
+
+/// Consecutive failure counts per upload filename, persisted in a small JSON
+/// sidecar file so they survive between runs.
+struct FileAttempts {
+    path: PathBuf,
+    counts: HashMap<String, u32>,
+}
+
+impl FileAttempts {
+    fn load(path: &str) -> Result<Self> {
+        let counts = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse attempts file {}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read attempts file {}", path))
+            }
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            counts,
+        })
+    }
+
+    /// Records a failure and returns the file's consecutive failure count.
+    fn record_failure(&mut self, file_name: &str) -> Result<u32> {
+        let count = self.counts.entry(file_name.to_string()).or_insert(0);
+        *count += 1;
+        let count = *count;
+        self.save()?;
+        Ok(count)
+    }
+
+    fn reset(&mut self, file_name: &str) -> Result<()> {
+        if self.counts.remove(file_name).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.counts)?)
+            .with_context(|| format!("Failed to write attempts file {}", self.path.display()))
+    }
+}
+
+/// Where to resume an interrupted file: the CSV position just past the last
+/// committed batch, plus the file size so a replaced file starts over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResumePoint {
+    file_size: u64,
+    byte: u64,
+    line: u64,
+    record: u64,
+}
+
+impl ResumePoint {
+    fn position(&self) -> csv::Position {
+        let mut position = csv::Position::new();
+        position.set_byte(self.byte).set_line(self.line).set_record(self.record);
+        position
+    }
+}
+
+/// Resume points per upload filename, persisted in a JSON sidecar file after
+/// every committed batch so a crashed or timed-out import continues where it
+/// stopped instead of re-reading rows that are already in the database.
+struct ResumePoints {
+    path: PathBuf,
+    points: HashMap<String, ResumePoint>,
+}
+
+impl ResumePoints {
+    fn load(path: &str) -> Result<Self> {
+        let points = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse resume file {}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read resume file {}", path))
+            }
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            points,
+        })
+    }
+
+    /// The saved position for a file, if it still has the same size.
+    fn get(&self, file_name: &str, file_size: u64) -> Option<csv::Position> {
+        self.points
+            .get(file_name)
+            .filter(|point| point.file_size == file_size)
+            .map(ResumePoint::position)
+    }
+
+    fn set(&mut self, file_name: &str, file_size: u64, position: &csv::Position) -> Result<()> {
+        self.points.insert(
+            file_name.to_string(),
+            ResumePoint {
+                file_size,
+                byte: position.byte(),
+                line: position.line(),
+                record: position.record(),
+            },
+        );
+        self.save()
+    }
+
+    fn clear(&mut self, file_name: &str) -> Result<()> {
+        if self.points.remove(file_name).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string_pretty(&self.points)?)
+            .with_context(|| format!("Failed to write resume file {}", self.path.display()))
+    }
+}
+
+/// Suffix appended to a file's name while a run is processing it.
+const CLAIM_SUFFIX: &str = ".processing";
+
+/// Exclusive claim on an upload file, taken by renaming it to `<name>.processing`
+/// in place. Renames are atomic, so only one process can win the claim. If the
+/// claimed file hasn't been moved elsewhere by the time the guard is dropped, it
+/// is renamed back so the next run can retry it.
+struct FileClaim {
+    original: PathBuf,
+    claimed: PathBuf,
+}
+
+impl FileClaim {
+    /// Claims `path`, returning `None` if the file is gone (claimed by someone else).
+    fn acquire(path: &Path) -> Result<Option<Self>> {
+        let mut claimed = path.as_os_str().to_owned();
+        claimed.push(CLAIM_SUFFIX);
+        let claimed = PathBuf::from(claimed);
+        match fs::rename(path, &claimed) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to claim file {}", path.display()))
+            }
+        }
+        // Renaming keeps the upload's mtime; reset it so stale-claim detection
+        // measures how long the claim has been held.
+        if let Ok(file) = fs::File::options().write(true).open(&claimed) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Ok(Some(Self {
+            original: path.to_path_buf(),
+            claimed,
+        }))
+    }
+
+    fn path(&self) -> &Path {
+        &self.claimed
+    }
+}
+
+impl Drop for FileClaim {
+    fn drop(&mut self) {
+        if self.claimed.exists() {
+            if let Err(e) = fs::rename(&self.claimed, &self.original) {
+                eprintln!(
+                    "Failed to release claim on {}: {:?}",
+                    self.original.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Renames `.processing` files older than `max_age` back to their original names.
+/// These are left behind when a run is killed mid-file.
+fn reclaim_stale_claims(upload_dir: &str, upload_glob: &str, max_age: Duration) {
+    let claimed_paths = upload_globs(upload_glob)
+        .filter_map(|pattern| glob::glob(&format!("{}/{}{}", upload_dir, pattern, CLAIM_SUFFIX)).ok())
+        .flatten()
+        .filter_map(Result::ok);
+    for claimed in claimed_paths {
+        let age = fs::metadata(&claimed)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if age.is_none_or(|age| age <= max_age) {
+            continue;
+        }
+        let name = claimed.to_string_lossy();
+        let original = PathBuf::from(name.strip_suffix(CLAIM_SUFFIX).unwrap_or(&name));
+        match fs::rename(&claimed, &original) {
+            Ok(()) => eprintln!("Reclaimed stale file {}", original.display()),
+            Err(e) => eprintln!("Failed to reclaim stale file {}: {:?}", claimed.display(), e),
+        }
+    }
+}
+
+/// Prefetch all phone numbers (the configured phone columns) from the phonequeue table.
+/// The columns are folded into a single distinct list server-side and the
+/// rows are streamed, so memory is bounded by the number of unique phones rather
+/// than the size of the full result set.
+async fn prefetch_all_phone_numbers(pool: &Pool<MySql>, table: &str, phone_columns: &[String]) -> Result<HashSet<String>> {
+    let mut set = HashSet::new();
+    let sql = phone_columns
+        .iter()
+        .map(|column| {
+            format!(
+                "SELECT TRIM({column}) AS phone FROM {table} WHERE {column} IS NOT NULL AND TRIM({column}) <> ''",
+                column = column,
+                table = table
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+    let mut rows = sqlx::query(&sql).fetch(pool);
+    while let Some(row) = rows
+        .try_next()
+        .await
+        .context("Failed to prefetch phone numbers")?
+    {
+        if let Ok(Some(phone)) = row.try_get::<Option<String>, _>("phone") {
+            if !phone.is_empty() {
+                set.insert(phone);
+            }
+        }
+    }
+    Ok(set)
+}
+
+/// Loads the opted-out phones from the `phone` column of `table`, trimmed, to
+/// be compared with candidates as they are read.
+async fn prefetch_optout(pool: &Pool<MySql>, table: &str) -> Result<HashSet<String>> {
+    let mut set = HashSet::new();
+    let sql = format!("SELECT DISTINCT TRIM(phone) AS phone FROM {} WHERE phone IS NOT NULL", table);
+    let mut rows = sqlx::query(&sql).fetch(pool);
+    while let Some(row) = rows
+        .try_next()
+        .await
+        .with_context(|| format!("Failed to prefetch opted-out phones from {}", table))?
+    {
+        if let Ok(Some(phone)) = row.try_get::<Option<String>, _>("phone") {
+            if !phone.is_empty() {
+                set.insert(phone);
+            }
+        }
+    }
+    eprintln!("Loaded {} opted-out phones from {}.", set.len(), table);
+    Ok(set)
+}
+
+/// The global phone set, which may still be loading in a background task.
+/// `process_file` only awaits it right before the record loop, so the prefetch
+/// can overlap with the first file's campaign lookup and DMID prefetch.
+enum PhoneSet {
+    Pending(JoinHandle<Result<HashSet<String>>>),
+    Ready(HashSet<String>),
+    Failed,
+}
+
+impl PhoneSet {
+    /// Starts prefetching phone numbers on a background task.
+    fn spawn(pool: &Pool<MySql>, table: String, phone_columns: Vec<String>) -> Self {
+        let pool = pool.clone();
+        PhoneSet::Pending(tokio::spawn(async move {
+            prefetch_all_phone_numbers(&pool, &table, &phone_columns).await
+        }))
+    }
+
+    /// Waits for the prefetch to finish (if it hasn't already) and returns the set.
+    async fn ready(&mut self) -> Result<&mut HashSet<String>> {
+        if let PhoneSet::Pending(handle) = self {
+            let result = match handle.await {
+                Ok(result) => result,
+                Err(e) => Err(anyhow::anyhow!("Phone prefetch task panicked: {}", e)),
+            };
+            match result {
+                Ok(set) => *self = PhoneSet::Ready(set),
+                Err(e) => {
+                    *self = PhoneSet::Failed;
+                    return Err(e);
+                }
+            }
+        }
+        match self {
+            PhoneSet::Ready(set) => Ok(set),
+            PhoneSet::Failed => Err(anyhow::anyhow!("Phone prefetch failed earlier in this run")),
+            PhoneSet::Pending(_) => unreachable!("phone set resolved above"),
+        }
+    }
+
+    /// Cancels a prefetch that is no longer needed.
+    fn abort(&self) {
+        if let PhoneSet::Pending(handle) = self {
+            handle.abort();
+        }
+    }
+}
+
+/// Marks a file as done according to `PROCESSED_TRACKING`: either moves it into
+/// the processed directory, or records its name and content hash in the
+/// `processed_files` table and leaves it where it is. `content_hash` is the
+/// hash taken when processing started, if the tracking mode needs one.
+///
+/// A file that disappeared while it was processed can't be moved, but its
+/// rows are already inserted; its hash is still recorded so that duplicate
+/// detection catches the file if it reappears.
+///
+/// With `VERIFY_MOVES`, the moved file is hashed again and a mismatch with
+/// `content_hash` is logged as an error. The rows are already inserted, so the
+/// file is left where it was moved for investigation.
+async fn mark_file_done(
+    pool: Option<&Pool<MySql>>,
+    config: &Config,
+    file_path: &Path,
+    file_name: &str,
+    content_hash: Option<&str>,
+    rows_inserted: usize,
+) -> Result<()> {
+    if let (Some(pool), Some(content_hash), true) = (pool, content_hash, config.records_processed_files()) {
+        record_processed_file(pool, file_name, content_hash, rows_inserted).await?;
+    }
+    match config.processed_tracking {
+        ProcessedTracking::Move => {
+            if !file_path.exists() {
+                let reappearance = if config.records_processed_files() {
+                    "it was recorded as processed and will be treated as a duplicate if it reappears"
+                } else {
+                    "set DUPLICATES_DIR to keep it from being imported again if it reappears"
+                };
+                eprintln!(
+                    "WARNING: File {} disappeared before it could be moved to {} ({} rows were already inserted); {}.",
+                    file_name, config.processed_dir, rows_inserted, reappearance
+                );
+                return Ok(());
+            }
+            let new_path = config
+                .output_path(&config.processed_dir, file_path, file_name)
+                .with_context(|| format!("Failed to create processed directory for {}", file_name))?;
+            move_file(file_path, &new_path)
+                .with_context(|| format!("Failed to move file to {}", new_path.display()))?;
+            if let (true, Some(expected)) = (config.verify_moves, content_hash) {
+                let actual = file_sha256(&new_path)?;
+                if actual != expected {
+                    eprintln!(
+                        "[{}] ERROR: Checksum mismatch after moving {} to {}: sha256 was {} before processing and is {} now. The file may be corrupted; compare it with the original upload.",
+                        log_timestamp(),
+                        file_name,
+                        new_path.display(),
+                        expected,
+                        actual
+                    );
+                    METRICS.move_checksum_mismatches.inc();
+                }
+            }
+            Ok(())
+        }
+        ProcessedTracking::Database => Ok(()),
+    }
+}
+
+/// Records a file's name and content hash in `processed_files`.
+async fn record_processed_file(
+    pool: &Pool<MySql>,
+    file_name: &str,
+    content_hash: &str,
+    rows_inserted: usize,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO processed_files (filename, content_hash, rows_inserted)
+        VALUES (?, ?, ?)
+        ON DUPLICATE KEY UPDATE rows_inserted = VALUES(rows_inserted), processed_at = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(file_name)
+    .bind(content_hash)
+    .bind(rows_inserted as u64)
+    .execute(pool)
+    .await
+    .with_context(|| format!("Failed to record {} as processed", file_name))?;
+    Ok(())
+}
+
+/// Returns the name of a previously processed file with the same content, if any.
+async fn find_duplicate_file(pool: &Pool<MySql>, content_hash: &str) -> Result<Option<String>> {
+    sqlx::query_scalar("SELECT filename FROM processed_files WHERE content_hash = ? ORDER BY processed_at DESC LIMIT 1")
+        .bind(content_hash)
+        .fetch_optional(pool)
+        .await
+        .context("Failed to look up processed file hashes")
+}
+
+/// Loads the filenames and content hashes already recorded in `processed_files`.
+async fn fetch_processed_files(pool: &Pool<MySql>) -> Result<HashMap<String, HashSet<String>>> {
+    let mut processed: HashMap<String, HashSet<String>> = HashMap::new();
+    let rows = sqlx::query("SELECT filename, content_hash FROM processed_files")
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch processed files")?;
+    for row in rows {
+        let filename: String = row.try_get("filename")?;
+        let content_hash: String = row.try_get("content_hash")?;
+        processed.entry(filename).or_default().insert(content_hash);
+    }
+    Ok(processed)
+}
+
+/// Computes the hex SHA-256 of a file, reading it in chunks.
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("Failed to open {} for hashing", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to hash {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Writes the rejected rows of one input file to `<REJECTS_DIR>/<stem>.rejects.csv`:
+/// the original columns plus a `reject_reason` column. The file is only created
+/// once a row is rejected, and is appended to when the input file is picked up
+/// again, so resumed files don't lose their earlier rejects.
+struct RejectWriter {
+    path: PathBuf,
+    header: StringRecord,
+    writer: Option<csv::Writer<fs::File>>,
+}
+
+impl RejectWriter {
+    fn new(dir: &str, file_name: &str, headers: &StringRecord) -> Self {
+        let stem = Path::new(file_name).file_stem().unwrap_or_default().to_string_lossy();
+        let mut header = headers.clone();
+        header.push_field("reject_reason");
+        RejectWriter {
+            path: Path::new(dir).join(format!("{}.rejects.csv", stem)),
+            header,
+            writer: None,
+        }
+    }
+
+    fn write(&mut self, record: &StringRecord, reason: &str) -> Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)
+                        .with_context(|| format!("Failed to create rejects directory {:?}", dir))?;
+                }
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .with_context(|| format!("Failed to open rejects file {:?}", self.path))?;
+                let is_new = file.metadata().map(|m| m.len() == 0).unwrap_or(true);
+                // Ragged rows are written as read, so the writer must accept them.
+                let mut writer = WriterBuilder::new().flexible(true).from_writer(file);
+                if is_new {
+                    writer.write_record(&self.header)?;
+                }
+                self.writer.insert(writer)
+            }
+        };
+        writer
+            .write_record(record.iter().chain(std::iter::once(reason)))
+            .with_context(|| format!("Failed to write rejects file {:?}", self.path))
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some(mut writer) = self.writer {
+            writer
+                .flush()
+                .with_context(|| format!("Failed to write rejects file {:?}", self.path))?;
+            eprintln!("Rejected rows written to {:?}.", self.path);
+        }
+        Ok(())
+    }
+}
+
+/// Where `process_file` sends each batch of records, per `OUTPUT_MODE`.
+enum BatchSink<'a> {
+    Database(&'a Pool<MySql>),
+    Jsonl(JsonlWriter),
+}
+
+impl BatchSink<'_> {
+    /// Writes and drains the batch, returning the number of records written.
+    async fn write(
+        &mut self,
+        conn: Option<&mut MySqlConnection>,
+        tables: &TargetTables,
+        combined_batch: &mut Vec<CombinedRecord>,
+        config: &Config,
+    ) -> Result<usize> {
+        match self {
+            BatchSink::Database(pool) => process_batch(pool, conn, tables, combined_batch, config).await,
+            BatchSink::Jsonl(writer) => writer.write_batch(combined_batch),
+        }
+    }
+}
+
+/// One line of `OUTPUT_MODE=jsonl` output.
+#[derive(Serialize)]
+struct JsonlLine<'a> {
+    campaign: &'a str,
+    address: &'a AddressRecord,
+    phones: &'a [String],
+}
+
+/// Writes the records of one input file to `<JSONL_DIR>/<stem>.jsonl`. The file
+/// is appended to, so a resumed file continues where it stopped.
+struct JsonlWriter {
+    path: PathBuf,
+    campaign: String,
+    writer: std::io::BufWriter<fs::File>,
+}
+
+impl JsonlWriter {
+    fn new(dir: &str, file_name: &str, campaign: &str) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create JSONL directory {}", dir))?;
+        let stem = Path::new(file_name).file_stem().unwrap_or_default().to_string_lossy();
+        let path = Path::new(dir).join(format!("{}.jsonl", stem));
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open JSONL file {:?}", path))?;
+        Ok(JsonlWriter {
+            path,
+            campaign: campaign.to_string(),
+            writer: std::io::BufWriter::new(file),
+        })
+    }
+
+    fn write_batch(&mut self, combined_batch: &mut Vec<CombinedRecord>) -> Result<usize> {
+        let records = std::mem::take(combined_batch);
+        for record in &records {
+            let line = JsonlLine {
+                campaign: &self.campaign,
+                address: &record.address,
+                phones: record.phone.as_ref().map_or(&[], |phone| phone.phones.as_slice()),
+            };
+            serde_json::to_writer(&mut self.writer, &line)?;
+            self.writer.write_all(b"\n")?;
+        }
+        // Flushed per batch, so a resume point never gets ahead of the file.
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to write JSONL file {:?}", self.path))?;
+        METRICS.rows_inserted.inc_by(records.len() as u64);
+        Ok(records.len())
+    }
+
+    fn finish(self) -> Result<()> {
+        eprintln!("Records written to {:?}.", self.path);
+        Ok(())
+    }
+}
+
+/// Reads `PHONE_SEED_FILE`: one phone number per line, blank lines ignored.
+fn load_phone_seed(path: &str) -> Result<HashSet<String>> {
+    let contents = fs::read_to_string(path).with_context(|| format!("Failed to read PHONE_SEED_FILE {}", path))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|phone| !phone.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Paces inserts to `MAX_INSERTS_PER_SECOND` on a shared database: after each
+/// batch, sleeps until the rows inserted so far fit the configured rate.
+struct InsertThrottle {
+    rate: u64,
+    started: Instant,
+    inserted: u64,
+    waited: Duration,
+}
+
+impl InsertThrottle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            inserted: 0,
+            waited: Duration::ZERO,
+        }
+    }
+
+    async fn batch_done(&mut self, rows: usize) {
+        self.inserted += rows as u64;
+        let due = Duration::from_secs_f64(self.inserted as f64 / self.rate as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+            self.waited += due - elapsed;
+        }
+    }
+
+    fn log_summary(&self, file_name: &str) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.inserted as f64 / elapsed } else { 0.0 };
+        eprintln!(
+            "Throughput for {}: {:.0} rows/sec effective (limit {}/sec), {:.1}s spent throttled.",
+            file_name,
+            rate,
+            self.rate,
+            self.waited.as_secs_f64()
+        );
+    }
+}
+
+/// Periodically logs how far through a file processing is, with throughput and
+/// an ETA extrapolated from the bytes read so far. A line is logged every
+/// `interval` and after every `every_batches` batches (0 disables either trigger).
+struct ProgressReporter {
+    start: Instant,
+    last_log: Instant,
+    interval: Duration,
+    every_batches: usize,
+    batches_since_log: usize,
+}
+
+impl ProgressReporter {
+    fn new(interval_seconds: u64, every_batches: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last_log: now,
+            interval: Duration::from_secs(interval_seconds),
+            every_batches,
+            batches_since_log: 0,
+        }
+    }
+
+    fn batch_done(&mut self) {
+        self.batches_since_log += 1;
+    }
+
+    fn maybe_log(
+        &mut self,
+        file_name: &str,
+        rows_read: usize,
+        rows_inserted: usize,
+        bytes_read: u64,
+        total_bytes: u64,
+    ) {
+        let time_due = !self.interval.is_zero() && self.last_log.elapsed() >= self.interval;
+        let batches_due = self.every_batches > 0 && self.batches_since_log >= self.every_batches;
+        if !time_due && !batches_due {
+            return;
+        }
+        self.last_log = Instant::now();
+        self.batches_since_log = 0;
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rows_per_sec = if elapsed > 0.0 { rows_read as f64 / elapsed } else { 0.0 };
+        let eta = if bytes_read > 0 && total_bytes > bytes_read {
+            let remaining = elapsed * (total_bytes - bytes_read) as f64 / bytes_read as f64;
+            format!("{:.0}s", remaining)
+        } else {
+            "unknown".to_string()
+        };
+        let percent = if total_bytes > 0 {
+            100.0 * bytes_read as f64 / total_bytes as f64
+        } else {
+            0.0
+        };
+        eprintln!(
+            "[{}] Progress {}: {} rows read, {} inserted, {:.1}% of file, {:.0} rows/sec, ETA {}.",
+            log_timestamp(),
+            file_name,
+            rows_read,
+            rows_inserted,
+            percent,
+            rows_per_sec,
+            eta
+        );
+    }
+}
+
+/// Whether a file past its `MAX_EXECUTION_SECONDS` limit should keep going:
+/// at the byte rate so far, the bytes left must be read before the limit plus
+/// the grace period runs out. Re-checked on every row, so a file that slows
+/// down is still stopped, and never later than the end of the grace period.
+fn finishes_within_grace(
+    elapsed: Duration,
+    limit_seconds: u64,
+    grace_seconds: u64,
+    bytes_done: u64,
+    bytes_left: u64,
+) -> bool {
+    let budget = limit_seconds.saturating_add(grace_seconds) as f64;
+    let elapsed = elapsed.as_secs_f64();
+    if grace_seconds == 0 || bytes_done == 0 || elapsed >= budget {
+        return false;
+    }
+    let seconds_left = elapsed * bytes_left as f64 / bytes_done as f64;
+    elapsed + seconds_left <= budget
+}
+
+/// Input the CSV reader can seek in, for resume points and rewinding.
+trait CsvSource: Read + Seek {}
+
+impl<T: Read + Seek> CsvSource for T {}
+
+/// Opens an upload for the CSV reader. `.xlsx` workbooks are converted to CSV in
+/// memory when built with the `xlsx` feature; anything else is read as CSV.
+#[cfg_attr(not(feature = "xlsx"), allow(unused_variables))]
+fn open_csv_source(file_path: &Path, file_name: &str, dialect: &CsvDialect) -> Result<Box<dyn CsvSource>> {
+    let is_xlsx = Path::new(file_name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("xlsx"));
+    if is_xlsx {
+        #[cfg(feature = "xlsx")]
+        return Ok(Box::new(std::io::Cursor::new(xlsx::sheet_to_csv(file_path, dialect)?)));
+        #[cfg(not(feature = "xlsx"))]
+        return Err(anyhow::anyhow!(
+            "{} is an Excel workbook, but DMParser was built without the `xlsx` feature",
+            file_name
+        ));
+    }
+    let mut file = fs::File::open(file_path)
+        .with_context(|| format!("Failed to open CSV file: {}", file_name))?;
+    if dialect.encoding == encoding_rs::UTF_8 {
+        return Ok(Box::new(file));
+    }
+    // Decoded in memory, so resume positions are offsets into the UTF-8 text.
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .with_context(|| format!("Failed to read CSV file: {}", file_name))?;
+    let (text, _, had_errors) = dialect.encoding.decode(&bytes);
+    if had_errors {
+        eprintln!(
+            "WARNING: {} has bytes that aren't valid {}; they were replaced with U+FFFD.",
+            file_name,
+            dialect.encoding.name()
+        );
+    }
+    Ok(Box::new(std::io::Cursor::new(text.into_owned().into_bytes())))
+}
+
+/// Maps trimmed header names to column indexes. With several columns of the
+/// same name, `First` keeps the first and anything else the last; the repeated
+/// names are returned as well.
+fn map_headers(headers: &StringRecord, policy: DuplicateHeaderPolicy) -> (HashMap<&str, usize>, Vec<&str>) {
+    let mut header_map: HashMap<&str, usize> = HashMap::with_capacity(headers.len());
+    let mut duplicate_headers: Vec<&str> = Vec::new();
+    for (idx, header) in headers.iter().enumerate() {
+        let header = header.trim();
+        if header_map.contains_key(header) {
+            if !duplicate_headers.contains(&header) {
+                duplicate_headers.push(header);
+            }
+            if policy == DuplicateHeaderPolicy::First {
+                continue;
+            }
+        }
+        header_map.insert(header, idx);
+    }
+    (header_map, duplicate_headers)
+}
+
+/// Header names compared case-insensitively, ignoring everything but letters
+/// and digits.
+fn header_key(header: &str) -> String {
+    header
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Adds the known columns a file only has under a variant name to
+/// `header_map`, returning each `(header, column)` pair used. A column present
+/// under its own name always wins, and of several variants the first counts.
+fn apply_header_aliases<'a>(headers: &'a StringRecord, header_map: &mut HashMap<&'a str, usize>) -> Vec<(&'a str, &'static str)> {
+    let mut aliased = Vec::new();
+    for (idx, header) in headers.iter().enumerate() {
+        let key = header_key(header);
+        let column = REQUIRED_COLUMNS
+            .iter()
+            .chain(&["skip_ai"])
+            .copied()
+            .find(|column| header_key(column) == key)
+            .or_else(|| {
+                HEADER_ALIASES
+                    .iter()
+                    .find(|(alias, _)| header_key(alias) == key)
+                    .map(|&(_, column)| column)
+            });
+        if let Some(column) = column {
+            if !header_map.contains_key(column) {
+                header_map.insert(column, idx);
+                aliased.push((header.trim(), column));
+            }
+        }
+    }
+    aliased
+}
+
+/// The required and dedup key columns a file lacks, split into those with a
+/// `COLUMN_DEFAULTS` value and those without.
+fn missing_required_columns<'a>(header_map: &HashMap<&str, usize>, config: &'a Config) -> (Vec<&'a str>, Vec<&'a str>) {
+    REQUIRED_COLUMNS
+        .iter()
+        .copied()
+        .chain(config.dedup_key_columns.iter().map(String::as_str))
+        .filter(|col| !header_map.contains_key(col))
+        .partition(|col| config.column_defaults.contains_key(*col))
+}
+
+/// Data rows read by `validate_file`.
+const VALIDATE_SAMPLE_ROWS: usize = 100;
+
+/// Checks a file the way `process_file` would without touching the database:
+/// the filename pattern, the content precheck, the header row, the required
+/// columns and a sample of rows. Returns each check by name, in order; later
+/// checks are left out once one makes them meaningless. Phone and DMID
+/// uniqueness need the database and are not checked.
+pub fn validate_file(config: &Config, file_path: &Path) -> Vec<(&'static str, Result<String>)> {
+    let mut checks = Vec::new();
+    let file_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+    checks.push((
+        "filename",
+        match config.filename_pattern.parse(&file_name) {
+            Some(parts) => Ok(format!(
+                "matches FILENAME_REGEX: campaign {:?}, skip_ai {}, vertical {}",
+                campaign_name_for(config, parts.original_filename).1,
+                parts.skip_ai_flag,
+                parts.vertical
+            )),
+            None => Err(anyhow::anyhow!("{} does not match FILENAME_REGEX", file_name)),
+        },
+    ));
+
+    match precheck_file(file_path, &file_name, config) {
+        Ok(None) => checks.push(("content", Ok("looks like CSV".to_string()))),
+        Ok(Some(reason)) => {
+            checks.push(("content", Err(anyhow::anyhow!("{} {}", file_name, reason))));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(("content", Err(e)));
+            return checks;
+        }
+    }
+
+    let mut rdr = match open_csv_source(file_path, &file_name, &config.csv_dialect) {
+        Ok(source) => config.csv_dialect.reader_builder().from_reader(source),
+        Err(e) => {
+            checks.push(("header row", Err(e)));
+            return checks;
+        }
+    };
+    let headers = match rdr.headers() {
+        Ok(headers) if !headers.is_empty() => headers.clone(),
+        Ok(_) => {
+            checks.push(("header row", Err(anyhow::anyhow!("{} is empty", file_name))));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(("header row", Err(anyhow::anyhow!("unreadable: {}", e))));
+            return checks;
+        }
+    };
+    let (mut header_map, duplicate_headers) = map_headers(&headers, config.duplicate_header_policy);
+    let aliased = if config.header_aliases {
+        apply_header_aliases(&headers, &mut header_map)
+    } else {
+        Vec::new()
+    };
+    checks.push((
+        "header row",
+        match (duplicate_headers.is_empty(), config.duplicate_header_policy) {
+            (true, _) => Ok(format!("{} columns", headers.len())),
+            (false, DuplicateHeaderPolicy::Fail) => Err(anyhow::anyhow!(
+                "duplicate header names {:?} with DUPLICATE_HEADER_POLICY=fail",
+                duplicate_headers
+            )),
+            (false, _) => Ok(format!(
+                "{} columns; duplicate header names {:?} are resolved by DUPLICATE_HEADER_POLICY",
+                headers.len(),
+                duplicate_headers
+            )),
+        },
+    ));
+    if !aliased.is_empty() {
+        checks.push(("header aliases", Ok(format!("{:?}", aliased))));
+    }
+
+    let (defaulted_columns, missing_columns) = missing_required_columns(&header_map, config);
+    checks.push((
+        "required columns",
+        match (missing_columns.is_empty(), defaulted_columns.is_empty()) {
+            (false, _) => Err(anyhow::anyhow!("missing {:?}", missing_columns)),
+            (true, true) => Ok("all present".to_string()),
+            (true, false) => Ok(format!("present, or defaulted by COLUMN_DEFAULTS: {:?}", defaulted_columns)),
+        },
+    ));
+    if !missing_columns.is_empty() {
+        return checks;
+    }
+
+    let key_columns: Vec<usize> = config
+        .dedup_key_columns
+        .iter()
+        .map(|column| header_map[column.as_str()])
+        .collect();
+    let (mut rows, mut usable, mut malformed) = (0, 0, 0);
+    let mut problems: BTreeMap<String, usize> = BTreeMap::new();
+    for result in rdr.records().take(VALIDATE_SAMPLE_ROWS) {
+        rows += 1;
+        let record = match result {
+            Ok(record) => record,
+            Err(_) => {
+                malformed += 1;
+                continue;
+            }
+        };
+        let field = |column: &str| {
+            match header_map.get(column) {
+                Some(&idx) => record.get(idx),
+                None => config.column_defaults.get(column).map(String::as_str),
+            }
+            .unwrap_or("")
+            .trim()
+        };
+        let problem = if dedup_key(&record, &key_columns).is_none() {
+            Some("missing_lead_id".to_string())
+        } else if let Some(rule) = config.validation_rules.iter().find(|rule| {
+            header_map
+                .get(rule.column.as_str())
+                .is_some_and(|&idx| rule.matches(record.get(idx).unwrap_or("").trim()))
+        }) {
+            Some(format!("validation: {}", rule.reason()))
+        } else if !config.state_filter.allows(field("property_address_state")) {
+            Some("state_filtered".to_string())
+        } else if config.require_property_address
+            && ["property_address_line_1", "property_address_city", "property_address_zipcode"]
+                .iter()
+                .any(|column| field(column).is_empty())
+        {
+            Some("no_address".to_string())
+        } else if config.missing_coordinates == MissingCoordinates::Skip
+            && (field("property_lat").is_empty() || field("property_lng").is_empty())
+        {
+            Some("missing_coordinates".to_string())
+        } else {
+            let owners = select_owners(&field, "", config);
+            if owners.iter().all(|owner| owner.fname.is_empty()) {
+                Some("missing_name".to_string())
+            } else if config.require_phone
+                && owners.iter().all(|owner| owner.phone_candidates.iter().all(String::is_empty))
+            {
+                Some("no_phone".to_string())
+            } else {
+                None
+            }
+        };
+        match problem {
+            Some(problem) => *problems.entry(problem).or_default() += 1,
+            None => usable += 1,
+        }
+    }
+    if malformed > 0 {
+        problems.insert("malformed".to_string(), malformed);
+    }
+    let detail = format!(
+        "{} of the first {} rows would import{}",
+        usable,
+        rows,
+        if problems.is_empty() { String::new() } else { format!("; rejected: {:?}", problems) }
+    );
+    checks.push((
+        "sample rows",
+        if rows > 0 && usable == 0 { Err(anyhow::anyhow!(detail)) } else { Ok(detail) },
+    ));
+    checks
+}
+
+/// Bytes read from the start of an upload by `precheck_file`.
+const PRECHECK_BYTES: usize = 8192;
+
+/// Reads the start of a CSV upload and returns why it can't be a CSV export,
+/// if it can't; see `precheck_prefix`. Workbooks are skipped, as they are
+/// binary by nature and checked when converted.
+fn precheck_file(file_path: &Path, file_name: &str, config: &Config) -> Result<Option<String>> {
+    let is_xlsx = Path::new(file_name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("xlsx"));
+    if is_xlsx {
+        return Ok(None);
+    }
+    let mut prefix = Vec::with_capacity(PRECHECK_BYTES);
+    fs::File::open(file_path)
+        .and_then(|file| file.take(PRECHECK_BYTES as u64).read_to_end(&mut prefix))
+        .with_context(|| format!("Failed to read {}", file_name))?;
+    Ok(precheck_prefix(&prefix, &config.csv_dialect, config.min_header_columns).err())
+}
+
+/// Cheap sanity checks on the first bytes of a file, catching uploads that
+/// aren't CSV at all before anything is parsed or written: an HTML error page
+/// saved as `.csv`, binary content, text that isn't UTF-8, or a header row with
+/// too few fields (usually the wrong delimiter). An empty prefix passes; empty
+/// files are handled separately. With another `INPUT_ENCODING`, the prefix is
+/// decoded first and only the other checks apply.
+fn precheck_prefix(prefix: &[u8], dialect: &CsvDialect, min_columns: usize) -> std::result::Result<(), String> {
+    let decoded;
+    let prefix = if dialect.encoding == encoding_rs::UTF_8 {
+        prefix
+    } else {
+        decoded = dialect.encoding.decode(prefix).0.into_owned().into_bytes();
+        &decoded
+    };
+    let text = prefix.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(prefix);
+    let start = text.trim_ascii_start();
+    let head = start[..start.len().min(16)].to_ascii_lowercase();
+    if head.starts_with(b"<!doctype") || head.starts_with(b"<html") {
+        return Err("starts like an HTML page, not CSV; was an error page saved instead of the export?".to_string());
+    }
+    if let Some(offset) = text.iter().position(|&b| b == 0) {
+        return Err(format!("contains binary data (a NUL byte at offset {})", offset));
+    }
+    if let Err(e) = std::str::from_utf8(text) {
+        // A character cut off at the end of the prefix is fine.
+        if e.error_len().is_some() {
+            return Err(format!("is not valid UTF-8 (invalid byte at offset {})", e.valid_up_to()));
+        }
+    }
+    let header = dialect
+        .reader_builder()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text)
+        .records()
+        .next();
+    if let Some(Ok(header)) = header {
+        if header.len() < min_columns {
+            return Err(format!(
+                "has {} field(s) in its header row, fewer than MIN_HEADER_COLUMNS ({}); is the delimiter wrong?",
+                header.len(),
+                min_columns
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Processes a single CSV file: parsing, validating, batching inserts,
+/// handling errors, and marking the file done post-processing. With
+/// `campaign_flag`, rows go to that existing campaign instead of the one named
+/// after the file. Reading stops early, as with `MAX_EXECUTION_SECONDS`, once
+/// the run's `deadline` passes. Phones in `optout` are dropped from every row.
+#[allow(clippy::too_many_arguments)]
+async fn process_file(
+    pool: Option<&Pool<MySql>>,
+    file_path: &Path,
+    config: &Config,
+    global_phone_set: &mut PhoneSet,
+    optout: &HashSet<String>,
+    insert_budget: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    campaigns_created: &AtomicUsize,
+) -> Result<FileSummary> {
+    let batch_size = config.batch_size;
+    let max_execution_seconds = config.max_execution_seconds;
+    let _duration_timer = METRICS.file_duration.start_timer();
+
+    let file_name = file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    // Claim the file before touching it so that no other run picks it up.
+    // Claiming renames the file, so it's skipped when the upload directory is
+    // treated as read-only (database tracking).
+    let claim = match config.processed_tracking {
+        ProcessedTracking::Move => match FileClaim::acquire(file_path)? {
+            Some(claim) => Some(claim),
+            None => {
+                eprintln!("File {} was claimed by another process; skipping.", file_name);
+                return Ok(FileSummary::skipped());
+            }
+        },
+        ProcessedTracking::Database => None,
+    };
+    let file_path = claim.as_ref().map_or(file_path, |claim| claim.path());
+
+    // The content hash is taken now, while the file is known to exist, for
+    // duplicate detection, for recording the file once it's done and for
+    // verifying the moved file.
+    let content_hash = if config.records_processed_files() || config.verify_moves {
+        Some(file_sha256(file_path)?)
+    } else {
+        None
+    };
+
+    // Files that plainly aren't CSV can't succeed on a retry; set them aside
+    // before any database work.
+    if let Some(reason) = precheck_file(file_path, &file_name, config)? {
+        eprintln!(
+            "WARNING: {} {}. Moving the file to {}.",
+            file_name, reason, config.quarantine_dir
+        );
+        quarantine_file(config, file_path, &file_name)
+            .with_context(|| format!("Failed to quarantine {}", file_name))?;
+        return Ok(FileSummary::skipped());
+    }
+
+    // Vendors sometimes re-send an identical file under a new timestamp. Catch
+    // that from the content hash before parsing anything.
+    if let (Some(duplicates_dir), Some(content_hash), Some(pool)) = (&config.duplicates_dir, &content_hash, pool) {
+        if let Some(original) = find_duplicate_file(pool, content_hash).await? {
+            config
+                .output_path(duplicates_dir, file_path, &file_name)
+                .and_then(|destination| move_file(file_path, &destination))
+                .with_context(|| format!("Failed to move duplicate file {} to {}", file_name, duplicates_dir))?;
+            eprintln!(
+                "File {} has the same content as already processed file {}; moved to {}.",
+                file_name, original, duplicates_dir
+            );
+            return Ok(FileSummary {
+                duplicate: true,
+                ..FileSummary::skipped()
+            });
+        }
+    }
+
+    // Validate filename against the expected pattern.
+    let parts = match config.filename_pattern.parse(&file_name) {
+        Some(parts) => parts,
+        None => {
+            eprintln!("Filename pattern mismatch: {}", file_name);
+            mark_file_done(pool, config, file_path, &file_name, content_hash.as_deref(), 0).await?;
+            return Ok(FileSummary::skipped());
+        }
+    };
+
+    let skip_ai_flag = parts.skip_ai_flag;
+    let vertical = parts.vertical;
+    let original_filename = parts.original_filename;
+
+    let mut source = open_csv_source(file_path, &file_name, &config.csv_dialect)?;
+    // Measured on the CSV stream, which for a workbook is the converted sheet.
+    let total_bytes = source
+        .seek(SeekFrom::End(0))
+        .and_then(|len| source.rewind().map(|()| len))
+        .with_context(|| format!("Failed to read {}", file_name))?;
+    let mut rdr = config.csv_dialect.reader_builder().from_reader(source);
+
+    // An unreadable header row means the file itself is malformed; retrying won't help.
+    let headers = match rdr.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            eprintln!("Unreadable header row in {}: {:?}", file_name, e);
+            mark_file_done(pool, config, file_path, &file_name, content_hash.as_deref(), 0).await?;
+            return Ok(FileSummary::skipped());
+        }
+    };
+    if headers.is_empty() {
+        return handle_empty_file(pool, config, file_path, &file_name, content_hash.as_deref()).await;
+    }
+    let (mut header_map, duplicate_headers) = map_headers(&headers, config.duplicate_header_policy);
+    if !duplicate_headers.is_empty() {
+        let occurrence = match config.duplicate_header_policy {
+            DuplicateHeaderPolicy::First => "first",
+            DuplicateHeaderPolicy::Last => "last",
+            DuplicateHeaderPolicy::Fail => {
+                eprintln!(
+                    "WARNING: Duplicate header names in {}: {:?}. Moving the file to {}.",
+                    file_name, duplicate_headers, config.quarantine_dir
+                );
+                quarantine_file(config, file_path, &file_name)
+                    .with_context(|| format!("Failed to quarantine {}", file_name))?;
+                return Ok(FileSummary::skipped());
+            }
+        };
+        eprintln!(
+            "WARNING: Duplicate header names in {}: {:?}. Using the {} column of each.",
+            file_name, duplicate_headers, occurrence
+        );
+    }
+    if config.header_aliases {
+        let aliased = apply_header_aliases(&headers, &mut header_map);
+        if !aliased.is_empty() {
+            eprintln!("Reading columns of {} by header alias: {:?}", file_name, aliased);
+        }
+    }
+
+    // Rules on columns this file doesn't have can never match.
+    let rule_columns: Vec<Option<usize>> = config
+        .validation_rules
+        .iter()
+        .map(|rule| header_map.get(rule.column.as_str()).copied())
+        .collect();
+    for (rule, _) in config.validation_rules.iter().zip(&rule_columns).filter(|(_, idx)| idx.is_none()) {
+        eprintln!(
+            "Validation rule \"{}\" ignored for {}: no column {}",
+            rule.reason(),
+            file_name,
+            rule.column
+        );
+    }
+
+    let email_idx = if config.store_email {
+        let idx = header_map.get(config.email_column.as_str()).copied();
+        if idx.is_none() {
+            eprintln!(
+                "No {} column in {}; emails will be left blank.",
+                config.email_column, file_name
+            );
+        }
+        idx
+    } else {
+        None
+    };
+
+    // An optional per-row skip_ai column overrides the filename's flag.
+    let skip_ai_idx = header_map.get("skip_ai").copied();
+
+    let unit_type_idx = config
+        .unit_type_column
+        .as_deref()
+        .and_then(|column| header_map.get(column.trim()).copied());
+    if let (Some(column), None) = (&config.unit_type_column, unit_type_idx) {
+        eprintln!("UNIT_TYPE_COLUMN ignored for {}: no column {}", file_name, column);
+    }
+
+    let (defaulted_columns, missing_columns) = missing_required_columns(&header_map, config);
+    for column in &defaulted_columns {
+        eprintln!(
+            "Column {} missing from {}; using the default {:?}.",
+            column, file_name, config.column_defaults[*column]
+        );
+    }
+    if !missing_columns.is_empty() {
+        eprintln!(
+            "Missing required columns in {}: {:?}",
+            file_name, missing_columns
+        );
+        mark_file_done(pool, config, file_path, &file_name, content_hash.as_deref(), 0).await?;
+        return Ok(FileSummary::skipped());
+    }
+
+    let key_columns: Vec<usize> = config
+        .dedup_key_columns
+        .iter()
+        .map(|column| header_map[column.as_str()])
+        .collect();
+
+    // Peek for a data row before creating the campaign, then rewind.
+    let data_start = rdr.position().clone();
+    let has_data = rdr.records().next().is_some();
+    rdr.seek(data_start)
+        .with_context(|| format!("Failed to rewind CSV file: {}", file_name))?;
+    if !has_data {
+        return handle_empty_file(pool, config, file_path, &file_name, content_hash.as_deref()).await;
+    }
+
+    let mut resume_points = match &config.resume_file {
+        Some(path) => Some(ResumePoints::load(path)?),
+        None => None,
+    };
+    if let Some(position) = resume_points.as_ref().and_then(|points| points.get(&file_name, total_bytes)) {
+        eprintln!(
+            "Resuming {} after row {} (byte {} of {}).",
+            file_name,
+            position.record().saturating_sub(1),
+            position.byte(),
+            total_bytes
+        );
+        rdr.seek(position)
+            .with_context(|| format!("Failed to seek to resume point in {}", file_name))?;
+    }
+
+    let start_time = Instant::now();
+    // Bytes read in this run, from which the soft timeout estimates the time left.
+    let start_byte = rdr.position().byte();
+    let mut in_grace = false;
+
+    let (raw_stem, campaign_name) = campaign_name_for(config, original_filename);
+    let (_campaign_id, new_flag) = match (campaign_flag, pool) {
+        (Some(flag), Some(pool)) => {
+            eprintln!("Loading {} into existing campaign with flag {}.", file_name, flag);
+            campaign_for_flag(pool, flag).await?
+        }
+        (None, Some(pool)) => {
+            if campaign_name != raw_stem {
+                eprintln!("Campaign name for {}: {:?} -> {:?}", file_name, raw_stem, campaign_name);
+            }
+            if !config.allow_new_campaigns && find_campaign(pool, &campaign_name).await?.is_none() {
+                return Err(anyhow::anyhow!(
+                    "Campaign {:?} for {} does not exist, and ALLOW_NEW_CAMPAIGNS is off",
+                    campaign_name,
+                    file_name
+                ));
+            }
+            match ensure_campaign(
+                pool,
+                &campaign_name,
+                vertical,
+                config.campaign_isolation_level,
+                config.max_new_campaigns_per_run,
+                campaigns_created,
+            )
+            .await
+            {
+                // Many new campaigns in one run usually means a bad upstream
+                // rename; the files are set aside for a person to look at.
+                Err(e) if e.downcast_ref::<NewCampaignLimit>().is_some() => {
+                    eprintln!(
+                        "WARNING: Not creating campaign {:?} for {}: {}. Moving the file to {}.",
+                        campaign_name, file_name, e, config.quarantine_dir
+                    );
+                    quarantine_file(config, file_path, &file_name)
+                        .with_context(|| format!("Failed to quarantine {}", file_name))?;
+                    return Ok(FileSummary::skipped());
+                }
+                result => result.context("Failed to ensure campaign exists")?,
+            }
+        }
+        // Without a database there are no campaign IDs; records carry the
+        // campaign name instead, and the flag from `--flag` if given.
+        (flag, None) => (0, flag.unwrap_or(0)),
+    };
+
+    let mut existing_dmids = match (config.dmid_prefetch, pool) {
+        (_, None) => HashMap::new(),
+        (DmidPrefetchStrategy::Full, Some(pool)) => prefetch_dmids(pool, &config.address_table, new_flag).await
+            .context("Failed to prefetch DMIDs")?,
+        (DmidPrefetchStrategy::Scoped, Some(pool)) => {
+            let source = open_csv_source(file_path, &file_name, &config.csv_dialect)?;
+            let mut lead_ids = collect_dedup_keys(source, &config.csv_dialect, &key_columns)
+                .with_context(|| format!("Failed to collect lead IDs from {}", file_name))?;
+            if config.owner_mode == OwnerMode::Both {
+                let owner_2_ids: Vec<String> = lead_ids.iter().map(|id| format!("{}-2", id)).collect();
+                lead_ids.extend(owner_2_ids);
+            }
+            prefetch_dmids_scoped(pool, &config.address_table, new_flag, &lead_ids).await
+                .context("Failed to prefetch DMIDs")?
+        }
+    };
+
+    // With DUPLICATE_ROW_SELECTION=most_complete, rows sharing a dedup key are
+    // only imported from the row picked by a first pass over the file.
+    let chosen_rows = match config.duplicate_row_selection {
+        DuplicateRowSelection::First => None,
+        DuplicateRowSelection::MostComplete => {
+            let source = open_csv_source(file_path, &file_name, &config.csv_dialect)?;
+            // The columns the records' phones are read from, aliases included.
+            let phone_columns: Vec<usize> = (1..=config.phone_columns.len())
+                .flat_map(|slot| [1, 2].map(|contact| phone_source_column(contact, slot)))
+                .filter_map(|column| header_map.get(column.as_str()).copied())
+                .collect();
+            Some(
+                select_most_complete_rows(source, &config.csv_dialect, &key_columns, &phone_columns)
+                    .with_context(|| format!("Failed to scan {} for duplicate rows", file_name))?,
+            )
+        }
+    };
+
+    let global_phone_set = global_phone_set.ready().await
+        .context("Failed to prefetch phone numbers")?;
+
+    // Combined batch for address and phone data.
+    let mut combined_batch: Vec<CombinedRecord> = Vec::with_capacity(batch_size);
+    let mut row_counter = 0_usize;
+    let mut processed_rows = 0_usize;
+    let mut rejected_rows = 0_usize;
+    let mut missing_coordinate_rows = 0_usize;
+    let mut geocoded_rows = 0_usize;
+    let mut invalid_coordinate_rows = 0_usize;
+    let mut mailing_fallback_rows = 0_usize;
+    let mut phone_tally = PhoneTally::default();
+    let mut rule_rejections: BTreeMap<String, usize> = BTreeMap::new();
+    let mut seen_addresses: HashSet<String> = HashSet::new();
+    let mut completed = true;
+    let mut throttle = config.max_inserts_per_second.map(InsertThrottle::new);
+    let mut rejects = config
+        .rejects_dir
+        .as_deref()
+        .map(|dir| RejectWriter::new(dir, &file_name, &headers));
+    let mut progress = ProgressReporter::new(config.progress_interval_seconds, config.progress_every_batches);
+
+    // With STAGING_MODE, batches go to temporary tables that are only copied to
+    // the live tables once the file stops cleanly. This supersedes COMMIT_STRATEGY.
+    let mut staging = match pool {
+        Some(pool) if config.staging_mode => Some(Staging::create(pool, TargetTables::live(config)).await?),
+        _ => None,
+    };
+
+    // With COMMIT_STRATEGY=per_file, every batch of the file runs in a savepoint
+    // of one transaction that is only committed once the file stops cleanly (or
+    // MAX_PENDING_ROWS is reached), so a failed batch rolls back the batches
+    // before it too.
+    let mut file_tx = match pool {
+        Some(pool) if config.commit_strategy == CommitStrategy::PerFile && staging.is_none() => {
+            Some(pool.begin().await.context("Failed to begin file transaction")?)
+        }
+        _ => None,
+    };
+    let tables = match &staging {
+        Some(staging) => staging.tables.clone(),
+        None => TargetTables::live(config),
+    };
+    let mut sink = match pool {
+        Some(pool) => BatchSink::Database(pool),
+        None => BatchSink::Jsonl(JsonlWriter::new(&config.jsonl_dir, &file_name, &campaign_name)?),
+    };
+
+    // Rows inserted in `file_tx` since it was last committed.
+    let mut pending_rows = 0;
+    let mut records = rdr.records();
+    // Where to resume if processing stops before the current record.
+    let mut resume_position = records.reader().position().clone();
+    loop {
+        let record_start = records.reader().position().clone();
+        let Some(result) = records.next() else {
+            break;
+        };
+        progress.maybe_log(
+            &file_name,
+            row_counter,
+            processed_rows,
+            records.reader().position().byte(),
+            total_bytes,
+        );
+
+        let record = match result {
+            Ok(rec) => rec,
+            Err(e) => {
+                eprintln!("Skipping malformed line in {}: {:?}", file_name, e);
+                rejected_rows += 1;
+                METRICS.reject("malformed", 1);
+                continue;
+            }
+        };
+        row_counter += 1;
+
+        if start_time.elapsed() > Duration::from_secs(max_execution_seconds) {
+            let finishing = finishes_within_grace(
+                start_time.elapsed(),
+                max_execution_seconds,
+                config.soft_timeout_grace_seconds,
+                record_start.byte().saturating_sub(start_byte),
+                total_bytes.saturating_sub(record_start.byte()),
+            );
+            if !finishing {
+                eprintln!(
+                    "Script timeout after {} seconds while processing {}.",
+                    max_execution_seconds, file_name
+                );
+                completed = false;
+                resume_position = record_start;
+                break;
+            }
+            if !in_grace {
+                eprintln!(
+                    "[{}] Timeout of {} seconds reached while processing {}, but it is estimated to finish within SOFT_TIMEOUT_GRACE_SECONDS; continuing.",
+                    log_timestamp(),
+                    max_execution_seconds,
+                    file_name
+                );
+                in_grace = true;
+            }
+        }
+        if deadline_passed(deadline) {
+            eprintln!("MAX_TOTAL_SECONDS reached while processing {}.", file_name);
+            completed = false;
+            resume_position = record_start;
+            break;
+        }
+
+        if insert_budget.is_some_and(|budget| processed_rows + combined_batch.len() >= budget) {
+            eprintln!("Row limit reached while processing {}.", file_name);
+            completed = false;
+            resume_position = record_start;
+            break;
+        }
+
+        // A missing column reads as its `COLUMN_DEFAULTS` value, and optional
+        // columns without one, such as extra phone slots, as blank.
+        let field = |column: &str| {
+            match header_map.get(column) {
+                Some(&idx) => record.get(idx),
+                None => config.column_defaults.get(column).map(String::as_str),
+            }
+            .unwrap_or("")
+            .trim()
+        };
+        // Free-text address fields may also have internal runs of whitespace
+        // collapsed. Phones, zips and coordinates stay on a strict trim so that
+        // stray spaces there still surface as bad data.
+        let text = |column: &str| {
+            let value = config.clean_text(field(column));
+            if config.collapse_whitespace {
+                normalize_whitespace(&value)
+            } else {
+                value
+            }
+        };
+        let street_text = |column: &str| {
+            if config.normalize_address {
+                normalize_address(&text(column))
+            } else {
+                text(column)
+            }
+        };
+
+        let Some(row_key) = dedup_key(&record, &key_columns) else {
+            rejected_rows += 1;
+            METRICS.reject("missing_lead_id", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, "missing_lead_id")?;
+            }
+            continue;
+        };
+
+        let record_byte = record.position().map(|position| position.byte());
+        if chosen_rows
+            .as_ref()
+            .and_then(|chosen| chosen.get(&row_key))
+            .is_some_and(|&chosen| record_byte != Some(chosen))
+        {
+            rejected_rows += 1;
+            METRICS.reject("duplicate_dmid", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, "duplicate_dmid")?;
+            }
+            continue;
+        }
+
+        let failed_rule = config
+            .validation_rules
+            .iter()
+            .zip(&rule_columns)
+            .find(|(rule, idx)| idx.is_some_and(|idx| rule.matches(record.get(idx).unwrap_or("").trim())));
+        if let Some((rule, _)) = failed_rule {
+            rejected_rows += 1;
+            METRICS.reject("validation", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, &format!("validation: {}", rule.reason()))?;
+            }
+            *rule_rejections.entry(rule.reason()).or_default() += 1;
+            continue;
+        }
+
+        if !config.state_filter.allows(field("property_address_state")) {
+            rejected_rows += 1;
+            METRICS.reject("state_filtered", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, "state_filtered")?;
+            }
+            continue;
+        }
+
+        let street = street_text("property_address_line_1");
+        let (parsed_unit_type, unit_num) = if config.parse_unit_type {
+            split_unit_designator(&text("property_address_line_2"))
+        } else {
+            (String::new(), text("property_address_line_2"))
+        };
+        let unit_type = unit_type_idx
+            .map(|idx| record.get(idx).unwrap_or("").trim().to_uppercase())
+            .filter(|unit_type| !unit_type.is_empty())
+            .unwrap_or(parsed_unit_type);
+        let mail_city = text("property_address_city");
+        let property_state = field("property_address_state");
+        let zipcode = field("property_address_zipcode");
+        if config.require_property_address && [street.as_str(), mail_city.as_str(), zipcode].contains(&"") {
+            rejected_rows += 1;
+            METRICS.reject("no_address", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, "no_address")?;
+            }
+            continue;
+        }
+        // With COORD_DECIMALS, each coordinate is parsed once, both to round it
+        // and to check it; one that isn't a number in range counts as blank.
+        let (latitude, longitude) = match config.coord_decimals {
+            Some(decimals) => {
+                let (raw_lat, raw_lng) = (field("property_lat"), field("property_lng"));
+                let lat = round_coordinate(raw_lat, 90.0, decimals);
+                let lng = round_coordinate(raw_lng, 180.0, decimals);
+                if (lat.is_none() && !raw_lat.is_empty()) || (lng.is_none() && !raw_lng.is_empty()) {
+                    invalid_coordinate_rows += 1;
+                }
+                (lat.unwrap_or_default(), lng.unwrap_or_default())
+            }
+            None => (field("property_lat").to_string(), field("property_lng").to_string()),
+        };
+        // Resolved by the first owner that isn't rejected, so the geocoder is
+        // only asked about rows that are inserted, and once per row. `None`
+        // once resolved: the row was rejected as `missing_coordinates`.
+        let mut row_coordinates: Option<Option<(Option<String>, Option<String>)>> = None;
+
+        // The key is only taken once a record of the row is kept, so a row
+        // rejected further down doesn't shut out a later, usable one. In
+        // normalized mode `key` splits the unit line itself, so "APT 1" and
+        // "#1" share a key.
+        let address_key = config.address_dedup.key(&street, field("property_address_line_2"), zipcode);
+        if address_key.as_ref().is_some_and(|key| seen_addresses.contains(key)) {
+            rejected_rows += 1;
+            METRICS.reject("duplicate_address", 1);
+            if let Some(rejects) = rejects.as_mut() {
+                rejects.write(&record, "duplicate_address")?;
+            }
+            continue;
+        }
+
+        let mailing_address = street_text("owner_address_line_1");
+        let mailing_city = text("owner_address_city");
+        let mailing_state = field("owner_address_state");
+        let mailing_zip = field("owner_address_zip");
+        let owner_occupied = owner_occupied(&street, zipcode, &mailing_address, mailing_zip);
+        // After `owner_occupied`, which stays unknown for a copied address.
+        let (mailing_address, mailing_city, mailing_state, mailing_zip) =
+            if config.mailing_fallback_to_property && mailing_address.is_empty() && !street.is_empty() {
+                mailing_fallback_rows += 1;
+                let line = [street.as_str(), unit_type.as_str(), unit_num.as_str()]
+                    .into_iter()
+                    .filter(|part| !part.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (line, mail_city.clone(), property_state, zipcode)
+            } else {
+                (mailing_address, mailing_city, mailing_state, mailing_zip)
+            };
+
+        let email = match email_idx.map(|idx| record.get(idx).unwrap_or("").trim()) {
+            Some(raw) if !raw.is_empty() => canonical_email(raw).unwrap_or_else(|| {
+                eprintln!(
+                    "WARNING: Invalid email {:?} for lead {} in {}; storing it blank.",
+                    config.loggable(raw),
+                    row_key,
+                    file_name
+                );
+                String::new()
+            }),
+            _ => String::new(),
+        };
+
+        let skip_ai_flag = skip_ai_idx
+            .and_then(|idx| parse_skip_ai(record.get(idx).unwrap_or("")))
+            .unwrap_or(skip_ai_flag);
+        let SkipAiTier { via, map_image_url } = config.skip_ai_tiers.get(skip_ai_flag);
+
+        let batch_len_before_row = combined_batch.len();
+        let mut limit_reached = false;
+        for owner in select_owners(&field, &row_key, config) {
+            // Checked per owner too, as a row can hold two with OWNER_MODE=both.
+            if insert_budget.is_some_and(|budget| processed_rows + combined_batch.len() >= budget) {
+                limit_reached = true;
+                break;
+            }
+            if existing_dmids.contains_key(&owner.dmid) {
+                rejected_rows += 1;
+                METRICS.reject("duplicate_dmid", 1);
+                if let Some(rejects) = rejects.as_mut() {
+                    rejects.write(&record, "duplicate_dmid")?;
+                }
+                continue;
+            } else {
+                existing_dmids.insert(owner.dmid.clone(), true);
+            }
+
+            if owner.fname.is_empty() {
+                rejected_rows += 1;
+                METRICS.reject("missing_name", 1);
+                if let Some(rejects) = rejects.as_mut() {
+                    rejects.write(&record, "missing_name")?;
+                }
+                continue;
+            }
+
+            if row_coordinates.is_none() {
+                let geocoded = if latitude.is_empty() || longitude.is_empty() {
+                    let lookup = AddressRecord {
+                        street: street.clone(),
+                        unit_type: unit_type.clone(),
+                        unit_num: unit_num.clone(),
+                        mail_city: mail_city.clone(),
+                        state: property_state.to_string(),
+                        zip: zipcode.to_string(),
+                        ..AddressRecord::default()
+                    };
+                    // A provider outage shouldn't fail the file; the row is
+                    // handled as if there was no match.
+                    config.geocoder.geocode(&lookup).await.unwrap_or_else(|e| {
+                        eprintln!(
+                            "WARNING: Failed to geocode lead {} in {}: {:#}. Applying MISSING_COORDINATES.",
+                            row_key, file_name, e
+                        );
+                        None
+                    })
+                } else {
+                    None
+                };
+                let resolved = if let Some((lat, lng)) = geocoded {
+                    geocoded_rows += 1;
+                    Some(match config.coord_decimals {
+                        Some(decimals) => (Some(format!("{:.*}", decimals, lat)), Some(format!("{:.*}", decimals, lng))),
+                        None => (Some(lat.to_string()), Some(lng.to_string())),
+                    })
+                } else if latitude.is_empty() || longitude.is_empty() {
+                    missing_coordinate_rows += 1;
+                    match config.missing_coordinates {
+                        MissingCoordinates::Empty => Some((Some(latitude.clone()), Some(longitude.clone()))),
+                        MissingCoordinates::Null => Some((None, None)),
+                        MissingCoordinates::Skip => {
+                            rejected_rows += 1;
+                            METRICS.reject("missing_coordinates", 1);
+                            if let Some(rejects) = rejects.as_mut() {
+                                rejects.write(&record, "missing_coordinates")?;
+                            }
+                            None
+                        }
+                    }
+                } else {
+                    Some((Some(latitude.clone()), Some(longitude.clone())))
+                };
+                row_coordinates = Some(resolved);
+            }
+            let Some(Some((latitude, longitude))) = row_coordinates.clone() else {
+                continue;
+            };
+
+            let address_record = AddressRecord {
+                street: street.clone(),
+                unit_type: unit_type.clone(),
+                unit_num: unit_num.clone(),
+                mail_city: mail_city.clone(),
+                state: property_state.to_string(),
+                zip: zipcode.to_string(),
+                latitude,
+                longitude,
+                fullname: owner.fullname,
+                fname: owner.fname,
+                lname: owner.lname,
+                mailing_address: mailing_address.clone(),
+                mailing_city: mailing_city.clone(),
+                mailing_state: mailing_state.to_string(),
+                mailing_zip: mailing_zip.to_string(),
+                flag: new_flag,
+                dmid: owner.dmid,
+                via: *via,
+                map_image_url: map_image_url.clone(),
+                email: email.clone(),
+                owner_occupied,
+            };
+
+            // --- Phone number processing with uniqueness check ---
+            // Filter out opted-out phone numbers, those that already exist and
+            // repeats within the row (and any empties).
+            let unique_candidates = phone_tally.select(owner.phone_candidates, optout, global_phone_set);
+
+            // If no unique phone numbers, skip the record entirely unless phones
+            // are optional, in which case the address is kept without a phonequeue row.
+            // A row whose phones are all opted out is handled the same way.
+            if unique_candidates.is_empty() && config.require_phone {
+                rejected_rows += 1;
+                METRICS.reject("no_unique_phone", 1);
+                if let Some(rejects) = rejects.as_mut() {
+                    rejects.write(&record, "no_unique_phone")?;
+                }
+                continue;
+            }
+
+            // Assign final phone numbers from the unique candidates (shifting them over).
+            let mut final_phones = unique_candidates;
+            final_phones.truncate(config.phone_columns.len());
+
+            // Update the global phone set with the new unique numbers, so a phone
+            // shared by both owners of a row is only assigned to the first.
+            global_phone_set.extend(final_phones.iter().cloned());
+            phone_tally.new += final_phones.len();
+
+            let phone_record = if final_phones.is_empty() {
+                None
+            } else {
+                Some(PhoneQueueRecord { phones: final_phones })
+            };
+            // --- End phone number processing ---
+
+            combined_batch.push(CombinedRecord {
+                address: address_record,
+                phone: phone_record,
+            });
+        }
+        if combined_batch.len() > batch_len_before_row {
+            if let Some(address_key) = address_key {
+                seen_addresses.insert(address_key);
+            }
+        }
+        // The row is read again on resume; its owners already inserted are then
+        // rejected as `duplicate_dmid`.
+        if limit_reached {
+            eprintln!("Row limit reached while processing {}.", file_name);
+            completed = false;
+            resume_position = record_start;
+            break;
+        }
+
+        if combined_batch.len() >= batch_size {
+            let batch_len = combined_batch.len();
+            let conn = batch_connection(&mut staging, &mut file_tx);
+            let inserted = sink.write(conn, &tables, &mut combined_batch, config).await
+                .context("Failed to process batch")?;
+            processed_rows += inserted;
+            rejected_rows += batch_len - inserted;
+            eprintln!(
+                "[{}] Processed batch: {} rows inserted.",
+                log_timestamp(),
+                inserted
+            );
+            progress.batch_done();
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.batch_done(inserted).await;
+            }
+
+            // Within a file transaction or staging nothing is durable until the end.
+            if let (Some(points), None, None) = (resume_points.as_mut(), &file_tx, &staging) {
+                points.set(&file_name, total_bytes, records.reader().position())?;
+            }
+
+            // Bound the server's undo log and held locks for a large file: commit
+            // what the file transaction holds so far and carry on in a new one.
+            pending_rows += inserted;
+            if let (Some(limit), Some(pool)) = (config.max_pending_rows, pool) {
+                if let Some(tx) = file_tx.take_if(|_| pending_rows >= limit) {
+                    tx.commit().await.context("Failed to commit file transaction")?;
+                    eprintln!(
+                        "[{}] Committed {} pending rows of {} (MAX_PENDING_ROWS).",
+                        log_timestamp(),
+                        pending_rows,
+                        file_name
+                    );
+                    pending_rows = 0;
+                    if let Some(points) = resume_points.as_mut() {
+                        points.set(&file_name, total_bytes, records.reader().position())?;
+                    }
+                    file_tx = Some(pool.begin().await.context("Failed to begin file transaction")?);
+                }
+            }
+        }
+    }
+
+    if !combined_batch.is_empty() {
+        let batch_len = combined_batch.len();
+        let conn = batch_connection(&mut staging, &mut file_tx);
+        let inserted = sink.write(conn, &tables, &mut combined_batch, config).await
+            .context("Failed to process final batch")?;
+        processed_rows += inserted;
+        rejected_rows += batch_len - inserted;
+        eprintln!(
+            "[{}] Processed final batch: {} rows inserted.",
+            log_timestamp(),
+            inserted
+        );
+        // Also paced, so a run of small single-batch files still keeps to the limit.
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.batch_done(inserted).await;
+        }
+    }
+    if let Some(throttle) = &throttle {
+        throttle.log_summary(&file_name);
+    }
+    if let Some(rejects) = rejects {
+        rejects.finish()?;
+    }
+    if let BatchSink::Jsonl(writer) = sink {
+        writer.finish()?;
+    }
+
+    if let Some(tx) = file_tx {
+        tx.commit().await.context("Failed to commit file transaction")?;
+    }
+    // Staged rows only become visible once the whole file is read. A file that
+    // stopped early is discarded and read again from the top next time, so no
+    // resume point is kept for it.
+    let discarded = staging.is_some() && !completed;
+    if let Some(staging) = staging {
+        if completed {
+            let published = staging.publish(&config.address_columns, &config.phone_columns).await?;
+            eprintln!("Published {} staged rows from {} to the live tables.", published, file_name);
+        } else {
+            staging.discard().await;
+            eprintln!(
+                "Discarded {} staged rows from {}, which stopped before the end (STAGING_MODE).",
+                processed_rows, file_name
+            );
+            processed_rows = 0;
+        }
+    }
+
+    if let Some(points) = resume_points.as_mut() {
+        if completed || discarded {
+            points.clear(&file_name)?;
+        } else {
+            points.set(&file_name, total_bytes, &resume_position)?;
+        }
+    }
+
+    if completed {
+        mark_file_done(pool, config, file_path, &file_name, content_hash.as_deref(), processed_rows).await?;
+        eprintln!(
+            "File {} processed successfully with {} rows inserted.",
+            file_name, processed_rows
+        );
+    } else {
+        eprintln!(
+            "File {} partially processed. Processed {} out of {} rows. It will be reprocessed.",
+            file_name, processed_rows, row_counter
+        );
+    }
+
+    for (reason, count) in &rule_rejections {
+        eprintln!("{} rows of {} rejected by validation rule: {}", count, file_name, reason);
+    }
+    if geocoded_rows > 0 {
+        eprintln!("{} rows of {} were geocoded.", geocoded_rows, file_name);
+    }
+    eprintln!(
+        "{} of {} phones in {} are new; {} were already taken and {} repeated within a row.",
+        phone_tally.new, phone_tally.seen, file_name, phone_tally.existing, phone_tally.repeated
+    );
+    if phone_tally.suppressed > 0 {
+        eprintln!(
+            "{} phones of {} were dropped as opted out (OPTOUT_TABLE).",
+            phone_tally.suppressed, file_name
+        );
+    }
+    if mailing_fallback_rows > 0 {
+        eprintln!(
+            "{} rows of {} had no mailing address; the property address was used (MAILING_FALLBACK_TO_PROPERTY).",
+            mailing_fallback_rows, file_name
+        );
+    }
+    if invalid_coordinate_rows > 0 {
+        eprintln!(
+            "WARNING: {} rows of {} have a latitude or longitude that isn't a valid coordinate; treated as blank.",
+            invalid_coordinate_rows, file_name
+        );
+    }
+    if missing_coordinate_rows > 0 {
+        let handling = match config.missing_coordinates {
+            MissingCoordinates::Empty => "stored as empty strings",
+            MissingCoordinates::Null => "stored as NULL",
+            MissingCoordinates::Skip => "rejected",
+        };
+        eprintln!(
+            "{} rows of {} have a blank latitude or longitude; {} (MISSING_COORDINATES).",
+            missing_coordinate_rows, file_name, handling
+        );
+    }
+
+    METRICS.files_processed.inc();
+    Ok(FileSummary {
+        rows_read: row_counter,
+        rows_inserted: processed_rows,
+        rows_rejected: rejected_rows,
+        rows_missing_coordinates: missing_coordinate_rows,
+        rows_geocoded: geocoded_rows,
+        rows_mailing_fallback: mailing_fallback_rows,
+        phones_seen: phone_tally.seen,
+        phones_existing: phone_tally.existing,
+        phones_repeated: phone_tally.repeated,
+        phones_new: phone_tally.new,
+        phones_suppressed: phone_tally.suppressed,
+        completed,
+        empty: false,
+        duplicate: false,
+        rule_rejections,
+        campaign_flag: if pool.is_some() { Some(new_flag) } else { campaign_flag },
+    })
+}
+
+/// Handles a file without data rows (header-only or completely empty). Such a
+/// file usually means an upstream export failed, so it's flagged loudly and,
+/// with `QUARANTINE_EMPTY_FILES`, moved aside for investigation.
+async fn handle_empty_file(
+    pool: Option<&Pool<MySql>>,
+    config: &Config,
+    file_path: &Path,
+    file_name: &str,
+    content_hash: Option<&str>,
+) -> Result<FileSummary> {
+    eprintln!("WARNING: File {} contained 0 data rows.", file_name);
+    if config.quarantine_empty_files {
+        quarantine_file(config, file_path, file_name)
+            .with_context(|| format!("Failed to quarantine empty file {}", file_name))?;
+        eprintln!("Empty file {} moved to {}.", file_name, config.quarantine_dir);
+    } else {
+        mark_file_done(pool, config, file_path, file_name, content_hash, 0).await?;
+    }
+    Ok(FileSummary {
+        empty: true,
+        ..FileSummary::skipped()
+    })
+}
+
+/// Moves a file into the quarantine directory under its original name.
+fn quarantine_file(config: &Config, file_path: &Path, file_name: &str) -> std::io::Result<()> {
+    let destination = config.output_path(&config.quarantine_dir, file_path, file_name)?;
+    move_file(file_path, &destination)
+}
+
+/// `EXDEV`, which `rename` returns for a destination on another filesystem on
+/// Linux and macOS; checked in case the error kind isn't mapped.
+const EXDEV: i32 = 18;
+
+/// Moves a file with `fs::rename`, falling back to copy-then-delete when the
+/// destination is on another filesystem, e.g. a processed directory on a
+/// separate mount.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices || e.raw_os_error() == Some(EXDEV) => {
+            copy_then_remove(from, to)
+        }
+        result => result,
+    }
+}
+
+/// The cross-device half of `move_file`. The copy is written under a
+/// temporary name, synced and renamed into place, so an interrupted move
+/// never leaves a truncated file under the final name; a retry overwrites the
+/// leftover. The source is only removed once the copy is complete.
+fn copy_then_remove(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut partial = to.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    fs::copy(from, &partial)?;
+    fs::File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, to)?;
+    fs::remove_file(from)
+}
+
+/// One owner's contact details from a CSV row, before phone dedup.
+struct OwnerCandidate {
+    dmid: String,
+    fname: String,
+    lname: String,
+    fullname: String,
+    phone_candidates: Vec<String>,
+}
+
+/// The CSV column holding a contact's phone for the given phonequeue slot.
+fn phone_source_column(contact: u8, slot: usize) -> String {
+    format!("contact_{}_phone{}", contact, slot)
+}
+
+/// Picks the owner(s) to emit for a CSV row according to `OWNER_MODE`. By default
+/// this is a single record that prefers owner 1 and falls back to owner 2 per
+/// name field, with phones taken per slot from contact 1, else contact 2. In
+/// `primary` mode owner 2 and contact 2 are ignored. In `both` mode, when owner 2
+/// has a non-empty name distinct from owner 1, two records are emitted: owner 1
+/// with contact 1's phones under the lead's DMID, and owner 2 with contact 2's
+/// phones under `<lead_id>-2`.
+fn select_owners<'a>(
+    field: &impl Fn(&str) -> &'a str,
+    lead_id: &str,
+    config: &Config,
+) -> Vec<OwnerCandidate> {
+    let name = |first: &str, last: &str, full: &str| {
+        (
+            clean_name(&config.clean_text(field(first)), config.name_case, config.normalize_names),
+            clean_name(&config.clean_text(field(last)), config.name_case, config.normalize_names),
+            clean_name(&config.clean_text(field(full)), config.name_case, config.normalize_names),
+        )
+    };
+    let phones = |contact: u8| -> Vec<String> {
+        (1..=config.phone_columns.len())
+            .map(|slot| field(&phone_source_column(contact, slot)).to_string())
+            .filter(|p| !p.is_empty())
+            .collect()
+    };
+
+    let (fname_1, lname_1, fullname_1) = name("owner_1_firstname", "owner_1_lastname", "owner_1_name");
+    if config.owner_mode == OwnerMode::Primary {
+        return vec![OwnerCandidate {
+            dmid: lead_id.to_string(),
+            fname: fname_1,
+            lname: lname_1,
+            fullname: fullname_1,
+            phone_candidates: phones(1),
+        }];
+    }
+    let (fname_2, lname_2, fullname_2) = name("owner_2_firstname", "owner_2_lastname", "owner_2_name");
+
+    let owner_2_distinct = !fullname_1.is_empty()
+        && !fullname_2.is_empty()
+        && !fullname_1.eq_ignore_ascii_case(&fullname_2);
+    if config.owner_mode == OwnerMode::Both && owner_2_distinct {
+        return vec![
+            OwnerCandidate {
+                dmid: lead_id.to_string(),
+                fname: fname_1,
+                lname: lname_1,
+                fullname: fullname_1,
+                phone_candidates: phones(1),
+            },
+            OwnerCandidate {
+                dmid: format!("{}-2", lead_id),
+                fname: fname_2,
+                lname: lname_2,
+                fullname: fullname_2,
+                phone_candidates: phones(2),
+            },
+        ];
+    }
+
+    let prefer = |primary: String, fallback: String| if primary.is_empty() { fallback } else { primary };
+    let phone_candidates = (1..=config.phone_columns.len())
+        .filter_map(|slot| {
+            [1, 2]
+                .into_iter()
+                .map(|contact| field(&phone_source_column(contact, slot)))
+                .find(|p| !p.is_empty())
+                .map(str::to_string)
+        })
+        .collect();
+    vec![OwnerCandidate {
+        dmid: lead_id.to_string(),
+        fname: prefer(fname_1, fname_2),
+        lname: prefer(lname_1, lname_2),
+        fullname: prefer(fullname_1, fullname_2),
+        phone_candidates,
+    }]
+}
+
+/// Per-file counts of what became of each candidate phone.
+#[derive(Default)]
+struct PhoneTally {
+    seen: usize,
+    suppressed: usize,
+    existing: usize,
+    repeated: usize,
+    /// Counted by the caller, for the phones it actually assigns.
+    new: usize,
+}
+
+impl PhoneTally {
+    /// Keeps the candidates that are neither opted out, already taken nor
+    /// repeated earlier in the same list, counting each one dropped.
+    fn select(&mut self, candidates: Vec<String>, optout: &HashSet<String>, taken: &HashSet<String>) -> Vec<String> {
+        let mut unique: Vec<String> = Vec::new();
+        for phone in candidates.into_iter().filter(|p| !p.is_empty()) {
+            self.seen += 1;
+            if optout.contains(&phone) {
+                self.suppressed += 1;
+            } else if taken.contains(&phone) {
+                self.existing += 1;
+            } else if unique.contains(&phone) {
+                self.repeated += 1;
+            } else {
+                unique.push(phone);
+            }
+        }
+        unique
+    }
+}
+
+/// Reads a row's `skip_ai` value: a number, or `true`/`false`/`yes`/`no`. Blank
+/// or unrecognized values return `None` so the filename's flag applies.
+fn parse_skip_ai(value: &str) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(flag) = value.parse() {
+        return Some(flag);
+    }
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "y" => Some(1),
+        "false" | "no" | "n" => Some(0),
+        _ => None,
+    }
+}
+
+/// Masks a record value for the logs. Phone-like values keep their last 4
+/// digits, emails their first character and domain, and anything else, such
+/// as a name, only its first character.
+fn redact_pii(value: &str) -> String {
+    let digits: String = value.chars().filter(char::is_ascii_digit).collect();
+    if digits.len() >= 7 && digits.len() * 2 > value.chars().filter(|c| !c.is_whitespace()).count() {
+        return format!("***{}", &digits[digits.len() - 4..]);
+    }
+    let mut chars = value.chars();
+    let first = chars.next().map(String::from).unwrap_or_default();
+    match value.rsplit_once('@') {
+        Some((_, domain)) => format!("{}***@{}", first, domain),
+        None if value.is_empty() => String::new(),
+        None => format!("{}***", first),
+    }
+}
+
+/// Lower-cases an email address, or returns `None` if it doesn't look like one.
+fn canonical_email(raw: &str) -> Option<String> {
+    let email = raw.trim().to_lowercase();
+    EMAIL_REGEX.is_match(&email).then_some(email)
+}
+
+/// Generational suffixes kept upper-case by title casing ("JR", not "Jr").
+const NAME_SUFFIXES: [&str; 5] = ["JR", "SR", "II", "III", "IV"];
+
+/// Named HTML entities seen in vendor exports, without the `&` and `;`.
+const HTML_ENTITIES: [(&str, char); 24] = [
+    ("amp", '&'),
+    ("lt", '<'),
+    ("gt", '>'),
+    ("quot", '"'),
+    ("apos", '\''),
+    ("nbsp", ' '),
+    ("ndash", '–'),
+    ("mdash", '—'),
+    ("lsquo", '‘'),
+    ("rsquo", '’'),
+    ("ldquo", '“'),
+    ("rdquo", '”'),
+    ("hellip", '…'),
+    ("aacute", 'á'),
+    ("eacute", 'é'),
+    ("iacute", 'í'),
+    ("oacute", 'ó'),
+    ("uacute", 'ú'),
+    ("Aacute", 'Á'),
+    ("Eacute", 'É'),
+    ("ntilde", 'ñ'),
+    ("Ntilde", 'Ñ'),
+    ("uuml", 'ü'),
+    ("ccedil", 'ç'),
+];
+
+/// Decodes the named entities in `HTML_ENTITIES` and numeric entities
+/// (`&#39;`, `&#x27;`). Double-encoded text such as `&amp;#39;` is decoded
+/// again, up to three times. Anything that isn't a known entity is kept as is.
+fn decode_html_entities(s: &str) -> String {
+    let mut decoded = s.to_string();
+    for _ in 0..3 {
+        if !decoded.contains('&') {
+            break;
+        }
+        let mut out = String::with_capacity(decoded.len());
+        let mut rest = decoded.as_str();
+        while let Some(start) = rest.find('&') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let entity = rest[1..].find(';').filter(|&end| end <= 10).and_then(|end| {
+                let name = &rest[1..end + 1];
+                let ch = match name.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok().and_then(char::from_u32),
+                    Some(dec) => dec.parse().ok().and_then(char::from_u32),
+                    None => HTML_ENTITIES.iter().find(|(entity, _)| *entity == name).map(|(_, ch)| *ch),
+                };
+                ch.map(|ch| (ch, end + 2))
+            });
+            match entity {
+                Some((ch, len)) => {
+                    out.push(ch);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        if out == decoded {
+            break;
+        }
+        decoded = out;
+    }
+    decoded
+}
+
+/// Trims a field and collapses internal runs of whitespace to single spaces
+/// ("123  MAIN  ST" -> "123 MAIN ST").
+fn normalize_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// USPS directional abbreviations (Publication 28).
+const DIRECTIONALS: [(&str, &str); 8] = [
+    ("NORTH", "N"), ("SOUTH", "S"), ("EAST", "E"), ("WEST", "W"),
+    ("NORTHEAST", "NE"), ("NORTHWEST", "NW"), ("SOUTHEAST", "SE"), ("SOUTHWEST", "SW"),
+];
+
+/// USPS street suffix abbreviations (Publication 28, Appendix C1) for common
+/// suffixes and their frequent variants.
+const STREET_SUFFIXES: [(&str, &str); 34] = [
+    ("ALLEY", "ALY"), ("AVENUE", "AVE"), ("AV", "AVE"), ("AVEN", "AVE"), ("AVNUE", "AVE"),
+    ("BOULEVARD", "BLVD"), ("BOULV", "BLVD"), ("CIRCLE", "CIR"), ("CIRC", "CIR"),
+    ("COURT", "CT"), ("COVE", "CV"), ("CROSSING", "XING"), ("DRIVE", "DR"), ("DRIV", "DR"),
+    ("EXPRESSWAY", "EXPY"), ("FREEWAY", "FWY"), ("HIGHWAY", "HWY"), ("HIWAY", "HWY"),
+    ("LANE", "LN"), ("LOOP", "LOOP"), ("PARKWAY", "PKWY"), ("PKY", "PKWY"), ("PLACE", "PL"),
+    ("PLAZA", "PLZ"), ("POINT", "PT"), ("ROAD", "RD"), ("SQUARE", "SQ"), ("STREET", "ST"),
+    ("STR", "ST"), ("TERRACE", "TER"), ("TRAIL", "TRL"), ("TRAILS", "TRL"), ("TURNPIKE", "TPKE"),
+    ("WAY", "WAY"),
+];
+
+fn usps_abbreviation(word: &str, table: &[(&str, &'static str)]) -> Option<&'static str> {
+    table.iter().find(|(long, short)| *long == word || *short == word).map(|(_, short)| *short)
+}
+
+/// USPS state and territory abbreviations (Publication 28, Appendix B).
+const STATE_CODES: [(&str, &str); 56] = [
+    ("ALABAMA", "AL"), ("ALASKA", "AK"), ("ARIZONA", "AZ"), ("ARKANSAS", "AR"), ("CALIFORNIA", "CA"),
+    ("COLORADO", "CO"), ("CONNECTICUT", "CT"), ("DELAWARE", "DE"), ("DISTRICT OF COLUMBIA", "DC"),
+    ("FLORIDA", "FL"), ("GEORGIA", "GA"), ("HAWAII", "HI"), ("IDAHO", "ID"), ("ILLINOIS", "IL"),
+    ("INDIANA", "IN"), ("IOWA", "IA"), ("KANSAS", "KS"), ("KENTUCKY", "KY"), ("LOUISIANA", "LA"),
+    ("MAINE", "ME"), ("MARYLAND", "MD"), ("MASSACHUSETTS", "MA"), ("MICHIGAN", "MI"), ("MINNESOTA", "MN"),
+    ("MISSISSIPPI", "MS"), ("MISSOURI", "MO"), ("MONTANA", "MT"), ("NEBRASKA", "NE"), ("NEVADA", "NV"),
+    ("NEW HAMPSHIRE", "NH"), ("NEW JERSEY", "NJ"), ("NEW MEXICO", "NM"), ("NEW YORK", "NY"),
+    ("NORTH CAROLINA", "NC"), ("NORTH DAKOTA", "ND"), ("OHIO", "OH"), ("OKLAHOMA", "OK"), ("OREGON", "OR"),
+    ("PENNSYLVANIA", "PA"), ("RHODE ISLAND", "RI"), ("SOUTH CAROLINA", "SC"), ("SOUTH DAKOTA", "SD"),
+    ("TENNESSEE", "TN"), ("TEXAS", "TX"), ("UTAH", "UT"), ("VERMONT", "VT"), ("VIRGINIA", "VA"),
+    ("WASHINGTON", "WA"), ("WEST VIRGINIA", "WV"), ("WISCONSIN", "WI"), ("WYOMING", "WY"),
+    ("PUERTO RICO", "PR"), ("GUAM", "GU"), ("VIRGIN ISLANDS", "VI"), ("AMERICAN SAMOA", "AS"),
+    ("NORTHERN MARIANA ISLANDS", "MP"),
+];
+
+/// The two-letter code for a state given by code or full name, ignoring case,
+/// periods and extra whitespace ("fl", "N.Y.", "new  york"). Other
+/// abbreviations, such as "Fla", are not recognized.
+fn normalize_state(state: &str) -> Option<&'static str> {
+    let state = normalize_whitespace(&state.replace('.', "")).to_ascii_uppercase();
+    usps_abbreviation(&state, &STATE_CODES)
+}
+
+/// Filters rows by property state with `STATE_ALLOWLIST` and `STATE_BLOCKLIST`.
+/// The default filters nothing.
+#[derive(Clone, Default)]
+pub struct StateFilter {
+    allow: Option<HashSet<&'static str>>,
+    block: HashSet<&'static str>,
+}
+
+impl StateFilter {
+    /// Entries may be codes or full names; anything else is an error, since a
+    /// typo would otherwise silently filter every row or none.
+    pub fn new(allow: Vec<String>, block: Vec<String>) -> Result<Self> {
+        let codes = |key: &str, states: Vec<String>| {
+            states
+                .iter()
+                .map(|state| {
+                    normalize_state(state)
+                        .ok_or_else(|| anyhow::anyhow!("Invalid value for {}: unknown state `{}`", key, state))
+                })
+                .collect::<Result<HashSet<_>>>()
+        };
+        Ok(Self {
+            allow: Some(codes("STATE_ALLOWLIST", allow)?).filter(|allow| !allow.is_empty()),
+            block: codes("STATE_BLOCKLIST", block)?,
+        })
+    }
+
+    /// Whether a row with this property state may be imported. With an
+    /// allowlist, a blank or unrecognized state is filtered too.
+    fn allows(&self, state: &str) -> bool {
+        let code = normalize_state(state);
+        let allowed = match (&self.allow, code) {
+            (None, _) => true,
+            (Some(allow), Some(code)) => allow.contains(code),
+            (Some(_), None) => false,
+        };
+        allowed && !code.is_some_and(|code| self.block.contains(code))
+    }
+}
+
+/// USPS secondary unit designators (Publication 28, Appendix C2).
+const UNIT_DESIGNATORS: [(&str, &str); 23] = [
+    ("APARTMENT", "APT"), ("BASEMENT", "BSMT"), ("BUILDING", "BLDG"), ("DEPARTMENT", "DEPT"),
+    ("FLOOR", "FL"), ("FRONT", "FRNT"), ("HANGAR", "HNGR"), ("LOBBY", "LBBY"), ("LOT", "LOT"),
+    ("LOWER", "LOWR"), ("OFFICE", "OFC"), ("PENTHOUSE", "PH"), ("PIER", "PIER"), ("REAR", "REAR"),
+    ("ROOM", "RM"), ("SIDE", "SIDE"), ("SLIP", "SLIP"), ("SPACE", "SPC"), ("STOP", "STOP"),
+    ("SUITE", "STE"), ("TRAILER", "TRLR"), ("UNIT", "UNIT"), ("UPPER", "UPPR"),
+];
+
+/// Splits a secondary address line into its unit designator and the unit number
+/// ("APT 4B" -> ("APT", "4B"), "#12" -> ("#", "12"), "Suite 200" -> ("STE", "200")).
+/// A line that doesn't start with a known designator is returned whole as the
+/// unit number.
+fn split_unit_designator(line: &str) -> (String, String) {
+    let line = line.trim();
+    if let Some(number) = line.strip_prefix('#') {
+        return ("#".to_string(), number.trim().to_string());
+    }
+    let word_len = line.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(line.len());
+    let (word, rest) = line.split_at(word_len);
+    match usps_abbreviation(&word.to_ascii_uppercase(), &UNIT_DESIGNATORS) {
+        Some(designator) => {
+            let number = rest.trim_start_matches(|c: char| c == '.' || c == '#' || c.is_whitespace());
+            (designator.to_string(), number.trim_end().to_string())
+        }
+        None => (String::new(), line.to_string()),
+    }
+}
+
+/// Whether the owner lives at the property, judged by comparing the property and
+/// mailing streets after `normalize_address` and their 5-digit zips, as in
+/// `ADDRESS_DEDUP=normalized`. Unit lines are not compared. `None` when either
+/// street or zip is blank, rather than guessing.
+fn owner_occupied(street: &str, zip: &str, mailing_street: &str, mailing_zip: &str) -> Option<bool> {
+    let zip5 = |zip: &str| zip.trim().chars().take(5).collect::<String>();
+    if [street, zip, mailing_street, mailing_zip].iter().any(|part| part.trim().is_empty()) {
+        return None;
+    }
+    Some(normalize_address(street) == normalize_address(mailing_street) && zip5(zip) == zip5(mailing_zip))
+}
+
+/// Parses a coordinate and formats it with `decimals` decimal places. `None`
+/// for a value that isn't a finite number within `±limit` degrees, including
+/// a blank one.
+fn round_coordinate(raw: &str, limit: f64, decimals: usize) -> Option<String> {
+    let value: f64 = raw.parse().ok()?;
+    (value.is_finite() && value.abs() <= limit).then(|| format!("{:.*}", decimals, value))
+}
+
+/// Normalizes a street address line toward the USPS standard: upper-cases it,
+/// collapses whitespace, drops trailing periods and commas from words, and
+/// abbreviates the street suffix and pre/post directionals ("123 north main
+/// street." -> "123 N MAIN ST"). A directional or suffix that is the street's
+/// only name ("100 NORTH RD", "5 PARKWAY") is left spelled out.
+fn normalize_address(street: &str) -> String {
+    let mut words: Vec<String> = street
+        .split_whitespace()
+        .map(|word| word.trim_end_matches(['.', ',']).to_uppercase())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    let has_number = words.first().is_some_and(|w| w.chars().next().is_some_and(|c| c.is_ascii_digit()));
+    let name_start = usize::from(has_number);
+    let mut name_end = words.len();
+
+    // Post-directional, only when it follows a street suffix ("MAIN STREET NORTH").
+    if name_end >= name_start + 3 {
+        let last = name_end - 1;
+        let suffix_before = usps_abbreviation(&words[last - 1], &STREET_SUFFIXES).is_some();
+        if let (Some(short), true) = (usps_abbreviation(&words[last], &DIRECTIONALS), suffix_before) {
+            words[last] = short.to_string();
+            name_end -= 1;
+        }
+    }
+
+    // Street suffix, only when a street name precedes it.
+    if name_end >= name_start + 2 {
+        let last = name_end - 1;
+        if let Some(short) = usps_abbreviation(&words[last], &STREET_SUFFIXES) {
+            words[last] = short.to_string();
+            name_end -= 1;
+        }
+    }
+
+    // Pre-directional, only when a street name follows it.
+    if name_end >= name_start + 2 {
+        if let Some(short) = usps_abbreviation(&words[name_start], &DIRECTIONALS) {
+            words[name_start] = short.to_string();
+        }
+    }
+
+    words.join(" ")
+}
+
+/// Cleans up an owner name for mail merge: collapses runs of whitespace, strips
+/// trailing punctuation, reorders the "Last, First" form to "First Last" when the
+/// name contains exactly one comma, and applies the configured casing.
+fn clean_name(raw: &str, case: NameCase, name_prefixes: bool) -> String {
+    let collapsed = normalize_whitespace(raw);
+    let mut name = collapsed
+        .trim_end_matches(|c: char| c.is_ascii_punctuation() && c != ')')
+        .to_string();
+
+    if name.matches(',').count() == 1 {
+        let (last, first) = name.split_once(',').unwrap_or_default();
+        let (last, first) = (last.trim(), first.trim());
+        if !last.is_empty() && !first.is_empty() {
+            // "SMITH, JOHN JR" -> "JOHN SMITH JR": keep a suffix at the end.
+            let mut first_words: Vec<&str> = first.split(' ').collect();
+            let suffix = first_words
+                .last()
+                .filter(|w| is_name_suffix(w))
+                .copied();
+            if suffix.is_some() {
+                first_words.pop();
+            }
+            let mut parts = vec![first_words.join(" "), last.to_string()];
+            parts.extend(suffix.map(str::to_string));
+            name = parts.join(" ");
+        }
+    }
+
+    match case {
+        NameCase::Upper => name.to_uppercase(),
+        NameCase::Lower => name.to_lowercase(),
+        NameCase::Title => name
+            .split(' ')
+            .map(|word| {
+                if is_name_suffix(word) {
+                    word.to_uppercase()
+                } else {
+                    title_case_word(word, name_prefixes)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn is_name_suffix(word: &str) -> bool {
+    let word = word.trim_end_matches('.').to_ascii_uppercase();
+    NAME_SUFFIXES.contains(&word.as_str())
+}
+
+/// Surnames starting with "Mac" that are not Gaelic patronymics and stay
+/// "Machado" rather than "MacHado".
+const MAC_EXCEPTIONS: [&str; 20] = [
+    "MACEDO", "MACEVICIUS", "MACHADO", "MACHAR", "MACHEN", "MACHIN", "MACHLIN", "MACIAS",
+    "MACIEL", "MACIN", "MACIULIS", "MACKEN", "MACKEY", "MACKIE", "MACKLE", "MACKLIN",
+    "MACKMIN", "MACOMBER", "MACON", "MACQUARIE",
+];
+
+/// Title-cases one word, capitalizing each hyphen-separated part ("mary-jane" -> "Mary-Jane").
+/// With `name_prefixes`, the Mc/Mac/O'/D' prefixes also capitalize the following
+/// letter ("MCDONALD" -> "McDonald", "o'brien" -> "O'Brien").
+fn title_case_word(word: &str, name_prefixes: bool) -> String {
+    word.split('-')
+        .map(|part| {
+            let titled = capitalize(part);
+            let prefix_len = if name_prefixes { name_prefix_len(&titled) } else { 0 };
+            if prefix_len == 0 {
+                titled
+            } else {
+                format!("{}{}", &titled[..prefix_len], capitalize(&titled[prefix_len..]))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Byte length of a Mc/Mac/O'/D' prefix at the start of a capitalized name part,
+/// or 0 if there is none (or nothing follows it).
+fn name_prefix_len(part: &str) -> usize {
+    let upper = part.to_uppercase();
+    let prefix_len = if upper.starts_with("MC") {
+        2
+    } else if upper.starts_with("MAC") && upper.len() > 5 && !MAC_EXCEPTIONS.contains(&upper.as_str()) {
+        3
+    } else if upper.starts_with("O'") || upper.starts_with("D'") {
+        2
+    } else if upper.starts_with("O\u{2019}") || upper.starts_with("D\u{2019}") {
+        1 + '\u{2019}'.len_utf8()
+    } else {
+        0
+    };
+    if part.len() > prefix_len {
+        prefix_len
+    } else {
+        0
+    }
+}
+
+/// Upper-cases the first character and lower-cases the rest.
+fn capitalize(part: &str) -> String {
+    let mut chars = part.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+        None => String::new(),
+    }
+}
+
+/// Ensures that a campaign exists; creates it with the given vertical if not.
+/// The vertical of an existing campaign is left untouched. Once
+/// `campaigns_created` reaches `max_new_campaigns`, creating another fails
+/// with `NewCampaignLimit`.
+///
+/// New flags come from the single `flag_sequence` row, which is locked with
+/// `SELECT ... FOR UPDATE` for the duration of the creating transaction. Concurrent
+/// creators therefore queue on that row, so two new campaigns can never receive
+/// the same flag and the same name can't be created twice.
+async fn ensure_campaign(
+    pool: &Pool<MySql>,
+    campaign_name: &str,
+    vertical: i64,
+    isolation_level: Option<IsolationLevel>,
+    max_new_campaigns: Option<usize>,
+    campaigns_created: &AtomicUsize,
+) -> Result<(i64, i64)> {
+    if let Some(campaign) = find_campaign(pool, campaign_name).await? {
+        return Ok(campaign);
+    }
+
+    let emoji: Option<String> = sqlx::query_scalar("SELECT e FROM emoji ORDER BY RAND() LIMIT 1")
+        .fetch_one(pool)
+        .await
+        .ok();
+
+    let mut conn = pool.acquire().await
+        .context("Failed to acquire connection for campaign creation")?;
+    if let Some(level) = isolation_level {
+        // Applies to the next transaction started on this connection.
+        sqlx::query(&format!("SET TRANSACTION ISOLATION LEVEL {}", level.as_sql()))
+            .execute(&mut *conn)
+            .await
+            .context("Failed to set transaction isolation level")?;
+    }
+    let mut tx = conn.begin().await
+        .context("Failed to begin campaign transaction")?;
+
+    // Seed the sequence on first use from the campaigns already present.
+    sqlx::query(
+        "INSERT IGNORE INTO flag_sequence (id, next_flag) SELECT 1, COALESCE(MAX(flag), 0) + 1 FROM campaigns",
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Failed to seed flag sequence")?;
+    let next_flag: i64 = sqlx::query_scalar("SELECT next_flag FROM flag_sequence WHERE id = 1 FOR UPDATE")
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to lock flag sequence")?;
+
+    // Another process may have created the campaign while we waited for the lock.
+    if let Some(campaign) = find_campaign(&mut *tx, campaign_name).await? {
+        tx.commit().await.context("Failed to commit campaign transaction")?;
+        return Ok(campaign);
+    }
+    // Reserved before inserting, so files processed at the same time can't
+    // both take the last slot.
+    let slot = match max_new_campaigns {
+        Some(limit) => {
+            campaigns_created
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |created| {
+                    (created < limit).then_some(created + 1)
+                })
+                .map_err(|_| NewCampaignLimit { limit })?;
+            CampaignSlot(Some(campaigns_created))
+        }
+        None => CampaignSlot(None),
+    };
+
+    // Stay ahead of campaigns created by writers that don't use the sequence.
+    let row = sqlx::query("SELECT MAX(flag) AS highest_flag FROM campaigns")
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to retrieve highest flag from campaigns")?;
+    let highest_flag = get_integer(&row, "highest_flag")?.unwrap_or(0);
+    let new_flag = highest_flag
+        .checked_add(1)
+        .map(|flag| flag.max(next_flag))
+        .ok_or_else(|| anyhow::anyhow!("Campaign flags are exhausted: highest flag is {}", highest_flag))?;
+
+    let insert_result = sqlx::query(
+        r#"
+        INSERT INTO campaigns (campaignName, vertical, textingActive, flag, emoji)
+        VALUES (?, ?, 0, ?, ?)
+        "#,
+    )
+    .bind(campaign_name)
+    .bind(vertical)
+    .bind(new_flag)
+    .bind(emoji.unwrap_or_default())
+    .execute(&mut *tx)
+    .await
+    .context("Failed to insert new campaign")?;
+
+    sqlx::query("UPDATE flag_sequence SET next_flag = ? WHERE id = 1")
+        .bind(new_flag.saturating_add(1))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to advance flag sequence")?;
+
+    tx.commit().await.context("Failed to commit campaign transaction")?;
+    slot.keep();
+    METRICS.campaigns_created.inc();
+
+    let campaign_id = signed_id(insert_result.last_insert_id(), "campaign ID")?;
+    Ok((campaign_id, new_flag))
+}
+
+/// Looks up the existing campaign with the given flag, returning its `(id, flag)`.
+/// Unlike `ensure_campaign`, a missing campaign is an error.
+async fn campaign_for_flag(pool: &Pool<MySql>, flag: i64) -> Result<(i64, i64)> {
+    let row = sqlx::query("SELECT id FROM campaigns WHERE flag = ? ORDER BY id LIMIT 1")
+        .bind(flag)
+        .fetch_optional(pool)
+        .await
+        .context("Database query failed for campaigns")?
+        .ok_or_else(|| anyhow::anyhow!("No campaign exists with flag {}", flag))?;
+    let campaign_id = get_integer(&row, "id")
+        .context("Failed to retrieve campaign ID")?
+        .ok_or_else(|| anyhow::anyhow!("Campaign with flag {} has a NULL id", flag))?;
+    Ok((campaign_id, flag))
+}
+
+/// Reads an integer column that may be signed or `UNSIGNED` (sqlx refuses to
+/// decode one as the other). An unsigned value beyond `i64::MAX` is an error
+/// rather than wrapping to a negative number.
+fn get_integer(row: &MySqlRow, column: &str) -> Result<Option<i64>> {
+    match row.try_get::<Option<i64>, _>(column) {
+        Ok(value) => Ok(value),
+        Err(sqlx::Error::ColumnDecode { .. }) => {
+            let value: Option<u64> = row
+                .try_get(column)
+                .with_context(|| format!("Failed to read integer column {}", column))?;
+            value.map(|value| signed_id(value, column)).transpose()
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read integer column {}", column)),
+    }
+}
+
+/// Converts an unsigned ID, such as `last_insert_id`, to the `i64` used
+/// throughout, failing instead of wrapping if it is out of range.
+fn signed_id(id: u64, what: &str) -> Result<i64> {
+    i64::try_from(id).map_err(|_| anyhow::anyhow!("{} {} exceeds the signed 64-bit range", what, id))
+}
+
+/// Looks up a campaign by name, returning its `(id, flag)`.
+async fn find_campaign<'e, E>(executor: E, campaign_name: &str) -> Result<Option<(i64, i64)>>
+where
+    E: sqlx::Executor<'e, Database = MySql>,
+{
+    let row_opt = sqlx::query("SELECT id, flag FROM campaigns WHERE campaignName = ?")
+        .bind(campaign_name)
+        .fetch_optional(executor)
+        .await
+        .context("Database query failed for campaigns")?;
+
+    match row_opt {
+        Some(row) => {
+            let campaign_id = get_integer(&row, "id")
+                .context("Failed to retrieve campaign ID")?
+                .ok_or_else(|| anyhow::anyhow!("Campaign {:?} has a NULL id", campaign_name))?;
+            let flag = get_integer(&row, "flag")
+                .context("Failed to retrieve campaign flag")?
+                .ok_or_else(|| anyhow::anyhow!("Campaign {:?} has a NULL flag", campaign_name))?;
+            Ok(Some((campaign_id, flag)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The raw filename stem and the campaign name derived from it by
+/// `CAMPAIGN_NAME_TRANSFORM`.
+fn campaign_name_for(config: &Config, original_filename: &str) -> (String, String) {
+    let raw_stem = Path::new(original_filename)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let campaign_name = config.campaign_name_transform.apply(&raw_stem);
+    (raw_stem, campaign_name)
+}
+
+/// What `--reprocess-flag` deletes and re-imports for one campaign.
+#[derive(Debug)]
+pub struct ReprocessPlan {
+    pub flag: i64,
+    pub campaign_name: String,
+    pub address_rows: i64,
+    pub phonequeue_rows: i64,
+    /// Original files of the campaign: in `PROCESSED_DIR` with move tracking,
+    /// in `UPLOAD_DIR` with database tracking.
+    pub files: Vec<PathBuf>,
+}
+
+/// Finds the campaign with `flag`, counts its rows and locates the files it
+/// was imported from, by matching the campaign name derived from each
+/// filename. Nothing is changed.
+pub async fn plan_reprocess(pool: &Pool<MySql>, config: &Config, flag: i64) -> Result<ReprocessPlan> {
+    if config.upload_dir.starts_with("s3://") {
+        return Err(anyhow::anyhow!("--reprocess-flag isn't supported with an S3 UPLOAD_DIR"));
+    }
+    let campaign_name: String = sqlx::query_scalar("SELECT campaignName FROM campaigns WHERE flag = ? ORDER BY id LIMIT 1")
+        .bind(flag)
+        .fetch_optional(pool)
+        .await
+        .context("Database query failed for campaigns")?
+        .ok_or_else(|| anyhow::anyhow!("No campaign exists with flag {}", flag))?;
+    let address_rows: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {} WHERE flag = ?", config.address_table))
+        .bind(flag)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count address rows")?;
+    let phonequeue_rows: i64 = sqlx::query_scalar(&format!(
+        "SELECT COUNT(*) FROM {} WHERE aid IN (SELECT id FROM {} WHERE flag = ?)",
+        config.phonequeue_table, config.address_table
+    ))
+    .bind(flag)
+    .fetch_one(pool)
+    .await
+    .context("Failed to count phonequeue rows")?;
+
+    let source_dir = match config.processed_tracking {
+        ProcessedTracking::Move => &config.processed_dir,
+        ProcessedTracking::Database => &config.upload_dir,
+    };
+    let files: Vec<PathBuf> = get_csv_files(source_dir, &config.upload_glob, &[])
+        .with_context(|| format!("Failed to list files in {}", source_dir))?
+        .into_iter()
+        .filter(|path| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            config
+                .filename_pattern
+                .parse(&file_name)
+                .is_some_and(|parts| campaign_name_for(config, parts.original_filename).1 == campaign_name)
+        })
+        .collect();
+    if files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No files for campaign {:?} (flag {}) found in {}; nothing was deleted",
+            campaign_name,
+            flag,
+            source_dir
+        ));
+    }
+
+    Ok(ReprocessPlan {
+        flag,
+        campaign_name,
+        address_rows,
+        phonequeue_rows,
+        files,
+    })
+}
+
+/// Carries out a `ReprocessPlan`: the campaign's files are made pending again,
+/// by moving them back to `UPLOAD_DIR` and forgetting their `processed_files`
+/// records, and its `address` and `phonequeue` rows are deleted in one
+/// transaction. Files are moved first, so a failed delete leaves the rows in
+/// place and the next run merely skips them as existing DMIDs.
+pub async fn reprocess(pool: &Pool<MySql>, config: &Config, plan: &ReprocessPlan) -> Result<()> {
+    let _lock_guard = ProcessLock::acquire(config).with_context(|| "Failed to acquire process lock")?;
+
+    if config.processed_tracking == ProcessedTracking::Move {
+        for path in &plan.files {
+            return_to_upload_dir(config, path)?;
+        }
+    }
+
+    let mut tx = pool.begin().await.context("Failed to begin transaction")?;
+    if config.processed_tracking == ProcessedTracking::Database || config.duplicates_dir.is_some() {
+        for path in &plan.files {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            sqlx::query("DELETE FROM processed_files WHERE filename = ?")
+                .bind(file_name.as_ref())
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Failed to forget processed file {}", file_name))?;
+        }
+    }
+    // Registered phones would otherwise be rejected as duplicates on re-import.
+    if config.phone_dedup_mode == PhoneDedupMode::Db {
+        sqlx::query(&format!(
+            r#"
+            DELETE r FROM phone_registry r
+            JOIN {phonequeue} q ON r.phone IN ({phones})
+            JOIN {address} a ON a.id = q.aid
+            WHERE a.flag = ?
+            "#,
+            phonequeue = config.phonequeue_table,
+            address = config.address_table,
+            phones = config
+                .phone_columns
+                .iter()
+                .map(|column| format!("q.{}", column))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))
+        .bind(plan.flag)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete registered phones")?;
+    }
+    let phonequeue_rows = sqlx::query(&format!(
+        "DELETE FROM {} WHERE aid IN (SELECT id FROM {} WHERE flag = ?)",
+        config.phonequeue_table, config.address_table
+    ))
+        .bind(plan.flag)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete phonequeue rows")?
+        .rows_affected();
+    let address_rows = sqlx::query(&format!("DELETE FROM {} WHERE flag = ?", config.address_table))
+        .bind(plan.flag)
+        .execute(&mut *tx)
+        .await
+        .context("Failed to delete address rows")?
+        .rows_affected();
+    tx.commit().await.context("Failed to commit reprocess deletes")?;
+
+    eprintln!(
+        "[{}] WARNING: Reprocessing campaign {:?} (flag {}): deleted {} address rows and {} phonequeue rows.",
+        log_timestamp(),
+        plan.campaign_name,
+        plan.flag,
+        address_rows,
+        phonequeue_rows
+    );
+    Ok(())
+}
+
+/// Moves a file from `PROCESSED_DIR` back to the same place under `UPLOAD_DIR`.
+fn return_to_upload_dir(config: &Config, path: &Path) -> Result<()> {
+    // Compared canonically, since glob results drop a leading `./`.
+    let relative = fs::canonicalize(path)
+        .ok()
+        .zip(fs::canonicalize(&config.processed_dir).ok())
+        .and_then(|(path, dir)| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from(path.file_name().unwrap_or_default()));
+    let destination = Path::new(&config.upload_dir).join(relative);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    move_file(path, &destination)
+        .with_context(|| format!("Failed to move {} back to {}", path.display(), destination.display()))?;
+    eprintln!("[{}] Moved {} back to {} for reprocessing.", log_timestamp(), path.display(), destination.display());
+    Ok(())
+}
+
+/// Finds the finished files `--reprocess <glob>` would make pending again: the
+/// files in `PROCESSED_DIR` matching `pattern` (comma-separated, like
+/// `UPLOAD_GLOB`), or with database tracking the matching files in
+/// `UPLOAD_DIR`. Nothing is changed.
+pub fn plan_reprocess_files(config: &Config, pattern: &str) -> Result<Vec<PathBuf>> {
+    if config.upload_dir.starts_with("s3://") {
+        return Err(anyhow::anyhow!("--reprocess isn't supported with an S3 UPLOAD_DIR"));
+    }
+    let source_dir = match config.processed_tracking {
+        ProcessedTracking::Move => &config.processed_dir,
+        ProcessedTracking::Database => &config.upload_dir,
+    };
+    let files = get_csv_files(source_dir, pattern, &[])
+        .with_context(|| format!("Failed to list files in {}", source_dir))?;
+    if files.is_empty() {
+        return Err(anyhow::anyhow!("No files matching {:?} found in {}", pattern, source_dir));
+    }
+    Ok(files)
+}
+
+/// Makes finished files pending again, for `--reprocess <glob>`: they are moved
+/// back to `UPLOAD_DIR` with move tracking, and their `processed_files` records
+/// are forgotten so neither database tracking nor duplicate detection skips
+/// them. Their rows stay in the database, so rows whose DMIDs still exist are
+/// skipped on re-import.
+pub async fn reprocess_files(pool: Option<&Pool<MySql>>, config: &Config, files: &[PathBuf]) -> Result<()> {
+    let _lock_guard = ProcessLock::acquire(config).with_context(|| "Failed to acquire process lock")?;
+
+    if config.processed_tracking == ProcessedTracking::Move {
+        for path in files {
+            return_to_upload_dir(config, path)?;
+        }
+    }
+    if let (Some(pool), true) = (pool, config.records_processed_files()) {
+        for path in files {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            sqlx::query("DELETE FROM processed_files WHERE filename = ?")
+                .bind(file_name.as_ref())
+                .execute(pool)
+                .await
+                .with_context(|| format!("Failed to forget processed file {}", file_name))?;
+        }
+    }
+    eprintln!("[{}] {} file(s) are pending again.", log_timestamp(), files.len());
+    Ok(())
+}
+
+/// Pre-fetches existing DMIDs for a given flag.
+async fn prefetch_dmids(pool: &Pool<MySql>, table: &str, flag: i64) -> Result<HashMap<String, bool>> {
+    let mut map = HashMap::new();
+    let rows = sqlx::query(&format!("SELECT DMID FROM {} WHERE flag = ?", table))
+        .bind(flag)
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch existing DMIDs")?;
+    for row in rows {
+        let dmid: String = row.try_get("DMID")
+            .context("Failed to retrieve DMID from row")?;
+        map.insert(dmid, true);
+    }
+    Ok(map)
+}
+
+/// Pre-fetches existing DMIDs for a given flag, restricted to the given lead IDs.
+/// The lookup is split into `IN (...)` queries of `DMID_LOOKUP_CHUNK_SIZE` IDs each.
+async fn prefetch_dmids_scoped(
+    pool: &Pool<MySql>,
+    table: &str,
+    flag: i64,
+    lead_ids: &HashSet<String>,
+) -> Result<HashMap<String, bool>> {
+    let mut map = HashMap::new();
+    let lead_ids: Vec<&String> = lead_ids.iter().collect();
+    for chunk in lead_ids.chunks(DMID_LOOKUP_CHUNK_SIZE) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let sql = format!(
+            "SELECT DMID FROM {} WHERE flag = ? AND DMID IN ({})",
+            table, placeholders
+        );
+        let mut query = sqlx::query(&sql).bind(flag);
+        for lead_id in chunk {
+            query = query.bind(*lead_id);
+        }
+        let rows = query
+            .fetch_all(pool)
+            .await
+            .context("Failed to fetch existing DMIDs")?;
+        for row in rows {
+            let dmid: String = row.try_get("DMID")
+                .context("Failed to retrieve DMID from row")?;
+            map.insert(dmid, true);
+        }
+    }
+    Ok(map)
+}
+
+/// Builds a row's dedup key, which becomes its DMID: the trimmed values of the
+/// `DEDUP_KEY_COLUMNS` joined with `|`, or just the lead ID by default. Returns
+/// `None` if any of the columns is blank.
+fn dedup_key(record: &StringRecord, key_columns: &[usize]) -> Option<String> {
+    let mut parts = Vec::with_capacity(key_columns.len());
+    for &idx in key_columns {
+        let value = record.get(idx).unwrap_or("").trim();
+        if value.is_empty() {
+            return None;
+        }
+        parts.push(value);
+    }
+    Some(parts.join(DEDUP_KEY_SEPARATOR))
+}
+
+/// Reads a CSV file once and collects the distinct dedup keys it contains.
+fn collect_dedup_keys(
+    source: Box<dyn CsvSource>,
+    dialect: &CsvDialect,
+    key_columns: &[usize],
+) -> Result<HashSet<String>> {
+    let mut rdr = dialect.reader_builder().from_reader(source);
+    Ok(rdr
+        .records()
+        .filter_map(|r| r.ok())
+        .filter_map(|record| dedup_key(&record, key_columns))
+        .collect())
+}
+
+/// Scans a file and picks, for each dedup key, the row to import: the one with
+/// the most non-empty phone columns, then the most non-empty fields overall,
+/// and the earliest among equals. Rows are identified by their byte offset.
+fn select_most_complete_rows(
+    source: Box<dyn CsvSource>,
+    dialect: &CsvDialect,
+    key_columns: &[usize],
+    phone_columns: &[usize],
+) -> Result<HashMap<String, u64>> {
+    let mut rdr = dialect.reader_builder().from_reader(source);
+    let mut best: HashMap<String, ((usize, usize), u64)> = HashMap::new();
+    for record in rdr.records().filter_map(|r| r.ok()) {
+        let (Some(key), Some(position)) = (dedup_key(&record, key_columns), record.position()) else {
+            continue;
+        };
+        let filled = |value: &str| !value.trim().is_empty();
+        let score = (
+            phone_columns
+                .iter()
+                .filter(|&&idx| record.get(idx).is_some_and(filled))
+                .count(),
+            record.iter().filter(|value| filled(value)).count(),
+        );
+        let entry = best.entry(key).or_insert((score, position.byte()));
+        if score > entry.0 {
+            *entry = (score, position.byte());
+        }
+    }
+    Ok(best.into_iter().map(|(key, (_, byte))| (key, byte)).collect())
+}
+
+/// The tables a batch is inserted into.
+#[derive(Debug, Clone)]
+struct TargetTables {
+    address: String,
+    phonequeue: String,
+}
+
+impl TargetTables {
+    /// `ADDRESS_TABLE` and `PHONEQUEUE_TABLE`.
+    fn live(config: &Config) -> Self {
+        Self {
+            address: config.address_table.clone(),
+            phonequeue: config.phonequeue_table.clone(),
+        }
+    }
+
+    /// The temporary tables `STAGING_MODE` creates for these tables.
+    fn staging(&self) -> Self {
+        Self {
+            address: format!("{}_staging", self.address),
+            phonequeue: format!("{}_staging", self.phonequeue),
+        }
+    }
+}
+
+/// Per-file staging for `STAGING_MODE`: temporary copies of the address and
+/// phonequeue tables on a dedicated connection that is detached from the pool.
+/// Temporary tables vanish with their connection, so if the file fails and the
+/// connection is dropped, the staged rows are discarded by the server.
+struct Staging {
+    conn: MySqlConnection,
+    live: TargetTables,
+    tables: TargetTables,
+}
+
+impl Staging {
+    async fn create(pool: &Pool<MySql>, live: TargetTables) -> Result<Self> {
+        let mut conn = pool
+            .acquire()
+            .await
+            .context("Failed to acquire staging connection")?
+            .detach();
+        let tables = live.staging();
+        for (staging, live) in [(&tables.address, &live.address), (&tables.phonequeue, &live.phonequeue)] {
+            sqlx::query(&format!("CREATE TEMPORARY TABLE {} LIKE {}", staging, live))
+                .execute(&mut conn)
+                .await
+                .with_context(|| format!("Failed to create staging table {}", staging))?;
+        }
+        Ok(Self { conn, live, tables })
+    }
+
+    /// Copies the staged rows into the live tables in one transaction and closes
+    /// the staging connection. Staged phone rows point at staging address IDs, so
+    /// they are re-linked to the live rows through the unique DMID.
+    async fn publish(mut self, address_columns: &[&str], phone_columns: &[String]) -> Result<u64> {
+        let columns = address_columns.join(", ");
+        let mut tx = self.conn.begin().await.context("Failed to begin staging publish")?;
+        let published = sqlx::query(&format!(
+            "INSERT INTO {live} ({columns}) SELECT {columns} FROM {staging} ORDER BY id",
+            live = self.live.address,
+            staging = self.tables.address,
+            columns = columns,
+        ))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to publish staged addresses")?
+        .rows_affected();
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {live_phones} (aid, {phones}, step)
+            SELECT a.id, {staged_phone_columns}, p.step
+            FROM {staged_phones} p
+            JOIN {staged_addresses} s ON s.id = p.aid
+            JOIN {live_addresses} a ON a.DMID = s.DMID
+            ORDER BY p.id
+            "#,
+            live_phones = self.live.phonequeue,
+            phones = phone_columns.join(", "),
+            staged_phone_columns = phone_columns
+                .iter()
+                .map(|column| format!("p.{}", column))
+                .collect::<Vec<_>>()
+                .join(", "),
+            staged_phones = self.tables.phonequeue,
+            staged_addresses = self.tables.address,
+            live_addresses = self.live.address,
+        ))
+        .execute(&mut *tx)
+        .await
+        .context("Failed to publish staged phone queue rows")?;
+        tx.commit().await.context("Failed to commit staging publish")?;
+
+        if let Err(e) = self.conn.close().await {
+            eprintln!("Failed to close staging connection: {:?}", e);
+        }
+        Ok(published)
+    }
+
+    /// Closes the staging connection without publishing; the server drops the
+    /// temporary tables and their rows with it.
+    async fn discard(self) {
+        if let Err(e) = self.conn.close().await {
+            eprintln!("Failed to close staging connection: {:?}", e);
+        }
+    }
+}
+
+/// The connection a batch should use: the staging connection, the file
+/// transaction's connection, or none (a fresh pooled transaction per batch).
+fn batch_connection<'a>(
+    staging: &'a mut Option<Staging>,
+    file_tx: &'a mut Option<Transaction<'static, MySql>>,
+) -> Option<&'a mut MySqlConnection> {
+    match (staging, file_tx) {
+        (Some(staging), _) => Some(&mut staging.conn),
+        (None, Some(tx)) => Some(&mut **tx),
+        (None, None) => None,
+    }
+}
+
+/// Time spent in each INSERT statement of a batch, summed over its chunks,
+/// and the round trips the INSERTs took. With `FAST_BATCH` the combined
+/// statement counts as the address insert.
+#[derive(Debug, Default)]
+struct BatchTimings {
+    address_insert: Duration,
+    phone_insert: Duration,
+    round_trips: usize,
+    /// Round trips `FAST_BATCH` avoided by sending a chunk's phone INSERT
+    /// along with its address INSERT.
+    round_trips_saved: usize,
+}
+
+/// Processes a batch of combined records (addresses and optional phone records) in a transaction.
+/// The batch is written as one or more multi-row INSERTs of at most `INSERT_CHUNK_SIZE`
+/// rows each, so a large buffer doesn't produce a statement above `max_allowed_packet`.
+/// Returns the number of address rows inserted, which can be lower than the batch
+/// size when database phone dedup drops records.
+async fn process_batch(
+    pool: &Pool<MySql>,
+    conn: Option<&mut MySqlConnection>,
+    tables: &TargetTables,
+    combined_batch: &mut Vec<CombinedRecord>,
+    config: &Config,
+) -> Result<usize> {
+    let started = Instant::now();
+    let mut timings = BatchTimings::default();
+
+    // On a connection inside a file transaction this opens a savepoint rather
+    // than a new transaction.
+    let mut tx = match conn {
+        Some(conn) => conn.begin().await,
+        None => pool.begin().await,
+    }
+    .context("Failed to begin database transaction")?;
+
+    let batch_len = combined_batch.len();
+    let mut records = std::mem::take(combined_batch).into_iter();
+    let mut inserted_count = 0;
+    loop {
+        let mut chunk: Vec<CombinedRecord> =
+            records.by_ref().take(config.insert_chunk_size.max(1)).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        if config.phone_dedup_mode == PhoneDedupMode::Db {
+            chunk = claim_phones(&mut tx, chunk, config.phone_conflict_policy).await?;
+        }
+        if config.fast_batch {
+            insert_chunk_fast(&mut tx, tables, &chunk, config, &mut timings).await?;
+        } else {
+            insert_chunk(&mut tx, tables, &chunk, config, &mut timings).await?;
+        }
+        inserted_count += chunk.len();
+    }
+
+    let commit_started = Instant::now();
+    tx.commit()
+        .await
+        .context("Failed to commit database transaction")?;
+    let commit_time = commit_started.elapsed();
+
+    let elapsed = started.elapsed();
+    let saved = if config.fast_batch {
+        format!(", {} saved by FAST_BATCH", timings.round_trips_saved)
+    } else {
+        String::new()
+    };
+    let timing = format!(
+        "{} rows in {} ms (address insert {} ms, phone insert {} ms, commit {} ms; {} INSERT round trips{})",
+        batch_len,
+        elapsed.as_millis(),
+        timings.address_insert.as_millis(),
+        timings.phone_insert.as_millis(),
+        commit_time.as_millis(),
+        timings.round_trips,
+        saved
+    );
+    if config.slow_batch_ms.is_some_and(|ms| elapsed >= Duration::from_millis(ms)) {
+        eprintln!("WARNING: Slow batch: {}.", timing);
+    } else if config.log_batch_timings {
+        eprintln!("Batch: {}.", timing);
+    }
+
+    METRICS.rows_inserted.inc_by(inserted_count as u64);
+    METRICS.reject("phone_conflict", (batch_len - inserted_count) as u64);
+    Ok(inserted_count)
+}
+
+/// Claims the chunk's phone numbers in `phone_registry`, whose primary key makes
+/// the database the final arbiter of phone uniqueness even across concurrent
+/// writers. Every phone is upserted with a token unique to this call; the phones
+/// still carrying the token afterwards are the ones this chunk inserted. Phones
+/// that were already registered are dropped from their record, and a record left
+/// without phones is kept or dropped according to `policy`.
+async fn claim_phones(
+    tx: &mut Transaction<'_, MySql>,
+    chunk: Vec<CombinedRecord>,
+    policy: PhoneConflictPolicy,
+) -> Result<Vec<CombinedRecord>> {
+    let phones: Vec<&String> = chunk
+        .iter()
+        .filter_map(|record| record.phone.as_ref())
+        .flat_map(|phone| &phone.phones)
+        .collect();
+    if phones.is_empty() {
+        return Ok(chunk);
+    }
+
+    let token = claim_token();
+    let placeholders = vec!["(?, ?)"; phones.len()].join(", ");
+    let sql = format!(
+        "INSERT INTO phone_registry (phone, claimed_by) VALUES {} ON DUPLICATE KEY UPDATE phone = phone",
+        placeholders
+    );
+    let mut query = sqlx::query(&sql);
+    for phone in &phones {
+        query = query.bind(*phone).bind(&token);
+    }
+    query
+        .execute(&mut **tx)
+        .await
+        .context("Failed to register phone numbers")?;
+
+    let placeholders = vec!["?"; phones.len()].join(", ");
+    let sql = format!(
+        "SELECT phone FROM phone_registry WHERE claimed_by = ? AND phone IN ({})",
+        placeholders
+    );
+    let mut query = sqlx::query(&sql).bind(&token);
+    for phone in &phones {
+        query = query.bind(*phone);
+    }
+    let claimed: HashSet<String> = query
+        .fetch_all(&mut **tx)
+        .await
+        .context("Failed to read back registered phone numbers")?
+        .iter()
+        .map(|row| row.try_get("phone"))
+        .collect::<std::result::Result<_, _>>()?;
+
+    let mut kept = Vec::with_capacity(chunk.len());
+    for mut record in chunk {
+        let Some(phone) = record.phone.take() else {
+            kept.push(record);
+            continue;
+        };
+        let remaining: Vec<String> = phone
+            .phones
+            .into_iter()
+            .filter(|p| claimed.contains(p))
+            .collect();
+        if remaining.is_empty() {
+            eprintln!(
+                "All phones for DMID {} are already registered by another writer.",
+                record.address.dmid
+            );
+            if policy == PhoneConflictPolicy::DropRecord {
+                continue;
+            }
+        } else {
+            record.phone = Some(PhoneQueueRecord { phones: remaining });
+        }
+        kept.push(record);
+    }
+    Ok(kept)
+}
+
+/// A token identifying one `claim_phones` call, unique across processes.
+fn claim_token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Inserts one chunk of records within an open transaction. Phone rows are
+/// aligned to the address IDs generated by this chunk's own address INSERT.
+/// Statement times are added to `timings`.
+async fn insert_chunk(
+    tx: &mut Transaction<'_, MySql>,
+    tables: &TargetTables,
+    chunk: &[CombinedRecord],
+    config: &Config,
+    timings: &mut BatchTimings,
+) -> Result<()> {
+    let address_columns = &config.address_columns;
+    let phone_columns = &config.phone_columns;
+    // Bulk insert addresses using the configured column list.
+    let mut address_query = format!(
+        "INSERT INTO {} ({}) VALUES ",
+        tables.address,
+        address_columns.join(", ")
+    );
+    let row_placeholder = format!("({})", vec!["?"; address_columns.len()].join(", "));
+    address_query += &vec![row_placeholder.as_str(); chunk.len()].join(", ");
+
+    let mut query = sqlx::query(&address_query);
+    for record in chunk {
+        for column in address_columns {
+            query = match column_value(&record.address, column, config.empty_as_null) {
+                ColumnValue::Text(value) => query.bind(value),
+                ColumnValue::Int(value) => query.bind(value),
+            };
+        }
+    }
+
+    let statement_started = Instant::now();
+    let address_result = query
+        .execute(&mut **tx)
+        .await
+        .context("Failed to execute bulk insert for addresses")?;
+    timings.address_insert += statement_started.elapsed();
+    timings.round_trips += 1;
+
+    // Phone rows are matched to addresses by offset from the first generated ID,
+    // which is only valid if every row in the statement was inserted. The ID is
+    // read from the INSERT's own result so no intervening statement can change it.
+    if address_result.rows_affected() != chunk.len() as u64 {
+        return Err(anyhow::anyhow!(
+            "Address insert affected {} rows but {} were sent; refusing to guess phone aids",
+            address_result.rows_affected(),
+            chunk.len()
+        ));
+    }
+    let first_id = signed_id(address_result.last_insert_id(), "address ID")?;
+
+    // Build bulk insert for phone queues for records with phone data.
+    let mut phone_inserts = Vec::new();
+    for (i, record) in chunk.iter().enumerate() {
+        if let Some(phone) = &record.phone {
+            let aid = i64::try_from(i)
+                .ok()
+                .and_then(|offset| first_id.checked_add(offset))
+                .ok_or_else(|| anyhow::anyhow!("Address ID {} + {} exceeds the signed 64-bit range", first_id, i))?;
+            phone_inserts.push((aid, phone));
+        }
+    }
+
+    if !phone_inserts.is_empty() {
+        let mut phone_query = format!(
+            "INSERT INTO {} (aid, {}, step) VALUES ",
+            tables.phonequeue,
+            phone_columns.join(", ")
+        );
+        let row_placeholder = format!("(?, {}, 11)", vec!["?"; phone_columns.len()].join(", "));
+        phone_query += &vec![row_placeholder.as_str(); phone_inserts.len()].join(", ");
+
+        let mut phone_query_builder = sqlx::query(&phone_query);
+        for (aid, phone) in phone_inserts {
+            phone_query_builder = phone_query_builder.bind(aid);
+            for slot in 0..phone_columns.len() {
+                phone_query_builder = phone_query_builder.bind(phone.phones.get(slot));
+            }
+        }
+        let statement_started = Instant::now();
+        phone_query_builder
+            .execute(&mut **tx)
+            .await
+            .context("Failed to execute bulk insert for phone queues")?;
+        timings.phone_insert += statement_started.elapsed();
+        timings.round_trips += 1;
+    }
+
+    Ok(())
+}
+
+/// `FAST_BATCH` version of `insert_chunk`: the address INSERT and the phone
+/// INSERT go to the server as one multi-statement query, with the phone aids
+/// derived there from `LAST_INSERT_ID()`. A multi-statement query can't take
+/// bind parameters, so values are written as literals by `fast_chunk_sql`.
+async fn insert_chunk_fast(
+    tx: &mut Transaction<'_, MySql>,
+    tables: &TargetTables,
+    chunk: &[CombinedRecord],
+    config: &Config,
+    timings: &mut BatchTimings,
+) -> Result<()> {
+    let phone_rows = chunk.iter().filter(|record| record.phone.is_some()).count();
+    let sql = fast_chunk_sql(tables, chunk, config);
+
+    let statement_started = Instant::now();
+    let result = sqlx::raw_sql(&sql)
+        .execute(&mut **tx)
+        .await
+        .context("Failed to execute fast bulk insert for addresses and phone queues")?;
+    timings.address_insert += statement_started.elapsed();
+    timings.round_trips += 1;
+    if phone_rows > 0 {
+        timings.round_trips_saved += 1;
+    }
+
+    // The phone rows were already written against offsets from the first
+    // address ID; a short address insert means they may be wrong, and the
+    // error rolls them back with the rest of the transaction.
+    let expected = (chunk.len() + phone_rows) as u64;
+    if result.rows_affected() != expected {
+        return Err(anyhow::anyhow!(
+            "Fast insert affected {} rows but {} were sent; refusing to keep guessed phone aids",
+            result.rows_affected(),
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// The multi-statement query `insert_chunk_fast` sends for a chunk.
+fn fast_chunk_sql(tables: &TargetTables, chunk: &[CombinedRecord], config: &Config) -> String {
+    let address_rows: Vec<String> = chunk
+        .iter()
+        .map(|record| {
+            let values: Vec<String> = config
+                .address_columns
+                .iter()
+                .map(|column| column_value(&record.address, column, config.empty_as_null).literal())
+                .collect();
+            format!("({})", values.join(", "))
+        })
+        .collect();
+    let mut sql = format!(
+        "INSERT INTO {} ({}) VALUES {};\nSET @dmparser_first_aid = LAST_INSERT_ID();",
+        tables.address,
+        config.address_columns.join(", "),
+        address_rows.join(", ")
+    );
+
+    let phone_rows: Vec<String> = chunk
+        .iter()
+        .enumerate()
+        .filter_map(|(i, record)| {
+            let phone = record.phone.as_ref()?;
+            let mut values = vec![format!("@dmparser_first_aid + {}", i)];
+            values.extend(
+                (0..config.phone_columns.len())
+                    .map(|slot| ColumnValue::Text(phone.phones.get(slot).map(String::as_str)).literal()),
+            );
+            values.push("11".to_string());
+            Some(format!("({})", values.join(", ")))
+        })
+        .collect();
+    if !phone_rows.is_empty() {
+        sql += &format!(
+            "\nINSERT INTO {} (aid, {}, step) VALUES {};",
+            tables.phonequeue,
+            config.phone_columns.join(", "),
+            phone_rows.join(", ")
+        );
+    }
+    sql
+}
+
+/// The value of one address column for a row.
+#[derive(Debug, PartialEq)]
+enum ColumnValue<'a> {
+    Text(Option<&'a str>),
+    Int(Option<i64>),
+}
+
+impl ColumnValue<'_> {
+    /// The value as a SQL literal. Text is written as a hex literal, so it
+    /// needs no escaping and reads the same under any `sql_mode`.
+    fn literal(&self) -> String {
+        match self {
+            ColumnValue::Text(None) | ColumnValue::Int(None) => "NULL".to_string(),
+            ColumnValue::Text(Some("")) => "''".to_string(),
+            ColumnValue::Text(Some(value)) => {
+                let hex: String = value.bytes().map(|b| format!("{:02X}", b)).collect();
+                format!("_utf8mb4 X'{}'", hex)
+            }
+            ColumnValue::Int(Some(value)) => value.to_string(),
+        }
+    }
+}
+
+/// The value written to an address column. With `empty_as_null`, blank text
+/// is NULL; the DMID is never blank and is written as is.
+fn column_value<'a>(addr: &'a AddressRecord, column: &str, empty_as_null: bool) -> ColumnValue<'a> {
+    let text = |value: &'a str| ColumnValue::Text((!(empty_as_null && value.is_empty())).then_some(value));
+    match column {
+        "street" => text(&addr.street),
+        "unit_type" => text(&addr.unit_type),
+        "unit_num" => text(&addr.unit_num),
+        "mail_city" => text(&addr.mail_city),
+        "state" => text(&addr.state),
+        "zip" => text(&addr.zip),
+        "latitude" => addr.latitude.as_deref().map_or(ColumnValue::Text(None), text),
+        "longitude" => addr.longitude.as_deref().map_or(ColumnValue::Text(None), text),
+        "fullname" => text(&addr.fullname),
+        "fname" => text(&addr.fname),
+        "lname" => text(&addr.lname),
+        "mailingAddress" => text(&addr.mailing_address),
+        "mailingCity" => text(&addr.mailing_city),
+        "mailingState" => text(&addr.mailing_state),
+        "mailingZip" => text(&addr.mailing_zip),
+        "flag" => ColumnValue::Int(Some(addr.flag)),
+        "DMID" => ColumnValue::Text(Some(&addr.dmid)),
+        "via" => ColumnValue::Int(Some(addr.via)),
+        "map_image_url" => text(&addr.map_image_url),
+        "email" => text(&addr.email),
+        "owner_occupied" => ColumnValue::Int(addr.owner_occupied.map(i64::from)),
+        other => unreachable!("unknown address column {}", other),
+    }
+}
+
+/// Process exit status of a run. Monitoring treats these differently: a held
+/// lock is benign, while an unreachable database should page someone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// `0`: every file was processed.
+    Success,
+    /// `1`: any error not covered by a more specific status.
+    Failure,
+    /// `2`: invalid command-line arguments or configuration.
+    Config,
+    /// `3`: another run holds the process lock.
+    LockHeld,
+    /// `4`: the database couldn't be reached.
+    Database,
+    /// `5`: a file was only partially processed, because it hit
+    /// `MAX_EXECUTION_SECONDS` or `--limit`; the rest is left for the next run.
+    Partial,
+    /// `6`: at least one file failed and was left in place for retry.
+    FileErrors,
+    /// `7`: the process lock couldn't be created.
+    LockUnavailable,
+}
+
+impl ExitStatus {
+    pub fn code(self) -> i32 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::Failure => 1,
+            ExitStatus::Config => 2,
+            ExitStatus::LockHeld => 3,
+            ExitStatus::Database => 4,
+            ExitStatus::Partial => 5,
+            ExitStatus::FileErrors => 6,
+            ExitStatus::LockUnavailable => 7,
+        }
+    }
+
+    /// Name recorded in the `runs` table.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Failure => "failure",
+            ExitStatus::Config => "config",
+            ExitStatus::LockHeld => "lock_held",
+            ExitStatus::Database => "database",
+            ExitStatus::Partial => "partial",
+            ExitStatus::FileErrors => "file_errors",
+            ExitStatus::LockUnavailable => "lock_unavailable",
+        }
+    }
+
+    /// Classifies an error that ended a run (or its setup).
+    pub fn of_error(error: &anyhow::Error) -> Self {
+        if let Some(lock_error) = error.downcast_ref::<LockError>() {
+            return match lock_error {
+                LockError::AlreadyRunning { .. } => ExitStatus::LockHeld,
+                LockError::Unavailable { .. } => ExitStatus::LockUnavailable,
+            };
+        }
+        if error.downcast_ref::<DatabaseUnavailable>().is_some() {
+            return ExitStatus::Database;
+        }
+        ExitStatus::Failure
+    }
+
+    /// Classifies a finished run. Files that failed outrank partially processed
+    /// ones, since both leave work behind but only failures need a look.
+    pub fn of_run(result: &Result<()>, summary: &RunSummary) -> Self {
+        match result {
+            Err(e) => Self::of_error(e),
+            Ok(()) if !summary.file_errors.is_empty() => ExitStatus::FileErrors,
+            Ok(()) if summary.files_incomplete > 0 => ExitStatus::Partial,
+            Ok(()) => ExitStatus::Success,
+        }
+    }
+}
+
+/// Context attached to a failed database connection, so that the failure can
+/// be told apart from other errors when picking the exit status.
+#[derive(Debug)]
+pub struct DatabaseUnavailable;
+
+impl std::fmt::Display for DatabaseUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Failed to connect to MySQL database")
+    }
+}
+
+/// Returned by `ensure_campaign` once `MAX_NEW_CAMPAIGNS_PER_RUN` campaigns
+/// have been created, so that the file can be quarantined.
+#[derive(Debug)]
+struct NewCampaignLimit {
+    limit: usize,
+}
+
+impl std::fmt::Display for NewCampaignLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "this run already created MAX_NEW_CAMPAIGNS_PER_RUN={} campaigns", self.limit)
+    }
+}
+
+impl std::error::Error for NewCampaignLimit {}
+
+/// A slot taken from the run's `MAX_NEW_CAMPAIGNS_PER_RUN` allowance, given
+/// back when dropped unless the campaign was created.
+struct CampaignSlot<'a>(Option<&'a AtomicUsize>);
+
+impl CampaignSlot<'_> {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CampaignSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(campaigns_created) = self.0 {
+            campaigns_created.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Why the process lock couldn't be taken. The two cases need different
+/// responses, so they map to different exit statuses.
+#[derive(Debug)]
+pub enum LockError {
+    /// Another run holds the lock.
+    AlreadyRunning { path: String },
+    /// The lock couldn't be created at all, e.g. on a read-only or full filesystem.
+    Unavailable { path: String, source: std::io::Error },
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::AlreadyRunning { path } => {
+                write!(f, "Another instance is already running (lock held on {}). Exiting.", path)
+            }
+            LockError::Unavailable { path, source } => {
+                write!(f, "Cannot create the process lock at {}: {}", path, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::AlreadyRunning { .. } => None,
+            LockError::Unavailable { source, .. } => Some(source),
+        }
+    }
+}
+
+/// How concurrent runs are excluded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Create `LOCK_FILE`, failing if it already exists.
+    File,
+    /// Take an advisory `flock` on the upload directory; needs no writable path
+    /// and is released by the OS even if the process is killed.
+    Flock,
+}
+
+impl FromStr for LockMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "file" => Ok(Self::File),
+            "flock" => Ok(Self::Flock),
+            other => Err(format!("expected `file` or `flock`, got `{}`", other)),
+        }
+    }
+}
+
+/// The held process lock. The variants are only held to be dropped: the guard
+/// removes the lock file, and closing the upload directory releases the `flock`.
+#[allow(dead_code)]
+enum ProcessLock {
+    File(LockFileGuard),
+    Flock(fs::File),
+}
+
+impl ProcessLock {
+    fn acquire(config: &Config) -> std::result::Result<Self, LockError> {
+        match config.lock_mode {
+            LockMode::File => LockFileGuard::new(&config.lock_file_path).map(ProcessLock::File),
+            LockMode::Flock => {
+                let path = config.upload_dir.clone();
+                let dir = fs::File::open(&path)
+                    .map_err(|source| LockError::Unavailable { path: path.clone(), source })?;
+                match dir.try_lock() {
+                    Ok(()) => Ok(ProcessLock::Flock(dir)),
+                    Err(fs::TryLockError::WouldBlock) => Err(LockError::AlreadyRunning { path }),
+                    Err(fs::TryLockError::Error(source)) => Err(LockError::Unavailable { path, source }),
+                }
+            }
+        }
+    }
+}
+
+/// A guard for managing the lock file.
+struct LockFileGuard {
+    path: String,
+}
+
+impl LockFileGuard {
+    fn new(path: &str) -> std::result::Result<Self, LockError> {
+        // `create_new` fails atomically if the file exists, so two runs starting
+        // together can't both take the lock.
+        let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(LockError::AlreadyRunning { path: path.to_string() });
+            }
+            Err(source) => return Err(LockError::Unavailable { path: path.to_string(), source }),
+        };
+        let guard = Self { path: path.to_string() };
+        // A lock file we created but couldn't fill is removed again by the guard.
+        std::io::Write::write_all(&mut file, format!("Process started: {}\n", log_timestamp()).as_bytes())
+            .map_err(|source| LockError::Unavailable { path: path.to_string(), source })?;
+        Ok(guard)
+    }
+}
+
+impl Drop for LockFileGuard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove lock file {}: {:?}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_field_with_embedded_comma_stays_intact() {
+        let data = "lead_id,owner_1_name,property_address_line_1\n\
+                    1,\"SMITH, JR\",\"123 MAIN ST, APT 4\"\n";
+        let mut rdr = CsvDialect::default().reader_builder().from_reader(data.as_bytes());
+        let record = rdr.records().next().unwrap().unwrap();
+        assert_eq!(record.len(), 3);
+        assert_eq!(&record[1], "SMITH, JR");
+        assert_eq!(&record[2], "123 MAIN ST, APT 4");
+    }
+
+    #[test]
+    fn single_quote_dialect_with_escape() {
+        let data = "lead_id,owner_1_name\n1,'O\\'BRIEN, PAT'\n";
+        let dialect = CsvDialect {
+            quote: b'\'',
+            escape: Some(b'\\'),
+            double_quote: false,
+            comment: None,
+            flexible: false,
+            encoding: encoding_rs::UTF_8,
+        };
+        let mut rdr = dialect.reader_builder().from_reader(data.as_bytes());
+        let record = rdr.records().next().unwrap().unwrap();
+        assert_eq!(record.len(), 2);
+        assert_eq!(&record[1], "O'BRIEN, PAT");
+    }
+
+    #[test]
+    fn quoted_field_with_embedded_newline_stays_intact() {
+        let data = "lead_id,property_address_line_1,owner_1_name\n\
+                    1,\"123 MAIN ST\nBLDG 2\",SMITH\n\
+                    2,456 OAK AVE,JONES\n";
+        let mut rdr = CsvDialect::default().reader_builder().from_reader(data.as_bytes());
+        let records: Vec<StringRecord> = rdr.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][1], "123 MAIN ST\nBLDG 2");
+        assert_eq!(&records[0][2], "SMITH");
+        assert_eq!(&records[1][0], "2");
+    }
+
+    #[test]
+    fn flexible_dialect_accepts_short_rows() {
+        let data = "lead_id,owner_1_name,contact_1_phone1\n1,SMITH\n2,JONES,5551234567\n";
+        let mut strict = CsvDialect::default().reader_builder().from_reader(data.as_bytes());
+        assert!(strict.records().next().unwrap().is_err());
+
+        let dialect = CsvDialect {
+            flexible: true,
+            ..CsvDialect::default()
+        };
+        let mut rdr = dialect.reader_builder().from_reader(data.as_bytes());
+        let short = rdr.records().next().unwrap().unwrap();
+        assert_eq!(short.get(2).unwrap_or(""), "");
+    }
+
+    #[test]
+    fn comment_lines_are_skipped() {
+        let data = "lead_id,owner_1_name\n1,SMITH\n# notes: second batch below\n2,JONES\n#\n";
+        let dialect = CsvDialect {
+            comment: Some(b'#'),
+            ..CsvDialect::default()
+        };
+        let mut rdr = dialect.reader_builder().from_reader(data.as_bytes());
+        let lead_ids: Vec<String> = rdr
+            .records()
+            .map(|record| record.unwrap()[0].to_string())
+            .collect();
+        assert_eq!(lead_ids, ["1", "2"]);
+    }
+
+    #[test]
+    fn normalize_whitespace_collapses_internal_runs() {
+        assert_eq!(normalize_whitespace("  123  MAIN \t ST  "), "123 MAIN ST");
+        assert_eq!(normalize_whitespace("APT 4"), "APT 4");
+    }
+
+    #[test]
+    fn normalize_whitespace_blank_becomes_empty() {
+        assert_eq!(normalize_whitespace("   "), "");
+        assert_eq!(normalize_whitespace(""), "");
+    }
+
+    #[test]
+    fn normalize_address_abbreviates_suffix_and_directionals() {
+        assert_eq!(normalize_address("123  north main street."), "123 N MAIN ST");
+        assert_eq!(normalize_address("45 Oak Avenue South"), "45 OAK AVE S");
+        assert_eq!(normalize_address("100 North Rd"), "100 NORTH RD");
+        assert_eq!(normalize_address("7 Parkway"), "7 PARKWAY");
+    }
+
+    #[test]
+    fn split_unit_designator_separates_type_and_number() {
+        let split = |line| {
+            let (unit_type, unit_num) = split_unit_designator(line);
+            format!("{}|{}", unit_type, unit_num)
+        };
+        assert_eq!(split("APT 4B"), "APT|4B");
+        assert_eq!(split("#12"), "#|12");
+        assert_eq!(split("STE 200"), "STE|200");
+        assert_eq!(split("Suite 200"), "STE|200");
+        assert_eq!(split("Apt. #3"), "APT|3");
+    }
+
+    #[test]
+    fn normalized_address_key_ignores_formatting() {
+        let key = |street, unit, zip| AddressDedup::Normalized.key(street, unit, zip);
+        assert_eq!(key("123 Main St", "APT 1", "33601"), key("123 MAIN STREET", "#1", "33601-1234"));
+        assert_ne!(key("123 Main St", "APT 1", "33601"), key("123 Main St", "APT 2", "33601"));
+        assert_ne!(
+            AddressDedup::Exact.key("123 Main St", "", "33601"),
+            AddressDedup::Exact.key("123 MAIN STREET", "", "33601")
+        );
+        assert_eq!(key("", "APT 1", "33601"), None);
+        assert_eq!(AddressDedup::Off.key("123 Main St", "", "33601"), None);
+    }
+
+    #[test]
+    fn split_unit_designator_keeps_unrecognized_lines() {
+        assert_eq!(split_unit_designator("4B"), (String::new(), "4B".to_string()));
+        assert_eq!(split_unit_designator("LOTUS 5"), (String::new(), "LOTUS 5".to_string()));
+        assert_eq!(split_unit_designator(""), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn phone_tally_drops_and_counts_each_kind_of_duplicate() {
+        let phones = |list: &[&str]| list.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let optout: HashSet<String> = phones(&["5550001"]).into_iter().collect();
+        let taken: HashSet<String> = phones(&["5550002"]).into_iter().collect();
+        let mut tally = PhoneTally::default();
+        let unique = tally.select(phones(&["5550003", "", "5550001", "5550002", "5550003", "5550004"]), &optout, &taken);
+        assert_eq!(unique, phones(&["5550003", "5550004"]));
+        assert_eq!((tally.seen, tally.suppressed, tally.existing, tally.repeated), (5, 1, 1, 1));
+    }
+
+    #[test]
+    fn decode_html_entities_handles_named_numeric_and_double_encoded() {
+        assert_eq!(decode_html_entities("O&#39;BRIEN"), "O'BRIEN");
+        assert_eq!(decode_html_entities("SMITH&amp;JONES"), "SMITH&JONES");
+        assert_eq!(decode_html_entities("O&amp;#39;BRIEN"), "O'BRIEN");
+        assert_eq!(decode_html_entities("JOS&Eacute; &#x4E;U&Ntilde;EZ"), "JOSÉ NUÑEZ");
+        assert_eq!(decode_html_entities("AT&T & SONS &bogus; &"), "AT&T & SONS &bogus; &");
+    }
+
+    #[test]
+    fn redact_pii_masks_phones_emails_and_names() {
+        assert_eq!(redact_pii("(813) 555-1234"), "***1234");
+        assert_eq!(redact_pii("jane.doe@example.com"), "j***@example.com");
+        assert_eq!(redact_pii("jane.doe@"), "j***@");
+        assert_eq!(redact_pii("Jane Doe"), "J***");
+        assert_eq!(redact_pii(""), "");
+    }
+
+    #[test]
+    fn canonical_email_lowercases_and_rejects_malformed() {
+        assert_eq!(canonical_email(" Pat.Smith@Example.COM ").as_deref(), Some("pat.smith@example.com"));
+        assert_eq!(canonical_email("pat smith@example.com"), None);
+        assert_eq!(canonical_email("pat@localhost"), None);
+        assert_eq!(canonical_email("pat@@example.com"), None);
+    }
+
+    #[test]
+    fn normalize_names_handles_mc_and_mac() {
+        let name = |raw| clean_name(raw, NameCase::Title, true);
+        assert_eq!(name("MCDONALD"), "McDonald");
+        assert_eq!(name("Mcdonald"), "McDonald");
+        assert_eq!(name("macdonald"), "MacDonald");
+        assert_eq!(name("MCDONALD, RONALD"), "Ronald McDonald");
+        assert_eq!(name("SMITH-MCDONALD"), "Smith-McDonald");
+    }
+
+    #[test]
+    fn normalize_names_handles_o_apostrophe() {
+        let name = |raw| clean_name(raw, NameCase::Title, true);
+        assert_eq!(name("O'BRIEN"), "O'Brien");
+        assert_eq!(name("d'angelo"), "D'Angelo");
+        assert_eq!(name("O\u{2019}NEIL"), "O\u{2019}Neil");
+    }
+
+    #[test]
+    fn normalize_names_leaves_lookalike_prefixes() {
+        let name = |raw| clean_name(raw, NameCase::Title, true);
+        assert_eq!(name("MACHADO"), "Machado");
+        assert_eq!(name("MACK"), "Mack");
+        assert_eq!(name("MACY"), "Macy");
+        assert_eq!(name("MC"), "Mc");
+        assert_eq!(clean_name("MCDONALD", NameCase::Title, false), "Mcdonald");
+    }
+
+    #[test]
+    fn exit_status_reflects_the_cause() {
+        let held = anyhow::Error::new(LockError::AlreadyRunning { path: "x.lock".to_string() })
+            .context("Failed to acquire process lock");
+        assert_eq!(ExitStatus::of_error(&held), ExitStatus::LockHeld);
+        let unreachable = anyhow::anyhow!("connection refused").context(DatabaseUnavailable);
+        assert_eq!(ExitStatus::of_error(&unreachable), ExitStatus::Database);
+
+        let mut summary = RunSummary::default();
+        assert_eq!(ExitStatus::of_run(&Ok(()), &summary), ExitStatus::Success);
+        summary.files_incomplete = 1;
+        assert_eq!(ExitStatus::of_run(&Ok(()), &summary), ExitStatus::Partial);
+        summary.record_error(Path::new("a.csv"), &anyhow::anyhow!("boom"));
+        assert_eq!(ExitStatus::of_run(&Ok(()), &summary), ExitStatus::FileErrors);
+    }
+
+    #[test]
+    fn skip_ai_tiers_map_flags_with_fallback() {
+        let tiers: SkipAiTiers = "1=101:google/img/geo.webp, 2=102:https://cdn.example.com/x.webp, *=199:none"
+            .parse()
+            .unwrap();
+        assert_eq!(tiers.get(0), &SkipAiTier { via: 0, map_image_url: "0".to_string() });
+        assert_eq!(tiers.get(1).via, 101);
+        assert_eq!(tiers.get(2).map_image_url, "https://cdn.example.com/x.webp");
+        assert_eq!(tiers.get(7), &SkipAiTier { via: 199, map_image_url: "none".to_string() });
+        assert_eq!(SkipAiTiers::default().get(3).via, 100);
+        assert!("1=abc:x".parse::<SkipAiTiers>().is_err());
+        assert!("1=101".parse::<SkipAiTiers>().is_err());
+    }
+
+    #[test]
+    fn most_complete_row_wins_among_duplicates() {
+        let data = "lead_id,owner_1_name,contact_1_phone1,contact_1_phone2\n\
+                    1,SMITH,,\n\
+                    1,SMITH,5551234567,\n\
+                    2,JONES,5550000001,\n\
+                    1,,5551234567,5559876543\n\
+                    2,JONES,5550000002,\n";
+        let source: Box<dyn CsvSource> = Box::new(std::io::Cursor::new(data.as_bytes().to_vec()));
+        let chosen = select_most_complete_rows(source, &CsvDialect::default(), &[0], &[2, 3]).unwrap();
+
+        let mut rdr = CsvDialect::default().reader_builder().from_reader(data.as_bytes());
+        let row_bytes: Vec<u64> = rdr
+            .records()
+            .map(|record| record.unwrap().position().unwrap().byte())
+            .collect();
+        // Two phones beat a name; equally complete rows keep the earliest.
+        assert_eq!(chosen["1"], row_bytes[3]);
+        assert_eq!(chosen["2"], row_bytes[2]);
+    }
+
+    #[test]
+    fn ids_beyond_i64_are_errors_not_negative() {
+        assert_eq!(signed_id(42, "campaign ID").unwrap(), 42);
+        assert_eq!(signed_id(i64::MAX as u64, "campaign ID").unwrap(), i64::MAX);
+        let err = signed_id(i64::MAX as u64 + 1, "campaign ID").unwrap_err();
+        assert!(err.to_string().contains("exceeds the signed 64-bit range"));
+        assert!(signed_id(u64::MAX, "address ID").is_err());
+    }
+
+    #[test]
+    fn precheck_catches_files_that_are_not_csv() {
+        let dialect = CsvDialect::default();
+        let check = |data: &[u8]| precheck_prefix(data, &dialect, 2);
+        assert!(check(b"lead_id,property_address_line_1\n1,12 Main St\n").is_ok());
+        assert!(check(b"\xEF\xBB\xBFlead_id,property_address_line_1\n").is_ok());
+        assert!(check(b"").is_ok());
+        // A multi-byte character cut off by the prefix length.
+        assert!(check(b"lead_id,name\n1,Jos\xC3").is_ok());
+
+        assert!(check(b"  <!DOCTYPE html><html><body>403</body></html>").unwrap_err().contains("HTML"));
+        assert!(check(b"<html>\n<head>").unwrap_err().contains("HTML"));
+        assert!(check(b"PK\x03\x04\x00\x00").unwrap_err().contains("binary"));
+        assert!(check(b"lead_id,name\n1,Jos\xE9\n").unwrap_err().contains("UTF-8"));
+        assert!(check(b"lead_id;property_address_line_1\n1;12 Main St\n").unwrap_err().contains("delimiter"));
+    }
+
+    #[test]
+    fn state_filter_compares_normalized_states() {
+        assert_eq!(normalize_state(" fl "), Some("FL"));
+        assert_eq!(normalize_state("north  carolina"), Some("NC"));
+        assert_eq!(normalize_state("D.C."), Some("DC"));
+        assert_eq!(normalize_state("Fla"), None);
+
+        let none = StateFilter::default();
+        assert!(none.allows("") && none.allows("ZZ"));
+
+        let allow = StateFilter::new(vec!["FL".into(), "Georgia".into()], Vec::new()).unwrap();
+        assert!(allow.allows("florida") && allow.allows("GA"));
+        assert!(!allow.allows("TX") && !allow.allows(""));
+
+        let block = StateFilter::new(Vec::new(), vec!["ca".into()]).unwrap();
+        assert!(!block.allows("California") && block.allows("NV") && block.allows(""));
+
+        assert!(StateFilter::new(vec!["Flordia".into()], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn copy_then_remove_replaces_leftovers_and_removes_the_source() {
+        let dir = std::env::temp_dir().join(format!("dmparser-move-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("upload.csv"), dir.join("processed.csv"));
+        fs::write(&from, b"lead_id\n1\n").unwrap();
+        // A leftover from an interrupted earlier attempt.
+        fs::write(dir.join("processed.csv.partial"), b"lead").unwrap();
+
+        copy_then_remove(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"lead_id\n1\n");
+        assert!(!from.exists());
+        assert!(!dir.join("processed.csv.partial").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn claimed_files_are_not_listed_under_a_broad_glob() {
+        let dir = std::env::temp_dir().join(format!("dmparser-scan-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.csv"), b"lead_id\n").unwrap();
+        fs::write(dir.join("b.csv.processing"), b"lead_id\n").unwrap();
+
+        let files = get_csv_files(&dir.to_string_lossy(), "*", &[]).unwrap();
+        assert_eq!(files, vec![dir.join("a.csv")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn windows_1252_files_are_decoded_before_parsing() {
+        let dir = std::env::temp_dir().join(format!("dmparser-encoding-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("legacy.csv");
+        let data = b"lead_id,owner_1_name\n1,Jos\xE9 O\x92Brien\n";
+        fs::write(&path, data).unwrap();
+        let dialect = CsvDialect {
+            encoding: Encoding::for_label(b"windows-1252").unwrap(),
+            ..CsvDialect::default()
+        };
+
+        assert!(precheck_prefix(data, &dialect, 2).is_ok());
+        let source = open_csv_source(&path, "legacy.csv", &dialect).unwrap();
+        let mut rdr = dialect.reader_builder().from_reader(source);
+        let record = rdr.records().next().unwrap().unwrap();
+        assert_eq!(&record[1], "Jos\u{e9} O\u{2019}Brien");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn header_aliases_fill_only_missing_columns() {
+        let headers = StringRecord::from(vec![
+            "Lead ID",
+            "Property Address Line1",
+            "Prop Zip",
+            "lat",
+            "Latitude",
+            "property_lng",
+            "Longitude",
+            "Contact 1 Phone 1",
+        ]);
+        let (mut header_map, _) = map_headers(&headers, DuplicateHeaderPolicy::Last);
+        let aliased = apply_header_aliases(&headers, &mut header_map);
+        assert_eq!(header_map.get("lead_id"), Some(&0));
+        assert_eq!(header_map.get("property_address_line_1"), Some(&1));
+        assert_eq!(header_map.get("property_address_zipcode"), Some(&2));
+        assert_eq!(header_map.get("property_lat"), Some(&3));
+        assert_eq!(header_map.get("property_lng"), Some(&5));
+        assert_eq!(header_map.get("contact_1_phone1"), Some(&7));
+        assert_eq!(aliased.len(), 5);
+
+        // Every alias and column stays distinct once normalized.
+        let mut keys: Vec<String> = REQUIRED_COLUMNS
+            .iter()
+            .chain(HEADER_ALIASES.iter().map(|(alias, _)| alias))
+            .map(|name| header_key(name))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), REQUIRED_COLUMNS.len() + HEADER_ALIASES.len());
+    }
+
+    #[test]
+    fn column_values_become_literals() {
+        let addr = AddressRecord {
+            street: "1 O'Hara St \\ Rear".to_string(),
+            dmid: "D1".to_string(),
+            flag: 7,
+            owner_occupied: Some(true),
+            ..AddressRecord::default()
+        };
+        assert_eq!(
+            column_value(&addr, "street", false).literal(),
+            "_utf8mb4 X'31204F2748617261205374205C2052656172'"
+        );
+        assert_eq!(column_value(&addr, "zip", false).literal(), "''");
+        assert_eq!(column_value(&addr, "zip", true).literal(), "NULL");
+        assert_eq!(column_value(&addr, "latitude", false).literal(), "NULL");
+        assert_eq!(column_value(&addr, "flag", true).literal(), "7");
+        assert_eq!(column_value(&addr, "owner_occupied", true).literal(), "1");
+        assert_eq!(column_value(&addr, "DMID", true), ColumnValue::Text(Some("D1")));
+    }
+
+    #[test]
+    fn round_coordinate_rounds_and_rejects_out_of_range() {
+        assert_eq!(round_coordinate("27.950575123456789", 90.0, 6), Some("27.950575".to_string()));
+        assert_eq!(round_coordinate("-82.4572", 180.0, 6), Some("-82.457200".to_string()));
+        assert_eq!(round_coordinate("91.5", 90.0, 6), None);
+        assert_eq!(round_coordinate("NaN", 90.0, 6), None);
+        assert_eq!(round_coordinate("north", 90.0, 6), None);
+        assert_eq!(round_coordinate("", 90.0, 6), None);
+    }
+
+    #[test]
+    fn owner_occupied_compares_normalized_street_and_zip() {
+        assert_eq!(owner_occupied("123 North Main Street", "33601", "123 N MAIN ST.", "33601-1234"), Some(true));
+        assert_eq!(owner_occupied("123 Main St", "33601", "PO Box 5", "33601"), Some(false));
+        assert_eq!(owner_occupied("123 Main St", "33601", "123 Main St", "10001"), Some(false));
+        assert_eq!(owner_occupied("123 Main St", "33601", "", ""), None);
+        assert_eq!(owner_occupied("123 Main St", "33601", "123 Main St", " "), None);
+    }
+
+    #[test]
+    fn soft_timeout_only_extends_files_about_to_finish() {
+        let elapsed = Duration::from_secs(100);
+        // 95% read in 100s: about 5s left.
+        assert!(finishes_within_grace(elapsed, 99, 10, 950, 50));
+        assert!(!finishes_within_grace(elapsed, 99, 0, 950, 50));
+        // Half read: another 100s would be needed.
+        assert!(!finishes_within_grace(elapsed, 99, 10, 500, 500));
+        // Past the end of the grace period, whatever the estimate.
+        assert!(!finishes_within_grace(Duration::from_secs(120), 99, 10, 999, 1));
+        assert!(!finishes_within_grace(elapsed, 99, 10, 0, 50));
+    }
+
+    /// Requires a disposable database: `TEST_DATABASE_URL=mysql://... cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn concurrent_new_campaigns_get_distinct_flags() {
+        let url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let pool = MySqlPoolOptions::new().max_connections(4).connect(&url).await.unwrap();
+        migrate(&pool, &Config::from_env().unwrap()).await.unwrap();
+
+        let suffix = claim_token();
+        let name_a = format!("flag-race-a-{}", suffix);
+        let name_b = format!("flag-race-b-{}", suffix);
+        let created = AtomicUsize::new(0);
+        let (a, b) = tokio::join!(
+            ensure_campaign(&pool, &name_a, DEFAULT_VERTICAL, None, None, &created),
+            ensure_campaign(&pool, &name_b, DEFAULT_VERTICAL, None, None, &created),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_ne!(a.1, b.1);
+
+        sqlx::query("DELETE FROM campaigns WHERE campaignName IN (?, ?)")
+            .bind(&name_a)
+            .bind(&name_b)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
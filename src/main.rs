@@ -1,710 +1,574 @@
 use anyhow::{Context, Result};
-use chrono::Local;
-use csv::ReaderBuilder;
+use dmparser::{
+    check_database, connect, insert_run_start, log_timestamp, migrate, plan_reprocess, plan_reprocess_files,
+    push_metrics, reprocess, reprocess_files, send_webhook, set_log_timezone, update_run_end, validate_file, Config, ExitStatus, Importer, LockMode, OutputMode, ProcessedTracking, RunSummary,
+};
 use dotenvy::dotenv;
-use lazy_static::lazy_static;
-use regex::Regex;
-use sqlx::{mysql::MySqlPoolOptions, MySql, Pool, Row};
 use std::{
-    collections::HashSet,
-    collections::HashMap,
-    env,
-    fs,
+    env, fs,
+    io::{BufRead, IsTerminal, Write},
     path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
-lazy_static! {
-    static ref FILENAME_PATTERN: Regex =
-        Regex::new(r"^(\d+)_skipAI_(\d+)_(.+\.csv)$").unwrap();
-}
-
-/// Struct representing a record to be inserted into the `address` table.
-#[derive(Debug)]
-struct AddressRecord {
-    street: String,
-    unit_type: String,
-    unit_num: String,
-    mail_city: String,
-    state: String, // New field for property_address_state
-    zip: String,
-    latitude: String,
-    longitude: String,
-    fullname: String,
-    fname: String,
-    lname: String,
-    mailing_address: String,
-    mailing_city: String,
-    mailing_state: String,
-    mailing_zip: String,
-    flag: i64,
-    dmid: String,
-    via: i64,
-    map_image_url: String,
+/// Usage text printed for `--help`.
+const USAGE: &str = "\
+Usage: DMParser [OPTIONS]
+
+Processes Deal Machine CSV files from UPLOAD_DIR into the database.
+Configuration is read from the environment (and a .env file).
+
+Options:
+  --limit <N>   Stop after inserting N rows in total, leaving remaining files in place
+  --flag <N>    Load every file into the existing campaign with flag N instead of
+                the campaign named after the file
+  --reprocess-flag <N>
+                DELETE the address and phonequeue rows of the campaign with flag N,
+                move its original files back to UPLOAD_DIR and import them again.
+                Asks for confirmation unless --force is given
+  --force       Skip the --reprocess-flag confirmation prompt
+  --reprocess <GLOB>
+                Move the files in PROCESSED_DIR matching GLOB back to UPLOAD_DIR
+                (with PROCESSED_TRACKING=database, forget the matching files in
+                UPLOAD_DIR) and import them again. Their rows are not deleted
+  --dry-run     With --reprocess or --reprocess-flag, list what would be moved
+                or deleted and exit without changing anything
+  -y, --yes     Create new campaigns without asking. When stdout is a terminal,
+                the campaigns a run would create are listed first and must be
+                confirmed; in cron and other non-interactive runs they are
+                created as named (see ALLOW_NEW_CAMPAIGNS)
+  --migrate     Create any missing database tables and exit
+  --check       Check the configuration, database and directories, then exit
+                with status 0 if everything passed or 1 otherwise
+  --stdin       Process the files whose paths are read from stdin, one per line,
+                in that order, instead of the files in UPLOAD_DIR. Every path
+                must be an existing file whose name matches FILENAME_REGEX, or
+                nothing is processed. New campaigns are created without asking
+  --validate-only <FILE>
+                Check FILE's name, header row, required columns and a sample of
+                rows without connecting to the database, then exit with status 0
+                if everything passed or 1 otherwise. DATABASE_URL is not needed
+  -h, --help    Print this help and exit
+
+Exit status:
+  0  Success             4  Database unreachable
+  1  Other error         5  A file was only partially processed
+  2  Invalid arguments   6  A file failed and was left for retry
+     or configuration    7  Process lock could not be created
+  3  Another run holds the lock
+";
+
+/// Command-line flags. Everything else is configured through the environment.
+#[derive(Debug, Default)]
+struct CliArgs {
+    limit: Option<usize>,
+    flag: Option<i64>,
+    reprocess_flag: Option<i64>,
+    reprocess_glob: Option<String>,
+    dry_run: bool,
+    force: bool,
+    yes: bool,
+    migrate: bool,
+    check: bool,
+    validate_only: Option<PathBuf>,
+    stdin: bool,
 }
 
-/// Struct representing a record to be inserted into the `phonequeue` table.
-#[derive(Debug)]
-struct PhoneQueueRecord {
-    phone1: Option<String>,
-    phone2: Option<String>,
-    phone3: Option<String>,
+impl CliArgs {
+    fn parse() -> Result<Self> {
+        let mut cli = Self::default();
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--limit" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--limit requires a value"))?;
+                    cli.limit = Some(
+                        value
+                            .parse()
+                            .map_err(|e| anyhow::anyhow!("Invalid value for --limit: {}", e))?,
+                    );
+                }
+                "--flag" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--flag requires a value"))?;
+                    cli.flag = Some(
+                        value
+                            .parse()
+                            .map_err(|e| anyhow::anyhow!("Invalid value for --flag: {}", e))?,
+                    );
+                }
+                "--reprocess-flag" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--reprocess-flag requires a value"))?;
+                    cli.reprocess_flag = Some(
+                        value
+                            .parse()
+                            .map_err(|e| anyhow::anyhow!("Invalid value for --reprocess-flag: {}", e))?,
+                    );
+                }
+                "--reprocess" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--reprocess requires a glob"))?;
+                    cli.reprocess_glob = Some(value);
+                }
+                "--dry-run" => cli.dry_run = true,
+                "--force" => cli.force = true,
+                "-y" | "--yes" => cli.yes = true,
+                "--migrate" => cli.migrate = true,
+                "--check" => cli.check = true,
+                "--stdin" => cli.stdin = true,
+                "--validate-only" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--validate-only requires a file"))?;
+                    cli.validate_only = Some(PathBuf::from(value));
+                }
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                other => {
+                    return Err(anyhow::anyhow!("Unknown argument: {}\n\n{}", other, USAGE));
+                }
+            }
+        }
+        if cli.flag.is_some() && cli.reprocess_flag.is_some() {
+            return Err(anyhow::anyhow!("--flag and --reprocess-flag can't be combined"));
+        }
+        if cli.reprocess_glob.is_some() && cli.reprocess_flag.is_some() {
+            return Err(anyhow::anyhow!("--reprocess and --reprocess-flag can't be combined"));
+        }
+        let reprocessing = cli.reprocess_flag.is_some() || cli.reprocess_glob.is_some();
+        if cli.stdin && reprocessing {
+            return Err(anyhow::anyhow!("--stdin can't be combined with --reprocess or --reprocess-flag"));
+        }
+        if cli.dry_run && !reprocessing {
+            return Err(anyhow::anyhow!("--dry-run requires --reprocess or --reprocess-flag"));
+        }
+        Ok(cli)
+    }
 }
 
-/// Combined record that holds both the address data and its optional phone data.
-/// This ensures the ordering is maintained so that each phone record is matched with
-/// the correct address row.
-#[derive(Debug)]
-struct CombinedRecord {
-    address: AddressRecord,
-    phone: Option<PhoneQueueRecord>,
+/// Prints the `--check` report, one line per item.
+struct CheckReport {
+    color: bool,
+    failed: bool,
 }
 
-/// Entry point of the application.
-/// Handles configuration loading, setting up database connections,
-/// processing CSV files, and managing concurrency via lock files.
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Load environment variables from `.env` file.
-    dotenv().ok();
-
-    // Validate and gather configuration from environment variables.
-    let config = Config::from_env().context("Failed to load configuration")?;
-
-    // Ensure upload and processed directories exist.
-    fs::create_dir_all(&config.upload_dir)
-        .with_context(|| format!("Failed to create upload directory: {}", config.upload_dir))?;
-    fs::create_dir_all(&config.processed_dir)
-        .with_context(|| format!("Failed to create processed directory: {}", config.processed_dir))?;
-
-    // Acquire a lock to prevent concurrent executions.
-    let _lock_guard = LockFileGuard::new(&config.lock_file_path)
-        .with_context(|| "Failed to acquire process lock")?;
-
-    // Establish a connection pool to the MySQL database.
-    let pool = MySqlPoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await
-        .context("Failed to connect to MySQL database")?;
-
-    // Prefetch all phone numbers from the database.
-    let mut global_phone_set = prefetch_all_phone_numbers(&pool).await
-        .context("Failed to prefetch phone numbers")?;
-
-    // Retrieve list of CSV files to process.
-    let files = get_csv_files(&config.upload_dir).context("Failed to retrieve CSV files")?;
-
-    if files.is_empty() {
-        eprintln!(
-            "[{}] No files to process.",
-            Local::now().format("%Y-%m-%d %H:%M:%S")
-        );
-        return Ok(()); // Nothing to do
-    }
-
-    // Process each CSV file individually.
-    for file_path in files {
-        if let Err(e) = process_file(
-            &pool,
-            &file_path,
-            &config.processed_dir,
-            config.batch_size,
-            config.max_execution_seconds,
-            &mut global_phone_set,
-        )
-        .await
-        {
-            eprintln!("Error processing file {:?}: {:?}", file_path, e);
-            // Attempt to move the problematic file to the processed directory.
-            let file_name = file_path.file_name().unwrap_or_default();
-            let new_path = Path::new(&config.processed_dir).join(file_name);
-            let _ = fs::rename(&file_path, &new_path);
+impl CheckReport {
+    fn new() -> Self {
+        CheckReport {
+            color: std::io::stdout().is_terminal(),
+            failed: false,
         }
     }
 
-    Ok(())
+    fn item(&mut self, name: &str, result: Result<String>) {
+        let (status, detail) = match result {
+            Ok(detail) => ("OK", detail),
+            Err(e) => {
+                self.failed = true;
+                ("FAIL", format!("{:#}", e))
+            }
+        };
+        let status = match (self.color, status) {
+            (false, status) => format!("{:<4}", status),
+            (true, "OK") => "\x1b[32mOK  \x1b[0m".to_string(),
+            (true, status) => format!("\x1b[31m{}\x1b[0m", status),
+        };
+        println!("[{}] {}: {}", status, name, detail);
+    }
 }
 
-/// Loads configuration from environment variables.
-struct Config {
-    database_url: String,
-    upload_dir: String,
-    processed_dir: String,
-    lock_file_path: String,
-    batch_size: usize,
-    max_execution_seconds: u64,
+/// Writes and removes a probe file to confirm `dir` is writable.
+fn probe_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".dmparser-check-{}", std::process::id()));
+    fs::write(&probe, b"").with_context(|| format!("{} is not writable", dir.display()))?;
+    fs::remove_file(&probe).with_context(|| format!("Failed to remove {}", probe.display()))?;
+    Ok(())
 }
 
-impl Config {
-    fn from_env() -> Result<Self> {
-        fn parse_env_var<T: std::str::FromStr>(
-            key: &str,
-            default: Option<T>,
-        ) -> Result<T>
-        where
-            T::Err: std::fmt::Display,
-        {
-            match env::var(key) {
-                Ok(val) => val.parse::<T>().map_err(|e| {
-                    anyhow::anyhow!("Invalid value for {}: {}", key, e)
-                }),
-                Err(_) => match default {
-                    Some(d) => Ok(d),
-                    None => Err(anyhow::anyhow!("Environment variable {} is required", key)),
-                },
-            }
+/// Checks that a directory can be listed and written to. A missing directory
+/// passes if it can be created, as the run creates it on demand.
+fn check_dir(dir: &str) -> Result<String> {
+    let path = Path::new(dir);
+    if path.is_dir() {
+        fs::read_dir(path).with_context(|| format!("{} is not readable", dir))?;
+        probe_writable(path)?;
+        return Ok(format!("{} is readable and writable", dir));
+    }
+    if path.exists() {
+        return Err(anyhow::anyhow!("{} is not a directory", dir));
+    }
+    let mut ancestor = PathBuf::from(path);
+    while !ancestor.is_dir() {
+        if !ancestor.pop() || ancestor.as_os_str().is_empty() {
+            ancestor = PathBuf::from(".");
+            break;
         }
-
-        Ok(Self {
-            database_url: env::var("DATABASE_URL")
-                .context("DATABASE_URL must be set in .env file")?,
-            upload_dir: env::var("UPLOAD_DIR").unwrap_or_else(|_| "./uploads".to_string()),
-            processed_dir: env::var("PROCESSED_DIR").unwrap_or_else(|_| "./processed".to_string()),
-            lock_file_path: env::var("LOCK_FILE").unwrap_or_else(|_| "./process.lock".to_string()),
-            batch_size: parse_env_var("BATCH_SIZE", Some(1000))?,
-            max_execution_seconds: parse_env_var("MAX_EXECUTION_SECONDS", Some(3600))?,
-        })
     }
+    probe_writable(&ancestor)?;
+    Ok(format!("{} does not exist yet and will be created", dir))
 }
 
-/// Retrieves a list of CSV files from the specified upload directory.
-fn get_csv_files(upload_dir: &str) -> Result<Vec<PathBuf>> {
-    let pattern = format!("{}/*.csv", upload_dir);
-    let files: Vec<PathBuf> = glob::glob(&pattern)?
-        .filter_map(Result::ok)
-        .collect();
-    Ok(files)
+/// Checks that the lock file can be created and isn't held.
+fn check_lock_file(lock_file: &str) -> Result<String> {
+    let path = Path::new(lock_file);
+    if path.exists() {
+        return Err(anyhow::anyhow!(
+            "{} exists; another run is active, or a stale lock must be removed",
+            lock_file
+        ));
+    }
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    probe_writable(dir)?;
+    Ok(format!("{} can be created", lock_file))
 }
 
-/// Prefetch all phone numbers (phone1, phone2, phone3) from the phonequeue table.
-async fn prefetch_all_phone_numbers(pool: &Pool<MySql>) -> Result<HashSet<String>> {
-    let mut set = HashSet::new();
-    let rows = sqlx::query("SELECT phone1, phone2, phone3 FROM phonequeue")
-        .fetch_all(pool)
-        .await
-        .context("Failed to prefetch phone numbers")?;
-    for row in rows {
-        if let Ok(Some(phone)) = row.try_get::<Option<String>, _>("phone1") {
-            if !phone.trim().is_empty() {
-                set.insert(phone.trim().to_string());
-            }
-        }
-        if let Ok(Some(phone)) = row.try_get::<Option<String>, _>("phone2") {
-            if !phone.trim().is_empty() {
-                set.insert(phone.trim().to_string());
-            }
-        }
-        if let Ok(Some(phone)) = row.try_get::<Option<String>, _>("phone3") {
-            if !phone.trim().is_empty() {
-                set.insert(phone.trim().to_string());
-            }
+/// Checks that the upload directory can take a `flock` and isn't locked by a
+/// run in progress (`LOCK_MODE=flock`).
+fn check_upload_dir_lock(upload_dir: &str) -> Result<String> {
+    let dir = fs::File::open(upload_dir).with_context(|| format!("Cannot open {} to lock it", upload_dir))?;
+    match dir.try_lock() {
+        Ok(()) => {
+            dir.unlock().with_context(|| format!("Cannot unlock {}", upload_dir))?;
+            Ok(format!("{} can be locked", upload_dir))
         }
+        Err(fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!("{} is locked; another run is active", upload_dir)),
+        Err(fs::TryLockError::Error(e)) => Err(e).with_context(|| format!("Cannot lock {}", upload_dir)),
     }
-    Ok(set)
 }
 
-/// Processes a single CSV file: parsing, validating, batching inserts,
-/// handling errors, and moving the file post-processing.
-async fn process_file(
-    pool: &Pool<MySql>,
-    file_path: &Path,
-    processed_dir: &str,
-    batch_size: usize,
-    max_execution_seconds: u64,
-    global_phone_set: &mut HashSet<String>,
-) -> Result<()> {
-    let file_name = file_path
-        .file_name()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-
-    // Validate filename against the expected pattern.
-    let captures = match FILENAME_PATTERN.captures(&file_name) {
-        Some(cap) => cap,
-        None => {
-            eprintln!("Filename pattern mismatch: {}", file_name);
-            let new_path = Path::new(processed_dir).join(&file_name);
-            fs::rename(file_path, new_path)?;
-            return Ok(());
+/// Runs the `--check` preflight without processing any files. Returns whether
+/// every check passed.
+async fn run_check() -> bool {
+    let mut report = CheckReport::new();
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            report.item("configuration", Err(e));
+            return false;
         }
     };
-
-    let _timestamp = captures.get(1).unwrap().as_str();
-    let skip_ai_flag: i64 = captures
-        .get(2)
-        .unwrap()
-        .as_str()
-        .parse()
-        .unwrap_or(0);
-    let original_filename = captures.get(3).unwrap().as_str();
-
-    let mut rdr = ReaderBuilder::new()
-        .has_headers(true)
-        .from_path(file_path)
-        .with_context(|| format!("Failed to open CSV file: {}", file_name))?;
-
-    let headers = rdr.headers()?.clone();
-    let header_map: HashMap<&str, usize> = headers
-        .iter()
-        .enumerate()
-        .map(|(idx, header)| (header.trim(), idx))
-        .collect();
-
-    // Define required columns.
-    let required_columns = [
-        "property_address_line_1",
-        "property_address_line_2",
-        "property_address_city",
-        "property_address_state", // New required column
-        "property_address_zipcode",
-        "property_lat",
-        "property_lng",
-        "owner_1_firstname",
-        "owner_1_lastname",
-        "owner_1_name",
-        "owner_address_line_1",
-        "owner_address_city",
-        "owner_address_state",
-        "owner_address_zip",
-        "lead_id",
-        "owner_2_firstname",
-        "owner_2_lastname",
-        "owner_2_name",
-        "contact_1_phone1",
-        "contact_1_phone2",
-        "contact_1_phone3",
-        "contact_2_phone1",
-        "contact_2_phone2",
-        "contact_2_phone3",
-    ];
-
-    let missing_columns: Vec<&str> = required_columns
-        .iter()
-        .filter(|col| !header_map.contains_key(*col))
-        .copied()
-        .collect();
-    if !missing_columns.is_empty() {
-        eprintln!(
-            "Missing required columns in {}: {:?}",
-            file_name, missing_columns
+    report.item("configuration", Ok("loaded".to_string()));
+    if config.output_mode == OutputMode::Jsonl {
+        report.item("database", Ok("not used with OUTPUT_MODE=jsonl".to_string()));
+        report.item("JSONL directory", check_dir(&config.jsonl_dir));
+    } else {
+        report.item(
+            "database",
+            check_database(&config)
+                .await
+                .map(|()| "connected; address and phonequeue tables have the configured columns".to_string()),
         );
-        let new_path = Path::new(processed_dir).join(&file_name);
-        fs::rename(file_path, new_path)?;
-        return Ok(());
     }
 
-    let campaign_name = Path::new(original_filename)
-        .file_stem()
-        .unwrap_or_default()
-        .to_string_lossy()
-        .to_string();
-
-    let start_time = Instant::now();
-
-    let (_campaign_id, new_flag) = ensure_campaign(pool, &campaign_name).await
-        .context("Failed to ensure campaign exists")?;
-
-    let mut existing_dmids = prefetch_dmids(pool, new_flag).await
-        .context("Failed to prefetch DMIDs")?;
-
-    // Combined batch for address and phone data.
-    let mut combined_batch: Vec<CombinedRecord> = Vec::with_capacity(batch_size);
-    let mut row_counter = 0_usize;
-    let mut processed_rows = 0_usize;
-
-    for result in rdr.records() {
-        let record = match result {
-            Ok(rec) => rec,
-            Err(e) => {
-                eprintln!("Skipping malformed line in {}: {:?}", file_name, e);
-                continue;
-            }
-        };
-        row_counter += 1;
-
-        if start_time.elapsed() > Duration::from_secs(max_execution_seconds) {
-            eprintln!(
-                "Script timeout after {} seconds while processing {}.",
-                max_execution_seconds, file_name
-            );
-            break;
-        }
-
-        let lead_id = record.get(*header_map.get("lead_id").unwrap()).unwrap_or("").trim();
-        if lead_id.is_empty() {
-            continue;
-        }
-        if existing_dmids.contains_key(lead_id) {
-            continue;
-        } else {
-            existing_dmids.insert(lead_id.to_string(), true);
+    if config.upload_dir.starts_with("s3://") {
+        report.item("upload directory", Ok(format!("{} is an S3 bucket; not checked", config.upload_dir)));
+    } else {
+        report.item("upload directory", check_dir(&config.upload_dir));
+        if config.processed_tracking == ProcessedTracking::Move {
+            report.item("processed directory", check_dir(&config.processed_dir));
         }
+    }
+    report.item("quarantine directory", check_dir(&config.quarantine_dir));
+    if let Some(dir) = &config.duplicates_dir {
+        report.item("duplicates directory", check_dir(dir));
+    }
+    if let Some(dir) = &config.rejects_dir {
+        report.item("rejects directory", check_dir(dir));
+    }
+    match config.lock_mode {
+        LockMode::File => report.item("lock file", check_lock_file(&config.lock_file_path)),
+        LockMode::Flock => report.item("upload directory lock", check_upload_dir_lock(&config.upload_dir)),
+    }
 
-        let owner_1_firstname = record.get(*header_map.get("owner_1_firstname").unwrap()).unwrap_or("").trim();
-        let owner_1_lastname = record.get(*header_map.get("owner_1_lastname").unwrap()).unwrap_or("").trim();
-        let owner_1_name = record.get(*header_map.get("owner_1_name").unwrap()).unwrap_or("").trim();
-        let owner_2_firstname = record.get(*header_map.get("owner_2_firstname").unwrap()).unwrap_or("").trim();
-        let owner_2_lastname = record.get(*header_map.get("owner_2_lastname").unwrap()).unwrap_or("").trim();
-        let owner_2_name = record.get(*header_map.get("owner_2_name").unwrap()).unwrap_or("").trim();
+    !report.failed
+}
 
-        let fname = if !owner_1_firstname.is_empty() { owner_1_firstname } else { owner_2_firstname };
-        let lname = if !owner_1_lastname.is_empty() { owner_1_lastname } else { owner_2_lastname };
-        let fullname = if !owner_1_name.is_empty() { owner_1_name } else { owner_2_name };
+/// Asks on stderr and reads the answer from stdin; only "yes" confirms.
+fn confirm() -> Result<bool> {
+    eprint!("Type \"yes\" to continue: ");
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim() == "yes")
+}
 
-        if fname.is_empty() {
+/// Reads the `--stdin` file list: one path per line, blank lines skipped.
+/// Every path must be an existing file whose name matches the filename
+/// pattern; otherwise all the offending lines are reported and nothing runs.
+/// The files inside a `.zip` archive are checked when it's processed.
+fn read_stdin_files(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut problems = Vec::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.context("Failed to read file paths from stdin")?;
+        let path = PathBuf::from(line.trim());
+        if path.as_os_str().is_empty() {
             continue;
         }
-
-        let street = record.get(*header_map.get("property_address_line_1").unwrap()).unwrap_or("").trim();
-        let unit_num = record.get(*header_map.get("property_address_line_2").unwrap()).unwrap_or("").trim();
-        let mail_city = record.get(*header_map.get("property_address_city").unwrap()).unwrap_or("").trim();
-        let property_state = record.get(*header_map.get("property_address_state").unwrap()).unwrap_or("").trim();
-        let zipcode = record.get(*header_map.get("property_address_zipcode").unwrap()).unwrap_or("").trim();
-        let latitude = record.get(*header_map.get("property_lat").unwrap()).unwrap_or("").trim();
-        let longitude = record.get(*header_map.get("property_lng").unwrap()).unwrap_or("").trim();
-
-        let mailing_address = record.get(*header_map.get("owner_address_line_1").unwrap()).unwrap_or("").trim();
-        let mailing_city = record.get(*header_map.get("owner_address_city").unwrap()).unwrap_or("").trim();
-        let mailing_state = record.get(*header_map.get("owner_address_state").unwrap()).unwrap_or("").trim();
-        let mailing_zip = record.get(*header_map.get("owner_address_zip").unwrap()).unwrap_or("").trim();
-
-        let via = if skip_ai_flag != 0 { 100 } else { 0 };
-        let map_image_url = if skip_ai_flag != 0 {
-            "google/img/missing.webp".to_string()
-        } else {
-            "0".to_string()
-        };
-
-        let address_record = AddressRecord {
-            street: street.to_string(),
-            unit_type: "".to_string(),
-            unit_num: unit_num.to_string(),
-            mail_city: mail_city.to_string(),
-            state: property_state.to_string(),
-            zip: zipcode.to_string(),
-            latitude: latitude.to_string(),
-            longitude: longitude.to_string(),
-            fullname: fullname.to_string(),
-            fname: fname.to_string(),
-            lname: lname.to_string(),
-            mailing_address: mailing_address.to_string(),
-            mailing_city: mailing_city.to_string(),
-            mailing_state: mailing_state.to_string(),
-            mailing_zip: mailing_zip.to_string(),
-            flag: new_flag,
-            dmid: lead_id.to_string(),
-            via,
-            map_image_url,
-        };
-
-        // --- Phone number processing with uniqueness check ---
-        // Build candidate phone numbers.
-        let candidate_phone1 = if !record.get(*header_map.get("contact_1_phone1").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_1_phone1").unwrap()).unwrap_or("").trim().to_string())
-        } else if !record.get(*header_map.get("contact_2_phone1").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_2_phone1").unwrap()).unwrap_or("").trim().to_string())
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if !path.is_file() {
+            problems.push(format!("{}: not a file", path.display()));
+        } else if !name.to_ascii_lowercase().ends_with(".zip") && !config.filename_pattern.matches(&name) {
+            problems.push(format!("{}: name doesn't match FILENAME_REGEX", path.display()));
         } else {
-            None
-        };
-        let candidate_phone2 = if !record.get(*header_map.get("contact_1_phone2").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_1_phone2").unwrap()).unwrap_or("").trim().to_string())
-        } else if !record.get(*header_map.get("contact_2_phone2").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_2_phone2").unwrap()).unwrap_or("").trim().to_string())
-        } else {
-            None
-        };
-        let candidate_phone3 = if !record.get(*header_map.get("contact_1_phone3").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_1_phone3").unwrap()).unwrap_or("").trim().to_string())
-        } else if !record.get(*header_map.get("contact_2_phone3").unwrap()).unwrap_or("").trim().is_empty() {
-            Some(record.get(*header_map.get("contact_2_phone3").unwrap()).unwrap_or("").trim().to_string())
-        } else {
-            None
-        };
-
-        // Combine candidates in order.
-        let mut candidates = Vec::new();
-        if let Some(p) = candidate_phone1 {
-            candidates.push(p);
-        }
-        if let Some(p) = candidate_phone2 {
-            candidates.push(p);
-        }
-        if let Some(p) = candidate_phone3 {
-            candidates.push(p);
-        }
-        // Filter out phone numbers that already exist (and any empties).
-        let unique_candidates: Vec<String> = candidates.into_iter()
-            .filter(|p| !p.is_empty() && !global_phone_set.contains(p))
-            .collect();
-
-        // If no unique phone numbers, skip the record entirely.
-        if unique_candidates.is_empty() {
-            continue;
+            files.push(path);
         }
+    }
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!("Invalid --stdin paths:\n  {}", problems.join("\n  ")));
+    }
+    Ok(files)
+}
 
-        // Assign final phone numbers from the unique candidates (shifting them over).
-        let final_phone1 = unique_candidates.get(0).cloned();
-        let final_phone2 = unique_candidates.get(1).cloned();
-        let final_phone3 = unique_candidates.get(2).cloned();
-
-        let phone_record = Some(PhoneQueueRecord {
-            phone1: final_phone1.clone(),
-            phone2: final_phone2.clone(),
-            phone3: final_phone3.clone(),
-        });
+/// Connects for `RUN_LOG_ENABLED` and records the run's start, returning the
+/// pool and run ID for recording its end.
+async fn start_run_log(config: &Config) -> Result<(sqlx::MySqlPool, u64)> {
+    let pool = connect(config).await?;
+    let run_id = insert_run_start(&pool).await?;
+    Ok((pool, run_id))
+}
 
-        // Update the global phone set with the new unique numbers.
-        if let Some(ref p) = final_phone1 {
-            global_phone_set.insert(p.clone());
-        }
-        if let Some(ref p) = final_phone2 {
-            global_phone_set.insert(p.clone());
-        }
-        if let Some(ref p) = final_phone3 {
-            global_phone_set.insert(p.clone());
-        }
-        // --- End phone number processing ---
-
-        combined_batch.push(CombinedRecord {
-            address: address_record,
-            phone: phone_record,
-        });
-
-        if combined_batch.len() >= batch_size {
-            let inserted = process_batch(pool, &mut combined_batch).await
-                .context("Failed to process batch")?;
-            processed_rows += inserted;
-            eprintln!(
-                "[{}] Processed batch: {} rows inserted.",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                inserted
-            );
+/// In an interactive run, lists the campaigns the run would create and asks
+/// before creating them, so a misnamed file doesn't silently add a campaign.
+/// Returns an error without processing anything if the answer isn't "yes".
+async fn confirm_new_campaigns(importer: &Importer, yes: bool) -> Result<()> {
+    if yes || !importer.config().allow_new_campaigns || !std::io::stdout().is_terminal() {
+        return Ok(());
+    }
+    let new_campaigns = importer.new_campaigns().await?;
+    if new_campaigns.is_empty() {
+        return Ok(());
+    }
+    eprintln!("This run will create {} new campaign(s):", new_campaigns.len());
+    for campaign in &new_campaigns {
+        eprintln!("  {:?}", campaign.name);
+        for file in &campaign.files {
+            eprintln!("      {}", file.display());
         }
     }
-
-    if !combined_batch.is_empty() {
-        let inserted = process_batch(pool, &mut combined_batch).await
-            .context("Failed to process final batch")?;
-        processed_rows += inserted;
-        eprintln!(
-            "[{}] Processed final batch: {} rows inserted.",
-            Local::now().format("%Y-%m-%d %H:%M:%S"),
-            inserted
-        );
+    if !confirm()? {
+        return Err(anyhow::anyhow!(
+            "Run aborted; no campaigns were created and no files were processed. Use --yes to skip this prompt"
+        ));
     }
+    Ok(())
+}
 
-    if row_counter >= processed_rows {
-        let new_path = Path::new(processed_dir).join(&file_name);
-        if file_path.exists() {
-            fs::rename(file_path, &new_path)
-                .with_context(|| format!("Failed to rename file to {}", new_path.display()))?;
-            eprintln!(
-                "File {} processed successfully with {} rows inserted.",
-                file_name, processed_rows
-            );
-        } else {
-            eprintln!("File {} missing when attempting rename.", file_name);
+/// Runs `--validate-only` on one file. Returns whether every check passed.
+fn run_validate_only(file: &Path) -> bool {
+    let mut report = CheckReport::new();
+    let config = match Config::from_env_offline() {
+        Ok(config) => config,
+        Err(e) => {
+            report.item("configuration", Err(e));
+            return false;
         }
-    } else {
-        eprintln!(
-            "File {} partially processed. Processed {} out of {} rows. It will be reprocessed.",
-            file_name, processed_rows, row_counter
-        );
+    };
+    if !file.is_file() {
+        report.item("file", Err(anyhow::anyhow!("{} is not a file", file.display())));
+        return false;
+    }
+    for (name, result) in validate_file(&config, file) {
+        report.item(name, result);
     }
+    !report.failed
+}
 
-    Ok(())
+/// Deletes the rows of the campaign with `flag` and moves its files back for
+/// import, after confirmation on stdin unless `force` is set. Returns an error
+/// without changing anything if the confirmation isn't given.
+async fn prepare_reprocess(config: &Config, flag: i64, force: bool, dry_run: bool) -> Result<()> {
+    let pool = connect(config).await?;
+    let plan = plan_reprocess(&pool, config, flag).await?;
+    eprintln!(
+        "--reprocess-flag {} will DELETE {} address rows and {} phonequeue rows of campaign {:?}, then re-import:",
+        plan.flag, plan.address_rows, plan.phonequeue_rows, plan.campaign_name
+    );
+    for file in &plan.files {
+        eprintln!("  {}", file.display());
+    }
+    if dry_run {
+        pool.close().await;
+        return Ok(());
+    }
+    if !force && !confirm()? {
+        return Err(anyhow::anyhow!("Reprocessing aborted; nothing was deleted"));
+    }
+    let result = reprocess(&pool, config, &plan).await;
+    pool.close().await;
+    result
 }
 
-/// Ensures that a campaign exists; creates it if not.
-async fn ensure_campaign(
-    pool: &Pool<MySql>,
-    campaign_name: &str,
-) -> Result<(i64, i64)> {
-    let row_opt = sqlx::query("SELECT id, flag FROM campaigns WHERE campaignName = ?")
-        .bind(campaign_name)
-        .fetch_optional(pool)
-        .await
-        .context("Database query failed for campaigns")?;
-
-    if let Some(row) = row_opt {
-        let campaign_id: i64 = row.try_get("id")
-            .context("Failed to retrieve campaign ID")?;
-        let flag: i64 = row.try_get("flag")
-            .context("Failed to retrieve campaign flag")?;
-        Ok((campaign_id, flag))
-    } else {
-        let highest_flag: Option<i64> = sqlx::query_scalar("SELECT MAX(flag) FROM campaigns")
-            .fetch_one(pool)
-            .await
-            .context("Failed to retrieve highest flag from campaigns")?;
-        let new_flag = highest_flag.unwrap_or(0) + 1;
-
-        let emoji: Option<String> = sqlx::query_scalar("SELECT e FROM emoji ORDER BY RAND() LIMIT 1")
-            .fetch_one(pool)
-            .await
-            .ok();
-
-        let insert_result = sqlx::query(
-            r#"
-            INSERT INTO campaigns (campaignName, vertical, textingActive, flag, emoji)
-            VALUES (?, 1, 0, ?, ?)
-            "#,
-        )
-        .bind(campaign_name)
-        .bind(new_flag)
-        .bind(emoji.unwrap_or_default())
-        .execute(pool)
-        .await
-        .context("Failed to insert new campaign")?;
-
-        let campaign_id = insert_result.last_insert_id() as i64;
-        Ok((campaign_id, new_flag))
+/// Makes the files matching `--reprocess <glob>` pending again, after listing
+/// them; with `dry_run` only the list is printed.
+async fn prepare_reprocess_files(config: &Config, pattern: &str, dry_run: bool) -> Result<()> {
+    let files = plan_reprocess_files(config, pattern)?;
+    eprintln!("--reprocess {:?} will re-import {} file(s):", pattern, files.len());
+    for file in &files {
+        eprintln!("  {}", file.display());
     }
+    if dry_run {
+        return Ok(());
+    }
+    let pool = match config.output_mode {
+        OutputMode::Mysql => Some(connect(config).await?),
+        OutputMode::Jsonl => None,
+    };
+    let result = reprocess_files(pool.as_ref(), config, &files).await;
+    if let Some(pool) = pool {
+        pool.close().await;
+    }
+    result
 }
 
-/// Pre-fetches existing DMIDs for a given flag.
-async fn prefetch_dmids(pool: &Pool<MySql>, flag: i64) -> Result<HashMap<String, bool>> {
-    let mut map = HashMap::new();
-    let rows = sqlx::query("SELECT DMID FROM address WHERE flag = ?")
-        .bind(flag)
-        .fetch_all(pool)
-        .await
-        .context("Failed to fetch existing DMIDs")?;
-    for row in rows {
-        let dmid: String = row.try_get("DMID")
-            .context("Failed to retrieve DMID from row")?;
-        map.insert(dmid, true);
-    }
-    Ok(map)
+/// Prints an error that ends the process and exits with the given status.
+fn exit_with_error(error: anyhow::Error, status: ExitStatus) -> ! {
+    eprintln!("Error: {:?}", error);
+    std::process::exit(status.code());
 }
 
-/// Processes a batch of combined records (addresses and optional phone records) in a transaction.
-async fn process_batch(
-    pool: &Pool<MySql>,
-    combined_batch: &mut Vec<CombinedRecord>,
-) -> Result<usize> {
-    let mut tx = pool.begin().await
-        .context("Failed to begin database transaction")?;
-
-    // Bulk insert addresses (note: includes the new state column).
-    let mut address_query = String::from(
-        "INSERT INTO address (
-            street, unit_type, unit_num, mail_city, state, zip, latitude, longitude,
-            fullname, fname, lname, mailingAddress, mailingCity, mailingState, mailingZip,
-            flag, DMID, via, map_image_url
-        ) VALUES ",
-    );
+/// Entry point of the application.
+/// Handles configuration loading, running the import, and reporting the outcome
+/// to the optional webhook. Exits with an `ExitStatus` code.
+#[tokio::main]
+async fn main() {
+    let cli = CliArgs::parse().unwrap_or_else(|e| exit_with_error(e, ExitStatus::Config));
+
+    // Load environment variables from `.env` file.
+    dotenv().ok();
 
-    let placeholders: Vec<String> = combined_batch
-        .iter()
-        .map(|_| "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)".to_string())
-        .collect();
-    address_query += &placeholders.join(", ");
-
-    let mut query = sqlx::query(&address_query);
-    for record in combined_batch.iter() {
-        let addr = &record.address;
-        query = query
-            .bind(&addr.street)
-            .bind(&addr.unit_type)
-            .bind(&addr.unit_num)
-            .bind(&addr.mail_city)
-            .bind(&addr.state)
-            .bind(&addr.zip)
-            .bind(&addr.latitude)
-            .bind(&addr.longitude)
-            .bind(&addr.fullname)
-            .bind(&addr.fname)
-            .bind(&addr.lname)
-            .bind(&addr.mailing_address)
-            .bind(&addr.mailing_city)
-            .bind(&addr.mailing_state)
-            .bind(&addr.mailing_zip)
-            .bind(&addr.flag)
-            .bind(&addr.dmid)
-            .bind(&addr.via)
-            .bind(&addr.map_image_url);
-    }
-
-    query
-        .execute(&mut *tx)
-        .await
-        .context("Failed to execute bulk insert for addresses")?;
-
-    let last_insert_id: u64 = sqlx::query("SELECT LAST_INSERT_ID()")
-        .fetch_one(&mut *tx)
-        .await?
-        .try_get(0)?;
-
-    // Build bulk insert for phone queues for records with phone data.
-    let mut phone_inserts = Vec::new();
-    for (i, record) in combined_batch.iter().enumerate() {
-        if let Some(phone) = &record.phone {
-            let aid = last_insert_id as i64 + i as i64;
-            phone_inserts.push((aid, phone));
+    if cli.check {
+        std::process::exit(if run_check().await { 0 } else { 1 });
+    }
+    if let Some(file) = &cli.validate_only {
+        std::process::exit(if run_validate_only(file) { 0 } else { 1 });
+    }
+
+    // Validate and gather configuration from environment variables.
+    let config = Config::from_env()
+        .context("Failed to load configuration")
+        .unwrap_or_else(|e| exit_with_error(e, ExitStatus::Config));
+    set_log_timezone(config.log_timezone);
+
+    if cli.migrate {
+        let result = match connect(&config).await {
+            Ok(pool) => migrate(&pool, &config).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            let status = ExitStatus::of_error(&e);
+            exit_with_error(e, status);
         }
+        return;
     }
 
-    if !phone_inserts.is_empty() {
-        let mut phone_query = String::from(
-            "INSERT INTO phonequeue (aid, phone1, phone2, phone3, step) VALUES ",
-        );
-        let phone_placeholders: Vec<String> = phone_inserts
-            .iter()
-            .map(|_| "(?, ?, ?, ?, 11)".to_string())
-            .collect();
-        phone_query += &phone_placeholders.join(", ");
-
-        let mut phone_query_builder = sqlx::query(&phone_query);
-        for (aid, phone) in phone_inserts {
-            phone_query_builder = phone_query_builder
-                .bind(aid)
-                .bind(&phone.phone1)
-                .bind(&phone.phone2)
-                .bind(&phone.phone3);
+    if let Some(flag) = cli.reprocess_flag {
+        if let Err(e) = prepare_reprocess(&config, flag, cli.force, cli.dry_run).await {
+            let status = ExitStatus::of_error(&e);
+            exit_with_error(e, status);
+        }
+    }
+    if let Some(pattern) = &cli.reprocess_glob {
+        if let Err(e) = prepare_reprocess_files(&config, pattern, cli.dry_run).await {
+            let status = ExitStatus::of_error(&e);
+            exit_with_error(e, status);
         }
-        phone_query_builder
-            .execute(&mut *tx)
-            .await
-            .context("Failed to execute bulk insert for phone queues")?;
+    }
+    if cli.dry_run {
+        return;
     }
 
-    tx.commit()
-        .await
-        .context("Failed to commit database transaction")?;
+    // Read before connecting, so a bad list fails fast.
+    let files = if cli.stdin {
+        Some(read_stdin_files(&config).unwrap_or_else(|e| exit_with_error(e, ExitStatus::Config)))
+    } else {
+        None
+    };
 
-    let inserted_count = combined_batch.len();
-    combined_batch.clear();
-    Ok(inserted_count)
-}
+    // Counted from here, so connecting and prefetching use up the budget too.
+    let run_start = Instant::now();
+    let deadline = config.max_total_seconds.map(|seconds| run_start + Duration::from_secs(seconds));
+    // Best-effort: the run goes ahead even if it can't be recorded.
+    let run_log = if config.run_log_enabled {
+        match start_run_log(&config).await {
+            Ok(run_log) => Some(run_log),
+            Err(e) => {
+                eprintln!("Failed to record run start: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut summary = RunSummary::default();
+    let result = match Importer::new(config.clone()).await {
+        Ok(importer) => {
+            let importer = importer
+                .with_limit(cli.limit)
+                .with_campaign_flag(cli.flag)
+                .with_deadline(deadline)
+                .with_files(files);
+            // With --stdin, stdin holds the file list and can't answer a prompt.
+            match confirm_new_campaigns(&importer, cli.yes || cli.stdin).await {
+                Ok(()) => importer.run(&mut summary).await,
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    summary.elapsed_seconds = run_start.elapsed().as_secs_f64();
+
+    eprintln!(
+        "[{}] Run finished: {} files processed ({} incomplete, {} empty, {} duplicate), {} failed ({} quarantined), {} rows inserted, {} rows rejected, {} new phones ({} already taken) in {:.1}s.",
+        log_timestamp(),
+        summary.files_processed,
+        summary.files_incomplete,
+        summary.files_empty,
+        summary.files_duplicate,
+        summary.file_errors.len(),
+        summary.files_quarantined,
+        summary.rows_inserted,
+        summary.rows_rejected,
+        summary.phones_new,
+        summary.phones_existing,
+        summary.elapsed_seconds
+    );
 
-/// A guard for managing the lock file.
-struct LockFileGuard {
-    path: String,
-}
+    // Webhook failures are logged but never change the exit status.
+    if let Some(url) = &config.webhook_url {
+        let run_error = result.as_ref().err().map(|e| format!("{:#}", e));
+        if let Err(e) = send_webhook(url, &summary, run_error.as_deref()).await {
+            eprintln!("Failed to send webhook notification: {:?}", e);
+        }
+    }
 
-impl LockFileGuard {
-    fn new(path: &str) -> Result<Self> {
-        let lock_path = Path::new(path);
-        if lock_path.exists() {
-            Err(anyhow::anyhow!("Another instance is already running. Exiting."))
-        } else {
-            fs::write(
-                lock_path,
-                format!("Process started: {}\n", Local::now().format("%Y-%m-%d %H:%M:%S")),
-            )
-            .with_context(|| format!("Failed to create lock file at {}", path))?;
-            Ok(Self { path: path.to_string() })
+    // Like the webhook, a failed push never changes the exit status.
+    if let Some(url) = &config.pushgateway_url {
+        if let Err(e) = push_metrics(url).await {
+            eprintln!("Failed to push metrics: {:?}", e);
         }
     }
-}
 
-impl Drop for LockFileGuard {
-    fn drop(&mut self) {
-        if let Err(e) = fs::remove_file(&self.path) {
-            eprintln!("Failed to remove lock file {}: {:?}", self.path, e);
+    let status = ExitStatus::of_run(&result, &summary);
+    if let Some((pool, run_id)) = run_log {
+        if let Err(e) = update_run_end(&pool, run_id, &summary, status).await {
+            eprintln!("Failed to record run end: {:?}", e);
         }
+        pool.close().await;
+    }
+    if let Err(e) = result {
+        exit_with_error(e, status);
     }
+    std::process::exit(status.code());
 }
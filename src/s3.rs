@@ -0,0 +1,332 @@
+//! Reads uploads from an S3 bucket when `UPLOAD_DIR` is an `s3://bucket/prefix` URL.
+//!
+//! Each object is downloaded to a scratch directory and handed to `process_file`
+//! like a local upload. Objects that finish are moved under `<prefix>processed/`,
+//! detected re-sends under `<prefix>duplicates/`, and objects set aside as
+//! malformed or failing `MAX_FILE_ATTEMPTS` times under `<prefix>quarantine/`;
+//! anything else stays in place for the next run.
+
+use crate::{
+    deadline_passed, log_timestamp, process_file, Config, FileAttempts, PhoneSet, ProcessedTracking, RunSummary,
+};
+use anyhow::{Context, Result};
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::Client;
+use sqlx::{MySql, Pool};
+use std::{collections::HashSet, env, fs, io::Write, path::Path, sync::atomic::AtomicUsize, time::Instant};
+
+/// Bucket and key prefix parsed from an `s3://bucket/prefix` URL.
+struct S3Location {
+    bucket: String,
+    /// Empty, or ends with `/`.
+    prefix: String,
+}
+
+impl S3Location {
+    fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| anyhow::anyhow!("Not an S3 URL: {}", url))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(anyhow::anyhow!("S3 URL has no bucket: {}", url));
+        }
+        let prefix = prefix.trim_end_matches('/');
+        Ok(S3Location {
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+        })
+    }
+
+    fn processed_key(&self, file_name: &str) -> String {
+        format!("{}processed/{}", self.prefix, file_name)
+    }
+
+    fn duplicate_key(&self, file_name: &str) -> String {
+        format!("{}duplicates/{}", self.prefix, file_name)
+    }
+
+    fn quarantine_key(&self, file_name: &str) -> String {
+        format!("{}quarantine/{}", self.prefix, file_name)
+    }
+}
+
+/// Processes every `.csv` object directly under the configured prefix.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn process_bucket(
+    pool: Option<&Pool<MySql>>,
+    config: &Config,
+    limit: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    campaigns_created: &AtomicUsize,
+    global_phone_set: &mut PhoneSet,
+    optout: &HashSet<String>,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    let location = S3Location::parse(&config.upload_dir)?;
+    let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let client = Client::new(&sdk_config);
+
+    let keys = list_csv_keys(&client, &location).await?;
+    if keys.is_empty() {
+        global_phone_set.abort();
+        eprintln!("[{}] No files to process.", log_timestamp());
+        return Ok(());
+    }
+
+    // Downloads land in a scratch upload directory and are processed with the
+    // regular move tracking; a download that ends up in the scratch processed,
+    // duplicates or quarantine directory is done with, and its object is moved
+    // to the matching prefix.
+    let scratch = env::temp_dir().join(format!("dmparser-s3-{}", std::process::id()));
+    let local_config = Config {
+        upload_dir: scratch.join("uploads").to_string_lossy().to_string(),
+        processed_dir: scratch.join("processed").to_string_lossy().to_string(),
+        processed_tracking: ProcessedTracking::Move,
+        quarantine_dir: scratch.join("quarantine").to_string_lossy().to_string(),
+        duplicates_dir: config
+            .duplicates_dir
+            .as_ref()
+            .map(|_| scratch.join("duplicates").to_string_lossy().to_string()),
+        ..config.clone()
+    };
+    fs::create_dir_all(&local_config.upload_dir)
+        .with_context(|| format!("Failed to create scratch directory: {}", local_config.upload_dir))?;
+    fs::create_dir_all(&local_config.processed_dir)
+        .with_context(|| format!("Failed to create scratch directory: {}", local_config.processed_dir))?;
+
+    let result = process_keys(
+        pool,
+        &client,
+        &location,
+        &keys,
+        &local_config,
+        limit,
+        campaign_flag,
+        deadline,
+        campaigns_created,
+        global_phone_set,
+        optout,
+        summary,
+    )
+    .await;
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        eprintln!("Failed to remove scratch directory {:?}: {:?}", scratch, e);
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_keys(
+    pool: Option<&Pool<MySql>>,
+    client: &Client,
+    location: &S3Location,
+    keys: &[String],
+    local_config: &Config,
+    limit: Option<usize>,
+    campaign_flag: Option<i64>,
+    deadline: Option<Instant>,
+    campaigns_created: &AtomicUsize,
+    global_phone_set: &mut PhoneSet,
+    optout: &HashSet<String>,
+    summary: &mut RunSummary,
+) -> Result<()> {
+    let mut attempts = FileAttempts::load(&local_config.attempts_file)?;
+    for key in keys {
+        let insert_budget = limit.map(|limit| limit.saturating_sub(summary.rows_inserted));
+        if insert_budget == Some(0) {
+            eprintln!(
+                "Row limit of {} reached; leaving remaining objects in s3://{}/{}.",
+                limit.unwrap_or_default(),
+                location.bucket,
+                location.prefix
+            );
+            break;
+        }
+        if deadline_passed(deadline) {
+            eprintln!(
+                "[{}] MAX_TOTAL_SECONDS reached; leaving remaining objects in s3://{}/{}.",
+                log_timestamp(),
+                location.bucket,
+                location.prefix
+            );
+            break;
+        }
+
+        let file_name = key.rsplit('/').next().unwrap_or(key);
+        let local_path = Path::new(&local_config.upload_dir).join(file_name);
+        let processed_path = Path::new(&local_config.processed_dir).join(file_name);
+        let quarantine_path = Path::new(&local_config.quarantine_dir).join(file_name);
+        let duplicate_path = local_config
+            .duplicates_dir
+            .as_ref()
+            .map(|dir| Path::new(dir).join(file_name));
+
+        if let Err(e) = download(client, &location.bucket, key, &local_path).await {
+            eprintln!("Error downloading s3://{}/{}: {:?}", location.bucket, key, e);
+            summary.record_error(Path::new(key), &e);
+            let _ = fs::remove_file(&local_path);
+            continue;
+        }
+
+        let mut give_up = false;
+        match process_file(
+            pool,
+            &local_path,
+            local_config,
+            global_phone_set,
+            optout,
+            insert_budget,
+            campaign_flag,
+            deadline,
+            campaigns_created,
+        )
+        .await
+        {
+            Ok(file_summary) => {
+                if file_summary.completed {
+                    attempts.reset(file_name)?;
+                }
+                summary.record_file(&file_summary);
+            }
+            Err(e) => {
+                // A failed background prefetch is fatal for the whole run.
+                if let PhoneSet::Failed = global_phone_set {
+                    return Err(e);
+                }
+                summary.record_error(Path::new(key), &e);
+                let failures = attempts.record_failure(file_name)?;
+                if failures < local_config.max_file_attempts {
+                    eprintln!(
+                        "Error processing s3://{}/{} (attempt {} of {}): {:?}. The object was left in place for retry.",
+                        location.bucket, key, failures, local_config.max_file_attempts, e
+                    );
+                } else {
+                    eprintln!(
+                        "ERROR: s3://{}/{} failed {} times and is being quarantined under {}. Last error: {:?}",
+                        location.bucket,
+                        key,
+                        failures,
+                        location.quarantine_key(""),
+                        e
+                    );
+                    give_up = true;
+                }
+            }
+        }
+
+        let destination = if processed_path.exists() {
+            Some(location.processed_key(file_name))
+        } else if duplicate_path.as_ref().is_some_and(|path| path.exists()) {
+            Some(location.duplicate_key(file_name))
+        } else if give_up || quarantine_path.exists() {
+            Some(location.quarantine_key(file_name))
+        } else {
+            None
+        };
+        if let Some(destination) = destination {
+            match move_object(client, &location.bucket, key, &destination).await {
+                Ok(()) if give_up => {
+                    attempts.reset(file_name)?;
+                    summary.files_quarantined += 1;
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    eprintln!(
+                        "ERROR: Failed to move s3://{}/{} to {}: {:?}",
+                        location.bucket, key, destination, e
+                    );
+                    summary.record_error(Path::new(key), &e);
+                }
+            }
+        }
+        let _ = fs::remove_file(&local_path);
+        let _ = fs::remove_file(&processed_path);
+        let _ = fs::remove_file(&quarantine_path);
+        if let Some(path) = &duplicate_path {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the `.csv` keys directly under the prefix. Keys in sub-"directories",
+/// including the processed prefix, are not listed.
+async fn list_csv_keys(client: &Client, location: &S3Location) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(&location.bucket)
+        .prefix(&location.prefix)
+        .delimiter("/")
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.with_context(|| {
+            format!("Failed to list s3://{}/{}", location.bucket, location.prefix)
+        })?;
+        keys.extend(
+            page.contents()
+                .iter()
+                .filter_map(|object| object.key())
+                .filter(|key| key.ends_with(".csv"))
+                .map(str::to_string),
+        );
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+/// Streams an object to a local file.
+async fn download(client: &Client, bucket: &str, key: &str, path: &Path) -> Result<()> {
+    let object = client
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("GetObject failed")?;
+    let mut body = object.body;
+    let mut file = fs::File::create(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    while let Some(chunk) = body.try_next().await.context("Failed to read object body")? {
+        file.write_all(&chunk)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Moves an object within the bucket. S3 has no rename, so this is a copy
+/// followed by a delete of the original.
+async fn move_object(client: &Client, bucket: &str, from: &str, to: &str) -> Result<()> {
+    client
+        .copy_object()
+        .bucket(bucket)
+        .copy_source(format!("{}/{}", bucket, encode_key(from)))
+        .key(to)
+        .send()
+        .await
+        .context("CopyObject failed")?;
+    client
+        .delete_object()
+        .bucket(bucket)
+        .key(from)
+        .send()
+        .await
+        .context("DeleteObject failed")?;
+    Ok(())
+}
+
+/// Percent-encodes a key for the `x-amz-copy-source` header, keeping `/` intact.
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,35 @@
+//! Reads `.xlsx` uploads. The first worksheet is converted to CSV in memory, in
+//! the configured dialect, so the rest of the import treats it like any upload.
+
+use crate::CsvDialect;
+use anyhow::{Context, Result};
+use calamine::{open_workbook, Reader, Xlsx};
+use csv::{QuoteStyle, WriterBuilder};
+use std::path::Path;
+
+/// Converts the first worksheet of a workbook to CSV bytes. Cells are written
+/// as displayed by calamine: text as-is, so ZIPs stored as text keep their
+/// leading zeros, and whole numbers without a trailing `.0`.
+pub(crate) fn sheet_to_csv(path: &Path, dialect: &CsvDialect) -> Result<Vec<u8>> {
+    let mut workbook: Xlsx<_> = open_workbook(path)
+        .with_context(|| format!("Failed to open workbook {}", path.display()))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| anyhow::anyhow!("Workbook {} has no worksheets", path.display()))?
+        .with_context(|| format!("Failed to read the first worksheet of {}", path.display()))?;
+
+    // Quoting every field keeps cells that start with the comment character
+    // from being read back as comment lines.
+    let mut writer = WriterBuilder::new()
+        .quote(dialect.quote)
+        .double_quote(dialect.double_quote)
+        .escape(dialect.escape.unwrap_or(b'\\'))
+        .quote_style(QuoteStyle::Always)
+        .from_writer(Vec::new());
+    for row in range.rows() {
+        writer.write_record(row.iter().map(|cell| cell.to_string()))?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| anyhow::anyhow!("Failed to convert {} to CSV: {}", path.display(), e))
+}